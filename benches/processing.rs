@@ -0,0 +1,129 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use trasaction_processor::{
+    domain::{Amount, ClientId, Transaction, TransactionId, TransactionType},
+    processor::TransactionProcessor,
+    state::State,
+};
+
+const SIZES: [usize; 4] = [1_000, 10_000, 100_000, 1_000_000];
+
+/// Client ids are `u16` unless `large_client_ids` is enabled, so clients may repeat. The
+/// reserved client `0` is skipped.
+fn client_for(index: usize) -> ClientId {
+    (index % ClientId::MAX as usize + 1) as ClientId
+}
+
+fn deposit(index: usize) -> Transaction {
+    Transaction::new(
+        TransactionType::Deposit,
+        client_for(index),
+        index as TransactionId,
+        Some(Amount::new(10_000, 4)),
+    )
+}
+
+fn deposits(n: usize) -> Vec<Transaction> {
+    (0..n).map(deposit).collect()
+}
+
+fn deposits_and_disputes(n: usize) -> Vec<Transaction> {
+    let disputes = (0..n).map(|index| {
+        Transaction::new(
+            TransactionType::Dispute,
+            client_for(index),
+            index as TransactionId,
+            None,
+        )
+    });
+    deposits(n).into_iter().chain(disputes).collect()
+}
+
+/// 70% deposits, 20% withdrawals, 5% disputes, 3% resolves and 2% chargebacks, where
+/// withdrawals and disputes pick a pseudo-random earlier deposit
+fn mixed(n: usize) -> Vec<Transaction> {
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut random = move |bound: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % bound as u64) as usize
+    };
+    let mut deposited = Vec::new();
+    let mut disputed = Vec::new();
+    (0..n)
+        .map(|index| {
+            let roll = random(100);
+            if roll < 70 || deposited.is_empty() {
+                deposited.push(index);
+                return deposit(index);
+            }
+            if roll < 90 {
+                let client = client_for(deposited[random(deposited.len())]);
+                return Transaction::new(
+                    TransactionType::Withdrawal,
+                    client,
+                    index as TransactionId,
+                    Some(Amount::new(1_000, 4)),
+                );
+            }
+            let (transaction_type, id) = match roll {
+                90..=94 => {
+                    let id = deposited[random(deposited.len())];
+                    disputed.push(id);
+                    (TransactionType::Dispute, id)
+                }
+                95..=97 if !disputed.is_empty() => (
+                    TransactionType::Resolve,
+                    disputed.swap_remove(random(disputed.len())),
+                ),
+                _ if !disputed.is_empty() => (
+                    TransactionType::Chargeback,
+                    disputed.swap_remove(random(disputed.len())),
+                ),
+                _ => (TransactionType::Dispute, deposited[random(deposited.len())]),
+            };
+            Transaction::new(transaction_type, client_for(id), id as TransactionId, None)
+        })
+        .collect()
+}
+
+fn bench_workload(c: &mut Criterion, name: &str, workload: fn(usize) -> Vec<Transaction>) {
+    let mut group = c.benchmark_group(name);
+    group.sample_size(10);
+    for n in SIZES {
+        let transactions = workload(n);
+        group.throughput(Throughput::Elements(transactions.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(n),
+            &transactions,
+            |b, transactions| {
+                b.iter_batched(
+                    || {
+                        (
+                            TransactionProcessor::<State>::default(),
+                            transactions.clone(),
+                        )
+                    },
+                    |(processor, transactions)| {
+                        for transaction in transactions {
+                            // Rejections, e.g. withdrawals exceeding the balance, are part of the workload
+                            let _ = processor.process_transaction(transaction);
+                        }
+                        processor
+                    },
+                    BatchSize::PerIteration,
+                );
+            },
+        );
+    }
+    group.finish();
+}
+
+fn processing(c: &mut Criterion) {
+    bench_workload(c, "deposits", deposits);
+    bench_workload(c, "deposits_and_disputes", deposits_and_disputes);
+    bench_workload(c, "mixed", mixed);
+}
+
+criterion_group!(benches, processing);
+criterion_main!(benches);
@@ -0,0 +1,203 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::error::Error;
+use core::fmt;
+
+use thiserror::Error;
+
+use crate::domain::{Amount, ClientId, TransactionId, TransactionType};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ProcessingError {
+    #[error("Transaction with id {id} is not valid")]
+    TransactionIsNotValid { id: TransactionId },
+    #[error("Client id {id} is reserved")]
+    ReservedClientId { id: ClientId },
+    #[error("Transaction with id {id} carries an amount its type doesn't take")]
+    UnexpectedAmount { id: TransactionId },
+    #[error("Transaction with id {id} not found")]
+    TransactionNotFound { id: TransactionId },
+    #[error("Transaction with id {id} already exists")]
+    TransactionAlreadyExists { id: TransactionId },
+    #[error("Transaction with id {id} exceeds the maximum transaction id {max}")]
+    TransactionIdSpaceExhausted {
+        id: TransactionId,
+        max: TransactionId,
+    },
+    #[error("Transaction with id {id} is not pending commit")]
+    TransactionNotPendingCommit { id: TransactionId },
+    #[error("Client {client_id} account changed since the state transaction began")]
+    StateTransactionConflict { client_id: ClientId },
+    #[error("Transaction with id {id} already under dispute")]
+    TransactionAlreadyUnderDispute { id: TransactionId },
+    #[error("Transaction with id {id} is not disputable")]
+    TransactionIsNotDisputable { id: TransactionId },
+    #[error("Transaction with id {id} is not under dispute")]
+    TransactionNotUnderDispute { id: TransactionId },
+    #[error("Transaction with id {id} owned by client with id {owner_id} can't be accessed by client with id {client_id}")]
+    TransactionAccessDenied {
+        id: TransactionId,
+        client_id: ClientId,
+        owner_id: ClientId,
+    },
+    #[error("Client {client_id} account has insufficient available funds")]
+    AccountInsufficientAvailableFunds { client_id: ClientId },
+    #[error("Deposit with id {id} is less than its fee of {fee}")]
+    DepositLessThanFee { id: TransactionId, fee: Amount },
+    #[error("Client {client_id} account has insufficient held funds")]
+    AccountInsufficientHeldFunds { client_id: ClientId },
+    #[error("Client {client_id} account has insufficient available funds for regulatory hold")]
+    InsufficientAvailableForRegulatoryHold { client_id: ClientId },
+    #[error("Transaction with id {id} is not an active regulatory hold")]
+    TransactionIsNotRegulatoryHold { id: TransactionId },
+    #[error("Client {client_id} has deposits pending KYC verification")]
+    KycRequired { client_id: ClientId },
+    #[error("Client {client_id} account balance overflow")]
+    AmountOverflow { client_id: ClientId },
+    #[error("Client {client_id} account would have a negative available or held balance")]
+    AccountBalanceInvariantViolated { client_id: ClientId },
+    #[error("State invariant violated: {reason}")]
+    InvariantViolation { reason: String },
+    #[error("Client {client_id} account is locked")]
+    AccountIsLocked { client_id: ClientId },
+    #[error("Client {client_id} account is closed")]
+    AccountIsClosed { client_id: ClientId },
+    #[error("Client {client_id} account has open disputes")]
+    AccountHasOpenDisputes { client_id: ClientId },
+    #[error("Client {client_id} account has held funds")]
+    AccountHasHeldFunds { client_id: ClientId },
+    #[error("Client {client_id} account can't receive its own closing balance")]
+    InvalidClosingFeeDestination { client_id: ClientId },
+    #[error("Client {client_id} exceeded the transaction velocity limit")]
+    VelocityLimitExceeded { client_id: ClientId },
+    #[error("Client {client_id} has too many open disputes")]
+    TooManyOpenDisputes { client_id: ClientId },
+    #[error("Client {client_id} has no {tx_type:?} transactions")]
+    NoTransactionsOfType {
+        client_id: ClientId,
+        tx_type: TransactionType,
+    },
+    #[error("Delegation chain {chain:?} is circular")]
+    CircularDelegation { chain: Vec<ClientId> },
+    #[error("Client {client_id} account not found")]
+    AccountNotFound { client_id: ClientId },
+    #[error("Client {client_id} account already exists")]
+    AccountAlreadyExists { client_id: ClientId },
+    #[error("Reconciliation input is not valid: {reason}")]
+    ReconciliationInputIsNotValid { reason: String },
+    #[error("Storage is poisoned: {context}")]
+    StoragePoisoned { context: String },
+    #[error("Internal error: {source}")]
+    InternalError {
+        #[source]
+        source: ErrorSource,
+    },
+}
+
+/// Name that isn't one of the transaction types read from input, see
+/// [`TransactionType::try_from`]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Unknown transaction type {0:?}")]
+pub struct UnknownTransactionType(pub String);
+
+/// Underlying error of a [`ProcessingError`], shared so that errors stay `Clone`. Equal
+/// only to its own clones since errors in general can't be compared.
+#[derive(Debug, Clone)]
+pub struct ErrorSource(pub Arc<dyn Error + Send + Sync>);
+
+impl ErrorSource {
+    pub fn new(error: impl Error + Send + Sync + 'static) -> Self {
+        Self(Arc::new(error))
+    }
+}
+
+impl PartialEq for ErrorSource {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ErrorSource {}
+
+impl fmt::Display for ErrorSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for ErrorSource {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.0.source()
+    }
+}
+
+impl ProcessingError {
+    /// Whether the same transaction may succeed when processed again.
+    pub const fn is_transient(&self) -> bool {
+        matches!(self, Self::StoragePoisoned { .. })
+    }
+}
+
+pub type ProcessingResult<T> = Result<T, ProcessingError>;
+
+/// Process exit code for the error, `3` when the state may be corrupted and `1` when only the
+/// transaction was rejected. `0` and `2` are left for success and I/O or parse errors.
+impl From<ProcessingError> for u8 {
+    fn from(error: ProcessingError) -> Self {
+        match error {
+            ProcessingError::AccountBalanceInvariantViolated { .. }
+            | ProcessingError::InvariantViolation { .. }
+            | ProcessingError::StoragePoisoned { .. } => 3,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl From<ProcessingError> for tonic::Status {
+    fn from(error: ProcessingError) -> Self {
+        use alloc::string::ToString;
+        use tonic::Code;
+
+        let code = match &error {
+            ProcessingError::TransactionIsNotValid { .. }
+            | ProcessingError::ReservedClientId { .. }
+            | ProcessingError::UnexpectedAmount { .. }
+            | ProcessingError::ReconciliationInputIsNotValid { .. } => Code::InvalidArgument,
+            ProcessingError::TransactionNotFound { .. }
+            | ProcessingError::TransactionNotPendingCommit { .. } => Code::NotFound,
+            ProcessingError::AccountNotFound { .. }
+            | ProcessingError::NoTransactionsOfType { .. } => Code::NotFound,
+            ProcessingError::TransactionAlreadyExists { .. }
+            | ProcessingError::AccountAlreadyExists { .. } => Code::AlreadyExists,
+            ProcessingError::TransactionAccessDenied { .. } => Code::PermissionDenied,
+            ProcessingError::StateTransactionConflict { .. } => Code::Aborted,
+            ProcessingError::TransactionAlreadyUnderDispute { .. }
+            | ProcessingError::TransactionIsNotDisputable { .. }
+            | ProcessingError::TransactionNotUnderDispute { .. }
+            | ProcessingError::TransactionIsNotRegulatoryHold { .. }
+            | ProcessingError::AccountInsufficientAvailableFunds { .. }
+            | ProcessingError::AccountInsufficientHeldFunds { .. }
+            | ProcessingError::DepositLessThanFee { .. }
+            | ProcessingError::InsufficientAvailableForRegulatoryHold { .. }
+            | ProcessingError::KycRequired { .. }
+            | ProcessingError::AccountIsLocked { .. }
+            | ProcessingError::AccountIsClosed { .. }
+            | ProcessingError::AccountHasOpenDisputes { .. }
+            | ProcessingError::AccountHasHeldFunds { .. }
+            | ProcessingError::InvalidClosingFeeDestination { .. }
+            | ProcessingError::CircularDelegation { .. } => Code::FailedPrecondition,
+            ProcessingError::AmountOverflow { .. } => Code::OutOfRange,
+            ProcessingError::VelocityLimitExceeded { .. }
+            | ProcessingError::TooManyOpenDisputes { .. }
+            | ProcessingError::TransactionIdSpaceExhausted { .. } => Code::ResourceExhausted,
+            ProcessingError::StoragePoisoned { .. } => Code::Unavailable,
+            ProcessingError::AccountBalanceInvariantViolated { .. }
+            | ProcessingError::InvariantViolation { .. }
+            | ProcessingError::InternalError { .. } => Code::Internal,
+        };
+        let message = error.to_string();
+        tonic::Status::with_details(code, message.clone(), bytes::Bytes::from(message))
+    }
+}
@@ -0,0 +1,887 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::mem;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::api::{ProcessingError, ProcessingResult, UnknownTransactionType};
+
+/// `u32` with the `large_client_ids` feature for more than 65,536 clients
+#[cfg(not(feature = "large_client_ids"))]
+pub type ClientId = u16;
+#[cfg(feature = "large_client_ids")]
+pub type ClientId = u32;
+pub type TransactionId = u32;
+/// Never a transaction's client, commonly a sentinel or null id in other systems
+pub const RESERVED_CLIENT_ID: ClientId = 0;
+pub type Amount = Decimal;
+
+const AMOUNT_PRECISION: u32 = 4;
+
+/// Operations on [`Amount`], which is a foreign type and can't have inherent methods
+pub trait AmountExt {
+    /// Divides the amount into `parts` equal amounts at 4 decimal places. The rounding
+    /// remainder goes to the first amount so that the amounts always sum up to `self`.
+    /// Returns no amounts for zero parts.
+    fn split(&self, parts: usize) -> Vec<Amount>;
+}
+
+impl AmountExt for Amount {
+    fn split(&self, parts: usize) -> Vec<Amount> {
+        if parts == 0 {
+            return Vec::new();
+        }
+        let count = Decimal::from(parts);
+        let part =
+            (self / count).round_dp_with_strategy(AMOUNT_PRECISION, RoundingStrategy::ToZero);
+        let mut amounts = alloc::vec![part; parts];
+        amounts[0] += self - part * count;
+        amounts
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Transfer,
+    Dispute,
+    Resolve,
+    Chargeback,
+    #[cfg_attr(feature = "serde", serde(rename = "regulatory_hold"))]
+    RegulatoryHold,
+    #[cfg_attr(feature = "serde", serde(rename = "regulatory_release"))]
+    RegulatoryRelease,
+    #[cfg_attr(feature = "serde", serde(rename = "kyc_approved"))]
+    KycApproved,
+    /// Credit issued manually by a merchant, not tied to any other transaction
+    Refund,
+    /// Only recorded by the processor for deposit fees, never read from input
+    #[cfg_attr(feature = "serde", serde(skip_deserializing))]
+    Fee,
+}
+
+impl TryFrom<&str> for TransactionType {
+    type Error = UnknownTransactionType;
+
+    /// Parses the names used in input, e.g. `deposit` or `kyc_approved`, ignoring case.
+    /// `fee` is rejected like in input since fees are only recorded by the processor.
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        const TYPES: [(&str, TransactionType); 10] = [
+            ("deposit", TransactionType::Deposit),
+            ("withdrawal", TransactionType::Withdrawal),
+            ("transfer", TransactionType::Transfer),
+            ("dispute", TransactionType::Dispute),
+            ("resolve", TransactionType::Resolve),
+            ("chargeback", TransactionType::Chargeback),
+            ("regulatory_hold", TransactionType::RegulatoryHold),
+            ("regulatory_release", TransactionType::RegulatoryRelease),
+            ("kyc_approved", TransactionType::KycApproved),
+            ("refund", TransactionType::Refund),
+        ];
+        TYPES
+            .iter()
+            .find(|(type_name, _)| type_name.eq_ignore_ascii_case(name))
+            .map(|&(_, transaction_type)| transaction_type)
+            .ok_or_else(|| UnknownTransactionType(name.into()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Transaction {
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub transaction_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub amount: Option<Decimal>,
+    pub to_client: Option<ClientId>,
+    pub requires_kyc: Option<bool>,
+    pub reason: Option<String>,
+    pub authority: Option<String>,
+}
+
+impl Transaction {
+    pub const fn new(
+        transaction_type: TransactionType,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<Amount>,
+    ) -> Self {
+        Self {
+            transaction_type,
+            client,
+            tx,
+            amount,
+            to_client: None,
+            requires_kyc: None,
+            reason: None,
+            authority: None,
+        }
+    }
+
+    /// Disputes, resolves, chargebacks, regulatory releases and KYC approvals reference other
+    /// transactions, an amount on them is dropped when converting to [`StoredTransaction`].
+    pub const fn has_unexpected_amount(&self) -> bool {
+        self.amount.is_some()
+            && matches!(
+                self.transaction_type,
+                TransactionType::Dispute
+                    | TransactionType::Resolve
+                    | TransactionType::Chargeback
+                    | TransactionType::RegulatoryRelease
+                    | TransactionType::KycApproved
+            )
+    }
+
+    pub const fn with_to_client(mut self, to_client: ClientId) -> Self {
+        self.to_client = Some(to_client);
+        self
+    }
+
+    pub const fn with_requires_kyc(mut self, requires_kyc: bool) -> Self {
+        self.requires_kyc = Some(requires_kyc);
+        self
+    }
+
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    pub fn with_authority(mut self, authority: impl Into<String>) -> Self {
+        self.authority = Some(authority.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum StoredTransaction {
+    Deposit {
+        id: TransactionId,
+        client_id: ClientId,
+        amount: Amount,
+        under_dispute: bool,
+        requires_kyc: bool,
+        processor_id: Option<String>,
+    },
+    Withdrawal {
+        id: TransactionId,
+        client_id: ClientId,
+        amount: Amount,
+        #[cfg_attr(feature = "serde", serde(default))]
+        under_dispute: bool,
+        processor_id: Option<String>,
+    },
+    Transfer {
+        id: TransactionId,
+        client_id: ClientId,
+        to_client: ClientId,
+        amount: Amount,
+    },
+    Dispute {
+        id: TransactionId,
+        client_id: ClientId,
+    },
+    Resolve {
+        id: TransactionId,
+        client_id: ClientId,
+    },
+    Chargeback {
+        id: TransactionId,
+        client_id: ClientId,
+    },
+    RegulatoryHold {
+        id: TransactionId,
+        client_id: ClientId,
+        amount: Amount,
+        reason: String,
+        authority: String,
+        released: bool,
+    },
+    RegulatoryRelease {
+        id: TransactionId,
+        client_id: ClientId,
+    },
+    KycApproved {
+        id: TransactionId,
+        client_id: ClientId,
+    },
+    Refund {
+        id: TransactionId,
+        client_id: ClientId,
+        amount: Amount,
+    },
+    /// Fee charged on the deposit with the same id
+    Fee {
+        id: TransactionId,
+        client_id: ClientId,
+        amount: Amount,
+    },
+}
+
+impl StoredTransaction {
+    pub const fn id(&self) -> &TransactionId {
+        match self {
+            Self::Deposit { id, .. }
+            | Self::Withdrawal { id, .. }
+            | Self::Transfer { id, .. }
+            | Self::Dispute { id, .. }
+            | Self::Resolve { id, .. }
+            | Self::Chargeback { id, .. }
+            | Self::RegulatoryHold { id, .. }
+            | Self::RegulatoryRelease { id, .. }
+            | Self::KycApproved { id, .. }
+            | Self::Refund { id, .. }
+            | Self::Fee { id, .. } => id,
+        }
+    }
+
+    pub const fn client_id(&self) -> &ClientId {
+        match self {
+            Self::Deposit { client_id, .. }
+            | Self::Withdrawal { client_id, .. }
+            | Self::Transfer { client_id, .. }
+            | Self::Dispute { client_id, .. }
+            | Self::Resolve { client_id, .. }
+            | Self::Chargeback { client_id, .. }
+            | Self::RegulatoryHold { client_id, .. }
+            | Self::RegulatoryRelease { client_id, .. }
+            | Self::KycApproved { client_id, .. }
+            | Self::Refund { client_id, .. }
+            | Self::Fee { client_id, .. } => client_id,
+        }
+    }
+
+    pub const fn transaction_type(&self) -> TransactionType {
+        match self {
+            Self::Deposit { .. } => TransactionType::Deposit,
+            Self::Withdrawal { .. } => TransactionType::Withdrawal,
+            Self::Transfer { .. } => TransactionType::Transfer,
+            Self::Dispute { .. } => TransactionType::Dispute,
+            Self::Resolve { .. } => TransactionType::Resolve,
+            Self::Chargeback { .. } => TransactionType::Chargeback,
+            Self::RegulatoryHold { .. } => TransactionType::RegulatoryHold,
+            Self::RegulatoryRelease { .. } => TransactionType::RegulatoryRelease,
+            Self::KycApproved { .. } => TransactionType::KycApproved,
+            Self::Refund { .. } => TransactionType::Refund,
+            Self::Fee { .. } => TransactionType::Fee,
+        }
+    }
+
+    pub const fn amount(&self) -> Option<&Amount> {
+        match self {
+            Self::Deposit { amount, .. }
+            | Self::Withdrawal { amount, .. }
+            | Self::Transfer { amount, .. }
+            | Self::RegulatoryHold { amount, .. }
+            | Self::Fee { amount, .. } => Some(amount),
+            _ => None,
+        }
+    }
+
+    pub fn is_not_valid(&self) -> bool {
+        match self {
+            Self::Deposit { amount, .. } => amount < &Amount::ZERO,
+            Self::Withdrawal { amount, .. } => amount < &Amount::ZERO,
+            Self::Transfer {
+                client_id,
+                to_client,
+                amount,
+                ..
+            } => amount < &Amount::ZERO || client_id == to_client,
+            Self::RegulatoryHold { amount, .. } => amount < &Amount::ZERO,
+            Self::Refund { amount, .. } => amount < &Amount::ZERO,
+            Self::Fee { amount, .. } => amount < &Amount::ZERO,
+            _ => false,
+        }
+    }
+
+    /// Whether the transaction is stored under its own id. Disputes, resolves, chargebacks,
+    /// regulatory releases and KYC approvals reference other transactions and fees share
+    /// their deposit's id, so these are only journaled.
+    pub const fn is_stored(&self) -> bool {
+        matches!(
+            self,
+            Self::Deposit { .. }
+                | Self::Withdrawal { .. }
+                | Self::Transfer { .. }
+                | Self::RegulatoryHold { .. }
+                | Self::Refund { .. }
+        )
+    }
+
+    pub fn set_under_dispute(&mut self, is_under_dispute: bool) {
+        if let StoredTransaction::Deposit {
+            ref mut under_dispute,
+            ..
+        }
+        | StoredTransaction::Withdrawal {
+            ref mut under_dispute,
+            ..
+        } = self
+        {
+            *under_dispute = is_under_dispute;
+        }
+    }
+
+    pub fn is_under_dispute(&self) -> bool {
+        matches!(
+            self,
+            StoredTransaction::Deposit {
+                under_dispute: true,
+                ..
+            } | StoredTransaction::Withdrawal {
+                under_dispute: true,
+                ..
+            }
+        )
+    }
+
+    pub fn set_kyc_approved(&mut self) {
+        if let StoredTransaction::Deposit {
+            ref mut requires_kyc,
+            ..
+        } = self
+        {
+            *requires_kyc = false;
+        }
+    }
+
+    pub fn set_client_id(&mut self, from: ClientId, to: ClientId) {
+        match self {
+            Self::Transfer {
+                client_id,
+                to_client,
+                ..
+            } => {
+                for id in [client_id, to_client] {
+                    if *id == from {
+                        *id = to;
+                    }
+                }
+            }
+            Self::Deposit { client_id, .. }
+            | Self::Withdrawal { client_id, .. }
+            | Self::Dispute { client_id, .. }
+            | Self::Resolve { client_id, .. }
+            | Self::Chargeback { client_id, .. }
+            | Self::RegulatoryHold { client_id, .. }
+            | Self::RegulatoryRelease { client_id, .. }
+            | Self::KycApproved { client_id, .. }
+            | Self::Refund { client_id, .. }
+            | Self::Fee { client_id, .. } => {
+                if *client_id == from {
+                    *client_id = to;
+                }
+            }
+        }
+    }
+
+    pub fn set_processor_id(&mut self, id: &str) {
+        if let Self::Deposit { processor_id, .. } | Self::Withdrawal { processor_id, .. } = self {
+            *processor_id = Some(id.into());
+        }
+    }
+
+    pub fn processor_id(&self) -> Option<&str> {
+        match self {
+            Self::Deposit { processor_id, .. } | Self::Withdrawal { processor_id, .. } => {
+                processor_id.as_deref()
+            }
+            _ => None,
+        }
+    }
+
+    pub fn set_released(&mut self, is_released: bool) {
+        if let StoredTransaction::RegulatoryHold {
+            ref mut released, ..
+        } = self
+        {
+            *released = is_released;
+        }
+    }
+}
+
+/// Hashes the variant and id only, consistent with equality since equal transactions share
+/// both. Transactions differing in other fields, e.g. a disputed and an undisputed copy,
+/// still compare unequal.
+impl Hash for StoredTransaction {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        self.id().hash(state);
+    }
+}
+
+impl From<Transaction> for StoredTransaction {
+    fn from(tx: Transaction) -> Self {
+        match tx.transaction_type {
+            TransactionType::Deposit => Self::Deposit {
+                id: tx.tx,
+                client_id: tx.client,
+                amount: tx.amount.unwrap_or_default(),
+                under_dispute: false,
+                requires_kyc: tx.requires_kyc.unwrap_or_default(),
+                processor_id: None,
+            },
+            TransactionType::Withdrawal => Self::Withdrawal {
+                id: tx.tx,
+                client_id: tx.client,
+                amount: tx.amount.unwrap_or_default(),
+                under_dispute: false,
+                processor_id: None,
+            },
+            TransactionType::Transfer => Self::Transfer {
+                id: tx.tx,
+                client_id: tx.client,
+                to_client: tx.to_client.unwrap_or(tx.client),
+                amount: tx.amount.unwrap_or_default(),
+            },
+            TransactionType::Dispute => Self::Dispute {
+                id: tx.tx,
+                client_id: tx.client,
+            },
+            TransactionType::Resolve => Self::Resolve {
+                id: tx.tx,
+                client_id: tx.client,
+            },
+            TransactionType::Chargeback => Self::Chargeback {
+                id: tx.tx,
+                client_id: tx.client,
+            },
+            TransactionType::RegulatoryHold => Self::RegulatoryHold {
+                id: tx.tx,
+                client_id: tx.client,
+                amount: tx.amount.unwrap_or_default(),
+                reason: tx.reason.unwrap_or_default(),
+                authority: tx.authority.unwrap_or_default(),
+                released: false,
+            },
+            TransactionType::RegulatoryRelease => Self::RegulatoryRelease {
+                id: tx.tx,
+                client_id: tx.client,
+            },
+            TransactionType::KycApproved => Self::KycApproved {
+                id: tx.tx,
+                client_id: tx.client,
+            },
+            TransactionType::Refund => Self::Refund {
+                id: tx.tx,
+                client_id: tx.client,
+                amount: tx.amount.unwrap_or_default(),
+            },
+            TransactionType::Fee => Self::Fee {
+                id: tx.tx,
+                client_id: tx.client,
+                amount: tx.amount.unwrap_or_default(),
+            },
+        }
+    }
+}
+
+impl From<(TransactionType, ClientId, TransactionId, Option<Amount>)> for StoredTransaction {
+    fn from(
+        (transaction_type, client, tx, amount): (
+            TransactionType,
+            ClientId,
+            TransactionId,
+            Option<Amount>,
+        ),
+    ) -> Self {
+        Transaction::new(transaction_type, client, tx, amount).into()
+    }
+}
+
+/// Accounts are ordered by `client` first, `risk_score` is a cache and isn't compared.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub struct Account {
+    pub client: ClientId,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub version: u32,
+    #[cfg_attr(feature = "serde", serde(rename = "chargebacks", default))]
+    pub chargeback_count: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub kyc_pending: Amount,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub closed: bool,
+    /// Cached [`RiskScore::score`], recomputed whenever the account is stored. A copy changed
+    /// since it was stored keeps the score of the stored version.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub risk_score: Option<f64>,
+}
+
+impl Account {
+    #[allow(clippy::type_complexity)]
+    fn comparison_key(
+        &self,
+    ) -> (
+        ClientId,
+        Amount,
+        Amount,
+        Amount,
+        bool,
+        u32,
+        u32,
+        Amount,
+        bool,
+    ) {
+        (
+            self.client,
+            self.available,
+            self.held,
+            self.total,
+            self.locked,
+            self.version,
+            self.chargeback_count,
+            self.kyc_pending,
+            self.closed,
+        )
+    }
+}
+
+impl PartialEq for Account {
+    fn eq(&self, other: &Self) -> bool {
+        self.comparison_key() == other.comparison_key()
+    }
+}
+
+impl Eq for Account {}
+
+impl PartialOrd for Account {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Account {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.comparison_key().cmp(&other.comparison_key())
+    }
+}
+
+/// Risk of an account between 0 and 100, the higher the riskier: 50 for a locked account,
+/// 10 per chargeback up to 30 and up to 20 for the share of the total balance on hold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RiskScore {
+    pub score: f64,
+}
+
+impl RiskScore {
+    const LOCKED: f64 = 50.0;
+    const PER_CHARGEBACK: f64 = 10.0;
+    const MAX_CHARGEBACKS: u32 = 3;
+    const HELD_SHARE: f64 = 20.0;
+
+    pub fn of(account: &Account) -> Self {
+        use rust_decimal::prelude::ToPrimitive;
+
+        let mut score = 0.0;
+        if account.locked {
+            score += Self::LOCKED;
+        }
+        score +=
+            Self::PER_CHARGEBACK * f64::from(account.chargeback_count.min(Self::MAX_CHARGEBACKS));
+        if account.total > Amount::ZERO && account.held > Amount::ZERO {
+            let share = (account.held / account.total).min(Decimal::ONE);
+            score += Self::HELD_SHARE * share.to_f64().unwrap_or(1.0);
+        }
+        Self { score }
+    }
+}
+
+impl Account {
+    pub const fn new(client: ClientId) -> Self {
+        Self {
+            client,
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            total: Amount::ZERO,
+            locked: false,
+            version: 0,
+            chargeback_count: 0,
+            kyc_pending: Amount::ZERO,
+            closed: false,
+            risk_score: None,
+        }
+    }
+
+    /// Cached risk score if there is one, computed otherwise
+    pub fn risk_score(&self) -> RiskScore {
+        self.risk_score
+            .map(|score| RiskScore { score })
+            .unwrap_or_else(|| RiskScore::of(self))
+    }
+
+    pub fn scaled(&mut self) {
+        self.available = scale_to_amount_precision(self.available);
+        self.held = scale_to_amount_precision(self.held);
+        self.total = scale_to_amount_precision(self.total);
+    }
+
+    /// Adds the other account's balances to this one, the result is locked if either is.
+    pub fn merge(&mut self, other: &Account) -> ProcessingResult<()> {
+        let overflow = || ProcessingError::AmountOverflow {
+            client_id: self.client,
+        };
+        let available = self
+            .available
+            .checked_add(other.available)
+            .ok_or_else(overflow)?;
+        let held = self.held.checked_add(other.held).ok_or_else(overflow)?;
+        let total = self.total.checked_add(other.total).ok_or_else(overflow)?;
+        let kyc_pending = self
+            .kyc_pending
+            .checked_add(other.kyc_pending)
+            .ok_or_else(overflow)?;
+        self.available = available;
+        self.held = held;
+        self.total = total;
+        self.kyc_pending = kyc_pending;
+        self.locked |= other.locked;
+        self.closed |= other.closed;
+        self.chargeback_count += other.chargeback_count;
+        Ok(())
+    }
+
+    pub fn is_high_value(&self, threshold: Amount) -> bool {
+        self.total >= threshold
+    }
+
+    pub fn tier(&self, config: &TierConfig) -> AccountTier {
+        if self.is_high_value(config.vip_threshold) {
+            AccountTier::Vip
+        } else if self.is_high_value(config.premium_threshold) {
+            AccountTier::Premium
+        } else {
+            AccountTier::Standard
+        }
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut account = self.clone();
+        account.scaled();
+        write!(
+            f,
+            "Client {}: available={:.4} held={:.4} total={:.4}",
+            account.client, account.available, account.held, account.total
+        )?;
+        if account.locked {
+            write!(f, " [LOCKED]")?;
+        }
+        if account.closed {
+            write!(f, " [CLOSED]")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum AccountTier {
+    Standard,
+    Premium,
+    Vip,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TierConfig {
+    pub premium_threshold: Amount,
+    pub vip_threshold: Amount,
+}
+
+/// Fee charged on every deposit, `flat` plus `percentage` percent of the deposited amount
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FeeSchedule {
+    pub flat: Amount,
+    pub percentage: Decimal,
+}
+
+impl FeeSchedule {
+    /// `None` when the fee doesn't fit an [`Amount`]
+    pub fn fee_for(&self, amount: &Amount) -> Option<Amount> {
+        let rate = self.percentage.checked_div(Decimal::ONE_HUNDRED)?;
+        let fee = amount.checked_mul(rate)?.checked_add(self.flat)?;
+        Some(fee.round_dp_with_strategy(AMOUNT_PRECISION, RoundingStrategy::MidpointAwayFromZero))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AccountDelta {
+    pub client_id: ClientId,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+    pub chargeback_count: u32,
+    pub kyc_pending: Amount,
+}
+
+impl AccountDelta {
+    pub fn between(before: &Account, after: &Account) -> Self {
+        Self {
+            client_id: after.client,
+            available: after.available - before.available,
+            held: after.held - before.held,
+            total: after.total - before.total,
+            locked: after.locked && !before.locked,
+            chargeback_count: after.chargeback_count - before.chargeback_count,
+            kyc_pending: after.kyc_pending - before.kyc_pending,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ReconciliationDiff {
+    pub client_id: ClientId,
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VelocityMetrics {
+    pub transaction_count: usize,
+    pub deposit_count: usize,
+    pub total_deposited: Amount,
+    pub unique_amounts: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SequenceWarning {
+    pub index: usize,
+    pub tx_id: TransactionId,
+    pub warning: String,
+}
+
+/// Outcome of processing transactions without keeping their effects
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DryRunReport {
+    pub succeeded: usize,
+    pub rejected: Vec<(Transaction, ProcessingError)>,
+}
+
+/// Outcome of processing a stream of records, malformed records and rejected transactions
+/// count as failed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessingStats {
+    pub attempted: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub malformed: usize,
+    pub rejected: Vec<(Transaction, ProcessingError)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct StatementEntry {
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub transaction_type: TransactionType,
+    pub tx: TransactionId,
+    pub amount: Option<Amount>,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+}
+
+impl StatementEntry {
+    pub fn new(transaction: &StoredTransaction, account: &Account) -> Self {
+        Self {
+            transaction_type: transaction.transaction_type(),
+            tx: *transaction.id(),
+            amount: transaction.amount().copied(),
+            available: account.available,
+            held: account.held,
+            total: account.total,
+        }
+    }
+
+    pub fn scaled(&mut self) {
+        self.amount = self.amount.map(scale_to_amount_precision);
+        self.available = scale_to_amount_precision(self.available);
+        self.held = scale_to_amount_precision(self.held);
+        self.total = scale_to_amount_precision(self.total);
+    }
+}
+
+fn scale_to_amount_precision(mut amount: Amount) -> Amount {
+    if amount.scale() > AMOUNT_PRECISION {
+        amount.rescale(AMOUNT_PRECISION);
+    }
+    amount
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn split_gives_the_rounding_remainder_to_the_first_amount() {
+        let amounts = dec!(10).split(3);
+
+        assert_eq!(amounts, vec![dec!(3.3334), dec!(3.3333), dec!(3.3333)]);
+        assert_eq!(amounts.iter().sum::<Amount>(), dec!(10));
+    }
+
+    #[test]
+    fn split_sums_up_to_the_amount_at_4_decimal_places() {
+        for (amount, parts) in [(dec!(1), 7), (dec!(0.0001), 3), (dec!(123.4567), 9)] {
+            let amounts = amount.split(parts);
+
+            assert_eq!(amounts.len(), parts);
+            assert_eq!(amounts.iter().sum::<Amount>(), amount);
+            assert!(amounts.iter().all(|part| part.scale() <= AMOUNT_PRECISION));
+            assert!(amounts[1..].iter().all(|part| *part == amounts[1]));
+        }
+        assert_eq!(dec!(1).split(7)[0], dec!(0.1432));
+        assert_eq!(dec!(0.0001).split(3), vec![dec!(0.0001), dec!(0), dec!(0)]);
+    }
+
+    #[test]
+    fn split_into_no_parts_is_empty() {
+        assert_eq!(dec!(5).split(0), Vec::new());
+    }
+
+    #[test]
+    fn fee_for_is_none_when_the_fee_overflows() {
+        let fee_schedule = FeeSchedule {
+            flat: dec!(1),
+            percentage: dec!(2),
+        };
+
+        assert_eq!(
+            fee_schedule.fee_for(&dec!(5e28)),
+            Some(dec!(1000000000000000000000000001))
+        );
+        assert_eq!(fee_schedule.fee_for(&dec!(0.005)), Some(dec!(1.0001)));
+        let above_amount = FeeSchedule {
+            flat: dec!(0),
+            percentage: dec!(300),
+        };
+        assert_eq!(above_amount.fee_for(&dec!(5e28)), None);
+    }
+}
@@ -0,0 +1,11 @@
+#![no_std]
+#![deny(clippy::let_underscore_must_use)]
+
+extern crate alloc;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+
+pub mod api;
+pub mod domain;
+pub mod sharding;
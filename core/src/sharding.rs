@@ -0,0 +1,40 @@
+//! Client sharding for deployments running several `TransactionProcessor` instances.
+//!
+//! Every client belongs to exactly one shard, [`shard_for_client`], and each shard's
+//! processor owns the accounts and stored transactions of its clients. Routing incoming
+//! transactions by their `client` keeps all processing local to one shard:
+//!
+//! - deposits, withdrawals and refunds only touch the client's own account
+//! - disputes, resolves and chargebacks only find transactions of the disputing client, so
+//!   they always refer to a transaction stored on the same shard. A dispute of another
+//!   client's transaction is ignored exactly as it is on a single shard.
+//! - transfers touch the accounts of `client` and `to_client`, which can't be updated
+//!   atomically on two shards. Such transfers must be rejected before routing, see
+//!   [`Transaction::client_crosses_shard_boundary`].
+//!
+//! All processors must agree on the number of shards, changing it moves clients between
+//! shards together with their accounts and transactions.
+
+use crate::domain::{ClientId, Transaction};
+
+/// Shard owning the client's account and transactions.
+///
+/// # Panics
+///
+/// If `num_shards` is zero.
+// `ClientId` is already `u32` with `large_client_ids`
+#[allow(clippy::useless_conversion)]
+pub fn shard_for_client(client_id: ClientId, num_shards: u16) -> u16 {
+    // The remainder is less than `num_shards`, so it fits into `u16`
+    (u32::from(client_id) % u32::from(num_shards)) as u16
+}
+
+impl Transaction {
+    /// Whether the transaction touches a client on another shard than `client`'s, which is
+    /// only the case for transfers
+    pub fn client_crosses_shard_boundary(&self, num_shards: u16) -> bool {
+        self.to_client.is_some_and(|to_client| {
+            shard_for_client(to_client, num_shards) != shard_for_client(self.client, num_shards)
+        })
+    }
+}
@@ -1,32 +1,35 @@
 use thiserror::Error;
 
-use crate::domain::{ClientId, TransactionId};
+pub use transaction_processor_core::api::{
+    ErrorSource, ProcessingError, ProcessingResult, UnknownTransactionType,
+};
+
+use crate::domain::{Amount, ClientId, TransactionId};
 
 #[derive(Error, Debug, PartialEq, Eq)]
-pub enum ProcessingError {
-    #[error("Transaction with id {id} is not valid")]
-    TransactionIsNotValid { id: TransactionId },
-    #[error("Transaction with id {id} not found")]
-    TransactionNotFound { id: TransactionId },
-    #[error("Transaction with id {id} already exists")]
-    TransactionAlreadyExists { id: TransactionId },
-    #[error("Transaction with id {id} already under dispute")]
-    TransactionAlreadyUnderDispute { id: TransactionId },
-    #[error("Transaction with id {id} is not disputable")]
-    TransactionIsNotDisputable { id: TransactionId },
-    #[error("Transaction with id {id} can't be accessed by client with id {client_id}")]
-    TransactionAccessDenied {
+pub enum ConsistencyError {
+    #[error("Client {client_id} account total {total} is not equal to available {available} plus held {held}")]
+    TotalMismatch {
+        client_id: ClientId,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+    },
+    #[error("Client {client_id} account has negative {field} balance {amount}")]
+    NegativeBalance {
+        client_id: ClientId,
+        field: &'static str,
+        amount: Amount,
+    },
+    #[error("Client {client_id} account held {held} does not cover {on_hold} on hold")]
+    InsufficientHeldFunds {
+        client_id: ClientId,
+        held: Amount,
+        on_hold: Amount,
+    },
+    #[error("Transaction with id {id} on hold has no client {client_id} account")]
+    MissingAccount {
         id: TransactionId,
         client_id: ClientId,
     },
-    #[error("Client {client_id} account has insufficient available funds")]
-    AccountInsufficientAvailableFunds { client_id: ClientId },
-    #[error("Client {client_id} account has insufficient held funds")]
-    AccountInsufficientHeldFunds { client_id: ClientId },
-    #[error("Client {client_id} account is locked")]
-    AccountIsLocked { client_id: ClientId },
-    #[error("Unknown error: {0}")]
-    UnknownError(String),
 }
-
-pub type ProcessingResult<T> = Result<T, ProcessingError>;
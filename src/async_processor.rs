@@ -0,0 +1,53 @@
+use std::sync::Arc;
+
+use crate::{
+    api::ProcessingResult,
+    async_state::AsyncStateStorage,
+    domain::{Account, StoredTransaction, Transaction},
+    metrics::ProcessingMetrics,
+    processor::{ProcessorBuilder, TransactionProcessor},
+};
+
+/// Async counterpart of [`TransactionProcessor`] for Tokio services. Transactions are
+/// processed by the same logic as the synchronous processor while holding the storage's
+/// async write lock, so waiting for the storage never blocks the executor thread.
+pub struct AsyncTransactionProcessor<S: AsyncStateStorage> {
+    state: S,
+    processor: TransactionProcessor<Arc<S::Storage>>,
+}
+
+impl<S: AsyncStateStorage> AsyncTransactionProcessor<S> {
+    pub fn new(state: S) -> Self {
+        Self::with_config(state, |builder| builder)
+    }
+
+    /// Configures the processing the same way as [`TransactionProcessor::builder`]
+    pub fn with_config(
+        state: S,
+        configure: impl FnOnce(ProcessorBuilder<Arc<S::Storage>>) -> ProcessorBuilder<Arc<S::Storage>>,
+    ) -> Self {
+        let processor = configure(TransactionProcessor::builder(state.storage().clone())).build();
+        Self { state, processor }
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub async fn process_transaction(&self, transaction: Transaction) -> ProcessingResult<()> {
+        let _guard = self.state.write().await;
+        self.processor.process_transaction(transaction)
+    }
+
+    pub async fn process(&self, transaction: StoredTransaction) -> ProcessingResult<()> {
+        let _guard = self.state.write().await;
+        self.processor.process(transaction)
+    }
+    pub async fn get_accounts(&self) -> ProcessingResult<Vec<Account>> {
+        self.state.get_all_accounts().await
+    }
+
+    pub fn metrics(&self) -> ProcessingMetrics {
+        self.processor.metrics()
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::sync::{watch, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{
+    api::ProcessingResult,
+    domain::{
+        Account, AccountTier, AccountVersion, Amount, BalanceSnapshot, ClientId, JournalEntry,
+        RiskScore, StoredTransaction, TierConfig, TransactionId,
+    },
+    state::{State, StateStorage},
+};
+
+/// Async counterpart of [`StateStorage`] for Tokio services. Every call waits on a
+/// `tokio::sync::RwLock` instead of blocking the executor thread, then runs on the wrapped
+/// synchronous storage, whose own locks are never contended while the async lock is held.
+/// Snapshots and state transactions are only available on the synchronous storage.
+pub trait AsyncStateStorage: Send + Sync {
+    type Storage: StateStorage;
+
+    /// The wrapped storage, only to be used while holding [`read`](Self::read) or
+    /// [`write`](Self::write)
+    fn storage(&self) -> &Arc<Self::Storage>;
+    fn read(&self) -> impl Future<Output = RwLockReadGuard<'_, ()>> + Send;
+    fn write(&self) -> impl Future<Output = RwLockWriteGuard<'_, ()>> + Send;
+
+    fn get_transaction(
+        &self,
+        id: TransactionId,
+    ) -> impl Future<Output = ProcessingResult<StoredTransaction>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_transaction(id)
+        }
+    }
+
+    fn get_transaction_for_client(
+        &self,
+        id: TransactionId,
+        client_id: ClientId,
+    ) -> impl Future<Output = ProcessingResult<StoredTransaction>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_transaction_for_client(id, client_id)
+        }
+    }
+
+    fn contains_transaction(
+        &self,
+        id: TransactionId,
+    ) -> impl Future<Output = ProcessingResult<bool>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().contains_transaction(id)
+        }
+    }
+
+    fn insert_transaction(
+        &self,
+        transaction: StoredTransaction,
+    ) -> impl Future<Output = ProcessingResult<StoredTransaction>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().insert_transaction(transaction)
+        }
+    }
+
+    fn remove_transaction(
+        &self,
+        id: TransactionId,
+    ) -> impl Future<Output = ProcessingResult<()>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().remove_transaction(id)
+        }
+    }
+
+    fn under_dispute(
+        &self,
+        id: TransactionId,
+        under_dispute: bool,
+    ) -> impl Future<Output = ProcessingResult<()>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().under_dispute(id, under_dispute)
+        }
+    }
+
+    fn release_hold(&self, id: TransactionId) -> impl Future<Output = ProcessingResult<()>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().release_hold(id)
+        }
+    }
+
+    fn approve_kyc(
+        &self,
+        client_id: ClientId,
+    ) -> impl Future<Output = ProcessingResult<()>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().approve_kyc(client_id)
+        }
+    }
+
+    fn get_transactions_for_client(
+        &self,
+        id: ClientId,
+    ) -> impl Future<Output = ProcessingResult<Vec<JournalEntry>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_transactions_for_client(id)
+        }
+    }
+
+    fn get_journal_entries_until(
+        &self,
+        end: SystemTime,
+    ) -> impl Future<Output = ProcessingResult<Vec<JournalEntry>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_journal_entries_until(end)
+        }
+    }
+
+    fn get_clients_with_no_activity_since(
+        &self,
+        cutoff: SystemTime,
+    ) -> impl Future<Output = ProcessingResult<Vec<ClientId>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_clients_with_no_activity_since(cutoff)
+        }
+    }
+
+    fn get_transactions_in_amount_range(
+        &self,
+        min: Amount,
+        max: Amount,
+    ) -> impl Future<Output = ProcessingResult<Vec<StoredTransaction>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_transactions_in_amount_range(min, max)
+        }
+    }
+
+    fn get_transactions_by_processor(
+        &self,
+        processor_id: &str,
+    ) -> impl Future<Output = ProcessingResult<Vec<StoredTransaction>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_transactions_by_processor(processor_id)
+        }
+    }
+
+    fn get_orphaned_transactions(
+        &self,
+    ) -> impl Future<Output = ProcessingResult<Vec<StoredTransaction>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_orphaned_transactions()
+        }
+    }
+
+    fn transaction_count(&self) -> impl Future<Output = ProcessingResult<usize>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().transaction_count()
+        }
+    }
+
+    fn count_open_disputes(
+        &self,
+        client_id: ClientId,
+    ) -> impl Future<Output = ProcessingResult<u32>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().count_open_disputes(client_id)
+        }
+    }
+    fn get_all_accounts(&self) -> impl Future<Output = ProcessingResult<Vec<Account>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_all_accounts()
+        }
+    }
+
+    fn get_accounts_sorted_by_risk(
+        &self,
+    ) -> impl Future<Output = ProcessingResult<Vec<(Account, RiskScore)>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_accounts_sorted_by_risk()
+        }
+    }
+
+    fn get_account(&self, id: &ClientId) -> impl Future<Output = ProcessingResult<Account>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_account(id)
+        }
+    }
+
+    fn with_account<F, R>(
+        &self,
+        id: ClientId,
+        f: F,
+    ) -> impl Future<Output = ProcessingResult<R>> + Send
+    where
+        F: FnOnce(&Account) -> R + Send,
+        R: Send,
+    {
+        async move {
+            let _guard = self.read().await;
+            self.storage().with_account(id, f)
+        }
+    }
+
+    fn account_count(&self) -> impl Future<Output = ProcessingResult<usize>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().account_count()
+        }
+    }
+
+    fn get_accounts_with_zero_balance(
+        &self,
+    ) -> impl Future<Output = ProcessingResult<Vec<Account>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_accounts_with_zero_balance()
+        }
+    }
+
+    fn get_clients_exceeding_balance_threshold(
+        &self,
+        threshold: Amount,
+    ) -> impl Future<Output = ProcessingResult<Vec<ClientId>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage()
+                .get_clients_exceeding_balance_threshold(threshold)
+        }
+    }
+
+    fn get_accounts_by_tier(
+        &self,
+        config: &TierConfig,
+    ) -> impl Future<Output = ProcessingResult<HashMap<AccountTier, Vec<Account>>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_accounts_by_tier(config)
+        }
+    }
+
+    fn remove_account(&self, id: ClientId) -> impl Future<Output = ProcessingResult<()>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().remove_account(id)
+        }
+    }
+
+    fn migrate_client_id(
+        &self,
+        old_id: ClientId,
+        new_id: ClientId,
+        merge: bool,
+    ) -> impl Future<Output = ProcessingResult<()>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().migrate_client_id(old_id, new_id, merge)
+        }
+    }
+
+    fn register_delegation(
+        &self,
+        from: ClientId,
+        to: ClientId,
+    ) -> impl Future<Output = ProcessingResult<()>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().register_delegation(from, to)
+        }
+    }
+
+    fn resolve_delegation(
+        &self,
+        id: ClientId,
+    ) -> impl Future<Output = ProcessingResult<ClientId>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().resolve_delegation(id)
+        }
+    }
+
+    fn upsert_account(
+        &self,
+        account: Account,
+        triggered_by: TransactionId,
+    ) -> impl Future<Output = ProcessingResult<()>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().upsert_account(account, triggered_by)
+        }
+    }
+
+    fn upsert_accounts(
+        &self,
+        accounts: Vec<Account>,
+        triggered_by: TransactionId,
+    ) -> impl Future<Output = ProcessingResult<()>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().upsert_accounts(accounts, triggered_by)
+        }
+    }
+
+    fn update_account<'a>(
+        &'a self,
+        id: ClientId,
+        triggered_by: TransactionId,
+        update: &'a mut (dyn FnMut(&mut Account) -> ProcessingResult<()> + Send),
+    ) -> impl Future<Output = ProcessingResult<()>> + Send + 'a {
+        async move {
+            let _guard = self.write().await;
+            self.storage().update_account(id, triggered_by, update)
+        }
+    }
+
+    fn update_accounts<'a>(
+        &'a self,
+        from: ClientId,
+        to: ClientId,
+        triggered_by: TransactionId,
+        update: &'a mut (dyn FnMut(&mut Account, &mut Account) -> ProcessingResult<()> + Send),
+    ) -> impl Future<Output = ProcessingResult<()>> + Send + 'a {
+        async move {
+            let _guard = self.write().await;
+            self.storage()
+                .update_accounts(from, to, triggered_by, update)
+        }
+    }
+
+    fn balance_history(
+        &self,
+        id: ClientId,
+    ) -> impl Future<Output = ProcessingResult<Vec<BalanceSnapshot>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().balance_history(id)
+        }
+    }
+
+    fn get_account_versions(
+        &self,
+        id: ClientId,
+    ) -> impl Future<Output = ProcessingResult<Vec<AccountVersion>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().get_account_versions(id)
+        }
+    }
+
+    fn compact_account_history(
+        &self,
+        id: ClientId,
+        keep_last: usize,
+    ) -> impl Future<Output = ProcessingResult<()>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().compact_account_history(id, keep_last)
+        }
+    }
+
+    fn subscribe_to_account_updates(
+        &self,
+        id: ClientId,
+    ) -> impl Future<Output = ProcessingResult<watch::Receiver<Account>>> + Send {
+        async move {
+            let _guard = self.read().await;
+            self.storage().subscribe_to_account_updates(id)
+        }
+    }
+
+    fn clear(&self) -> impl Future<Output = ProcessingResult<()>> + Send {
+        async move {
+            let _guard = self.write().await;
+            self.storage().clear()
+        }
+    }
+}
+
+/// [`State`] behind a `tokio::sync::RwLock`
+pub struct AsyncState<S: StateStorage = State> {
+    state: Arc<S>,
+    lock: RwLock<()>,
+}
+
+impl<S: StateStorage> AsyncState<S> {
+    pub fn new(state: S) -> Self {
+        Self {
+            state: Arc::new(state),
+            lock: RwLock::new(()),
+        }
+    }
+}
+
+impl<S: StateStorage + Default> Default for AsyncState<S> {
+    fn default() -> Self {
+        Self::new(S::default())
+    }
+}
+
+impl<S: StateStorage> AsyncStateStorage for AsyncState<S> {
+    type Storage = S;
+
+    fn storage(&self) -> &Arc<S> {
+        &self.state
+    }
+
+    fn read(&self) -> impl Future<Output = RwLockReadGuard<'_, ()>> + Send {
+        self.lock.read()
+    }
+
+    fn write(&self) -> impl Future<Output = RwLockWriteGuard<'_, ()>> + Send {
+        self.lock.write()
+    }
+}
@@ -0,0 +1,991 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::hash::Hash;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use tokio::sync::watch;
+
+use crate::{
+    api::{ProcessingError, ProcessingResult},
+    domain::{
+        Account, AccountTier, AccountVersion, Amount, BalanceSnapshot, ClientId, JournalEntry,
+        RiskScore, StoredTransaction, TierConfig, TransactionId,
+    },
+    state::{
+        check_balance_invariant, check_distinct_clients, delegation_target, sorted_by_risk,
+        storage_poisoned, StateStorage, DEFAULT_BALANCE_HISTORY_DEPTH,
+        DEFAULT_MAX_ACCOUNT_VERSIONS,
+    },
+};
+
+/// `StateStorage` backed by sharded concurrent maps. Accounts are updated through
+/// `DashMap::entry`, which holds the shard lock of that one account for the whole
+/// read-modify-write instead of locking every account twice.
+pub struct ConcurrentState {
+    accounts: Arc<DashMap<ClientId, Account>>,
+    /// Single-account writes share it, `update_accounts` takes it exclusively since a
+    /// `DashMap` can't hold the entries of two accounts that may live in the same shard
+    account_writes: RwLock<()>,
+    balance_index: RwLock<BTreeSet<(Amount, ClientId)>>,
+    transactions: DashMap<TransactionId, StoredTransaction>,
+    amount_index: RwLock<BTreeMap<Amount, Vec<TransactionId>>>,
+    journal: RwLock<Vec<JournalEntry>>,
+    account_subscribers: DashMap<ClientId, watch::Sender<Account>>,
+    delegations: RwLock<HashMap<ClientId, ClientId>>,
+    balance_history: DashMap<ClientId, VecDeque<BalanceSnapshot>>,
+    history_depth: usize,
+    account_versions: DashMap<ClientId, Vec<AccountVersion>>,
+    max_account_versions: usize,
+}
+
+impl Default for ConcurrentState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConcurrentState {
+    pub fn new() -> Self {
+        Self {
+            accounts: Arc::new(DashMap::new()),
+            account_writes: RwLock::new(()),
+            balance_index: RwLock::new(BTreeSet::new()),
+            transactions: DashMap::new(),
+            amount_index: RwLock::new(BTreeMap::new()),
+            journal: RwLock::new(Vec::new()),
+            account_subscribers: DashMap::new(),
+            delegations: RwLock::new(HashMap::new()),
+            balance_history: DashMap::new(),
+            history_depth: DEFAULT_BALANCE_HISTORY_DEPTH,
+            account_versions: DashMap::new(),
+            max_account_versions: DEFAULT_MAX_ACCOUNT_VERSIONS,
+        }
+    }
+
+    pub fn with_history_depth(mut self, history_depth: usize) -> Self {
+        self.history_depth = history_depth;
+        self
+    }
+
+    pub fn with_max_account_versions(mut self, max_account_versions: usize) -> Self {
+        self.max_account_versions = max_account_versions;
+        self
+    }
+
+    /// Bumps the version of `account` past `previous` and records it everywhere an account
+    /// update is tracked. Callers hold the account's entry so updates are recorded in order.
+    fn on_account_updated(
+        &self,
+        previous: Option<&Account>,
+        account: &mut Account,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        account.version = previous.map_or(0, |a| a.version) + 1;
+        account.risk_score = Some(RiskScore::of(account).score);
+        self.update_balance_index(previous, Some(account))?;
+        self.record_balance_snapshot(account, triggered_by);
+        self.record_account_version(account);
+        self.notify_account_update(account);
+        Ok(())
+    }
+
+    /// Stores the account through its entry, callers hold `account_writes`
+    fn store_account(
+        &self,
+        mut account: Account,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        match self.accounts.entry(account.client) {
+            Entry::Occupied(mut entry) => {
+                self.on_account_updated(Some(entry.get()), &mut account, triggered_by)?;
+                entry.insert(account);
+            }
+            Entry::Vacant(entry) => {
+                self.on_account_updated(None, &mut account, triggered_by)?;
+                entry.insert(account);
+            }
+        }
+        Ok(())
+    }
+
+    fn update_balance_index(
+        &self,
+        previous: Option<&Account>,
+        account: Option<&Account>,
+    ) -> ProcessingResult<()> {
+        self.balance_index
+            .write()
+            .map_err(|e| storage_poisoned("balance_index", e))
+            .map(|mut index| {
+                if let Some(previous) = previous {
+                    index.remove(&(previous.total, previous.client));
+                }
+                if let Some(account) = account {
+                    index.insert((account.total, account.client));
+                }
+            })
+    }
+
+    fn index_amount(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        match transaction {
+            StoredTransaction::Deposit { id, amount, .. }
+            | StoredTransaction::Withdrawal { id, amount, .. } => self
+                .amount_index
+                .write()
+                .map_err(|e| storage_poisoned("amount_index", e))
+                .map(|mut index| index.entry(*amount).or_default().push(*id)),
+            _ => Ok(()),
+        }
+    }
+
+    fn unindex_amount(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        match transaction {
+            StoredTransaction::Deposit { id, amount, .. }
+            | StoredTransaction::Withdrawal { id, amount, .. } => self
+                .amount_index
+                .write()
+                .map_err(|e| storage_poisoned("amount_index", e))
+                .map(|mut index| {
+                    if let Some(ids) = index.get_mut(amount) {
+                        ids.retain(|indexed| indexed != id);
+                        if ids.is_empty() {
+                            index.remove(amount);
+                        }
+                    }
+                }),
+            _ => Ok(()),
+        }
+    }
+
+    fn append_to_journal(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        self.journal
+            .write()
+            .map_err(|e| storage_poisoned("journal", e))
+            .map(|mut journal| {
+                journal.push(JournalEntry {
+                    created_at: SystemTime::now(),
+                    transaction: transaction.clone(),
+                })
+            })
+    }
+
+    fn record_account_version(&self, account: &Account) {
+        if self.max_account_versions == 0 {
+            return;
+        }
+        let mut versions = self.account_versions.entry(account.client).or_default();
+        if versions.len() >= self.max_account_versions {
+            let overflow = versions.len() + 1 - self.max_account_versions;
+            versions.drain(..overflow);
+        }
+        versions.push(AccountVersion {
+            version: account.version,
+            created_at: SystemTime::now(),
+            account: account.clone(),
+        });
+    }
+
+    fn record_balance_snapshot(&self, account: &Account, triggered_by: TransactionId) {
+        if self.history_depth == 0 {
+            return;
+        }
+        let mut snapshots = self.balance_history.entry(account.client).or_default();
+        if snapshots.len() >= self.history_depth {
+            snapshots.pop_front();
+        }
+        snapshots.push_back(BalanceSnapshot {
+            timestamp: SystemTime::now(),
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            triggered_by_tx: triggered_by,
+        });
+    }
+
+    fn notify_account_update(&self, account: &Account) {
+        if let Some(sender) = self.account_subscribers.get(&account.client) {
+            sender.send_replace(account.clone());
+        }
+    }
+}
+
+impl StateStorage for ConcurrentState {
+    fn get_transaction(&self, id: TransactionId) -> ProcessingResult<StoredTransaction> {
+        tracing::debug!("Retrieving transaction with id {}", id);
+        self.transactions
+            .get(&id)
+            .map(|tx| tx.clone())
+            .ok_or(ProcessingError::TransactionNotFound { id })
+    }
+
+    fn get_transaction_for_client(
+        &self,
+        id: TransactionId,
+        client_id: ClientId,
+    ) -> ProcessingResult<StoredTransaction> {
+        tracing::debug!("Retrieving client {} transaction {}", client_id, id);
+        self.transactions
+            .get(&id)
+            .filter(|tx| *tx.client_id() == client_id)
+            .map(|tx| tx.clone())
+            .ok_or(ProcessingError::TransactionNotFound { id })
+    }
+
+    fn contains_transaction(&self, id: TransactionId) -> ProcessingResult<bool> {
+        Ok(self.transactions.contains_key(&id))
+    }
+
+    fn insert_transaction(
+        &self,
+        transaction: StoredTransaction,
+    ) -> ProcessingResult<StoredTransaction> {
+        match transaction {
+            StoredTransaction::Deposit { .. }
+            | StoredTransaction::Withdrawal { .. }
+            | StoredTransaction::Transfer { .. }
+            | StoredTransaction::RegulatoryHold { .. }
+            | StoredTransaction::Refund { .. } => {
+                tracing::debug!("Inserting: {:?}", transaction);
+                match self.transactions.entry(*transaction.id()) {
+                    Entry::Occupied(_) => Err(ProcessingError::TransactionAlreadyExists {
+                        id: *transaction.id(),
+                    }),
+                    Entry::Vacant(entry) => {
+                        entry.insert(transaction.clone());
+                        self.index_amount(&transaction)?;
+                        self.append_to_journal(&transaction)?;
+                        Ok(transaction)
+                    }
+                }
+            }
+            _ => {
+                self.append_to_journal(&transaction)?;
+                Ok(transaction)
+            }
+        }
+    }
+
+    fn remove_transaction(&self, id: TransactionId) -> ProcessingResult<()> {
+        tracing::debug!("Removing transaction with id {}", id);
+        match self.transactions.remove(&id) {
+            Some((_, removed)) => self.unindex_amount(&removed),
+            None => Ok(()),
+        }
+    }
+
+    fn under_dispute(&self, id: TransactionId, under_dispute: bool) -> ProcessingResult<()> {
+        tracing::debug!(
+            "Updating transaction with id {} to under dispute = {}",
+            id,
+            under_dispute
+        );
+        if let Some(mut tx) = self.transactions.get_mut(&id) {
+            tx.set_under_dispute(under_dispute);
+        }
+        Ok(())
+    }
+
+    fn release_hold(&self, id: TransactionId) -> ProcessingResult<()> {
+        tracing::debug!("Releasing regulatory hold with id {}", id);
+        if let Some(mut tx) = self.transactions.get_mut(&id) {
+            tx.set_released(true);
+        }
+        Ok(())
+    }
+
+    fn approve_kyc(&self, client_id: ClientId) -> ProcessingResult<()> {
+        tracing::debug!("Approving KYC deposits for client with id {}", client_id);
+        self.transactions
+            .iter_mut()
+            .filter(|tx| tx.client_id() == &client_id)
+            .for_each(|mut tx| tx.set_kyc_approved());
+        Ok(())
+    }
+
+    fn get_transactions_for_client(&self, id: ClientId) -> ProcessingResult<Vec<JournalEntry>> {
+        tracing::debug!("Retrieving transactions of client with id {}", id);
+        self.journal
+            .read()
+            .map_err(|e| storage_poisoned("journal", e))
+            .map(|journal| {
+                journal
+                    .iter()
+                    .filter(|entry| match &entry.transaction {
+                        StoredTransaction::Transfer {
+                            client_id,
+                            to_client,
+                            ..
+                        } => client_id == &id || to_client == &id,
+                        tx => tx.client_id() == &id,
+                    })
+                    .cloned()
+                    .collect()
+            })
+    }
+
+    fn get_journal_entries_until(&self, end: SystemTime) -> ProcessingResult<Vec<JournalEntry>> {
+        tracing::debug!("Retrieving journal entries until {:?}", end);
+        self.journal
+            .read()
+            .map_err(|e| storage_poisoned("journal", e))
+            .map(|journal| {
+                journal
+                    .iter()
+                    .filter(|entry| entry.created_at <= end)
+                    .cloned()
+                    .collect()
+            })
+    }
+
+    fn get_clients_with_no_activity_since(
+        &self,
+        cutoff: SystemTime,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        tracing::debug!("Retrieving clients with no activity since {:?}", cutoff);
+        self.journal
+            .read()
+            .map_err(|e| storage_poisoned("journal", e))
+            .map(|journal| {
+                let mut last_activity = BTreeMap::<ClientId, SystemTime>::new();
+                for entry in journal.iter() {
+                    let mut clients = vec![*entry.transaction.client_id()];
+                    if let StoredTransaction::Transfer { to_client, .. } = &entry.transaction {
+                        clients.push(*to_client);
+                    }
+                    for client in clients {
+                        let last = last_activity.entry(client).or_insert(entry.created_at);
+                        *last = (*last).max(entry.created_at);
+                    }
+                }
+                last_activity
+                    .into_iter()
+                    .filter(|(_, last)| *last < cutoff)
+                    .map(|(client, _)| client)
+                    .collect()
+            })
+    }
+
+    fn get_transactions_in_amount_range(
+        &self,
+        min: Amount,
+        max: Amount,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        tracing::debug!(
+            "Retrieving transactions with amount from {} to {}",
+            min,
+            max
+        );
+        if min > max {
+            return Ok(Vec::new());
+        }
+        let ids = self
+            .amount_index
+            .read()
+            .map_err(|e| storage_poisoned("amount_index", e))?
+            .range(min..=max)
+            .flat_map(|(_, ids)| ids.clone())
+            .collect::<Vec<_>>();
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| self.transactions.get(&id).map(|tx| tx.clone()))
+            .collect())
+    }
+
+    fn get_transactions_by_processor(
+        &self,
+        processor_id: &str,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        tracing::debug!(
+            "Retrieving transactions recorded by processor {}",
+            processor_id
+        );
+        Ok(self
+            .transactions
+            .iter()
+            .filter(|tx| tx.processor_id() == Some(processor_id))
+            .map(|tx| tx.clone())
+            .collect())
+    }
+
+    fn get_orphaned_transactions(&self) -> ProcessingResult<Vec<StoredTransaction>> {
+        tracing::debug!("Retrieving transactions without a client account");
+        let transactions = self
+            .transactions
+            .iter()
+            .map(|tx| tx.clone())
+            .collect::<Vec<_>>();
+        Ok(transactions
+            .into_iter()
+            .filter(|tx| !self.accounts.contains_key(tx.client_id()))
+            .collect())
+    }
+
+    fn transaction_count(&self) -> ProcessingResult<usize> {
+        Ok(self.transactions.len())
+    }
+
+    fn count_open_disputes(&self, client_id: ClientId) -> ProcessingResult<u32> {
+        tracing::debug!("Counting open disputes of client with id {}", client_id);
+        Ok(self
+            .transactions
+            .iter()
+            .filter(|tx| tx.client_id() == &client_id && tx.is_under_dispute())
+            .count() as u32)
+    }
+
+    fn iter_transactions<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = ProcessingResult<StoredTransaction>> + 'a> {
+        tracing::debug!("Iterating all transactions");
+        Box::new(
+            self.transactions
+                .iter()
+                .map(|transaction| Ok(transaction.value().clone())),
+        )
+    }
+
+    fn get_all_accounts(&self) -> ProcessingResult<Vec<Account>> {
+        tracing::debug!("Retrieving all client account balances");
+        Ok(self
+            .accounts
+            .iter()
+            .map(|account| account.clone())
+            .collect())
+    }
+
+    fn get_accounts_sorted_by_risk(&self) -> ProcessingResult<Vec<(Account, RiskScore)>> {
+        tracing::debug!("Retrieving accounts sorted by risk score");
+        Ok(sorted_by_risk(
+            self.accounts.iter().map(|account| account.clone()),
+        ))
+    }
+
+    fn get_account(&self, id: &ClientId) -> ProcessingResult<Account> {
+        tracing::debug!("Retrieving account for client with id {} ", id);
+        Ok(self
+            .accounts
+            .get(id)
+            .map(|account| account.clone())
+            .unwrap_or_else(|| Account::new(*id)))
+    }
+
+    fn with_account<F, R>(&self, id: ClientId, f: F) -> ProcessingResult<R>
+    where
+        F: FnOnce(&Account) -> R,
+    {
+        tracing::debug!("Reading account for client with id {} ", id);
+        Ok(match self.accounts.get(&id) {
+            Some(account) => f(&account),
+            None => f(&Account::new(id)),
+        })
+    }
+
+    fn account_count(&self) -> ProcessingResult<usize> {
+        Ok(self.accounts.len())
+    }
+
+    fn get_accounts_with_zero_balance(&self) -> ProcessingResult<Vec<Account>> {
+        tracing::debug!("Retrieving client accounts with zero balance");
+        Ok(self
+            .accounts
+            .iter()
+            .filter(|account| account.total == Amount::ZERO && account.held == Amount::ZERO)
+            .map(|account| account.clone())
+            .collect())
+    }
+
+    fn get_clients_exceeding_balance_threshold(
+        &self,
+        threshold: Amount,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        tracing::debug!("Retrieving clients with total balance above {}", threshold);
+        self.balance_index
+            .read()
+            .map_err(|e| storage_poisoned("balance_index", e))
+            .map(|index| {
+                index
+                    .range((
+                        Bound::Excluded((threshold, ClientId::MAX)),
+                        Bound::Unbounded,
+                    ))
+                    .map(|(_, client_id)| *client_id)
+                    .collect()
+            })
+    }
+
+    fn get_accounts_by_tier(
+        &self,
+        config: &TierConfig,
+    ) -> ProcessingResult<HashMap<AccountTier, Vec<Account>>> {
+        tracing::debug!("Retrieving client accounts by tier");
+        let mut tiers = HashMap::<AccountTier, Vec<Account>>::new();
+        for tier in [
+            AccountTier::Standard,
+            AccountTier::Premium,
+            AccountTier::Vip,
+        ] {
+            tiers.insert(tier, Vec::new());
+        }
+        for account in self.accounts.iter() {
+            tiers
+                .entry(account.tier(config))
+                .or_default()
+                .push(account.clone());
+        }
+        Ok(tiers)
+    }
+
+    fn remove_account(&self, id: ClientId) -> ProcessingResult<()> {
+        tracing::debug!("Removing account for client with id {}", id);
+        let removed = self.accounts.remove(&id).map(|(_, account)| account);
+        self.update_balance_index(removed.as_ref(), None)
+    }
+
+    fn migrate_client_id(
+        &self,
+        old_id: ClientId,
+        new_id: ClientId,
+        merge: bool,
+    ) -> ProcessingResult<()> {
+        tracing::debug!(
+            "Migrating client with id {} to id {}, merge = {}",
+            old_id,
+            new_id,
+            merge
+        );
+        let old = self
+            .accounts
+            .get(&old_id)
+            .map(|account| account.clone())
+            .ok_or(ProcessingError::AccountNotFound { client_id: old_id })?;
+        if old_id == new_id {
+            return Ok(());
+        }
+        match self.accounts.entry(new_id) {
+            Entry::Occupied(_) if !merge => {
+                return Err(ProcessingError::AccountAlreadyExists { client_id: new_id })
+            }
+            Entry::Occupied(mut entry) => {
+                let previous = entry.get().clone();
+                let mut account = previous.clone();
+                account.merge(&old)?;
+                account.version = previous.version + 1;
+                self.update_balance_index(Some(&previous), Some(&account))?;
+                self.record_account_version(&account);
+                self.notify_account_update(&account);
+                entry.insert(account);
+            }
+            Entry::Vacant(entry) => {
+                let account = Account {
+                    client: new_id,
+                    version: 1,
+                    ..old.clone()
+                };
+                self.update_balance_index(None, Some(&account))?;
+                self.record_account_version(&account);
+                self.notify_account_update(&account);
+                entry.insert(account);
+            }
+        }
+
+        self.transactions
+            .iter_mut()
+            .for_each(|mut tx| tx.set_client_id(old_id, new_id));
+        self.journal
+            .write()
+            .map_err(|e| storage_poisoned("journal", e))?
+            .iter_mut()
+            .for_each(|entry| entry.transaction.set_client_id(old_id, new_id));
+
+        self.accounts.remove(&old_id);
+        self.update_balance_index(Some(&old), None)
+    }
+
+    fn register_delegation(&self, from: ClientId, to: ClientId) -> ProcessingResult<()> {
+        tracing::debug!(
+            "Delegating client with id {} to client with id {}",
+            from,
+            to
+        );
+        self.delegations
+            .write()
+            .map_err(|e| storage_poisoned("delegations", e))
+            .and_then(|mut delegations| {
+                let previous = delegations.insert(from, to);
+                if let Err(e) = delegation_target(&delegations, from) {
+                    match previous {
+                        Some(previous) => delegations.insert(from, previous),
+                        None => delegations.remove(&from),
+                    };
+                    return Err(e);
+                }
+                Ok(())
+            })
+    }
+
+    fn resolve_delegation(&self, id: ClientId) -> ProcessingResult<ClientId> {
+        self.delegations
+            .read()
+            .map_err(|e| storage_poisoned("delegations", e))
+            .and_then(|delegations| delegation_target(&delegations, id))
+    }
+
+    fn upsert_account(
+        &self,
+        account: Account,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        tracing::debug!("Upserting {}", account);
+        check_balance_invariant(&account)?;
+        let _writes = self
+            .account_writes
+            .read()
+            .map_err(|e| storage_poisoned("account_writes", e))?;
+        self.store_account(account, triggered_by)
+    }
+
+    fn upsert_accounts(
+        &self,
+        accounts: Vec<Account>,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        tracing::debug!("Upserting {:?}", accounts);
+        accounts.iter().try_for_each(check_balance_invariant)?;
+        let _writes = self
+            .account_writes
+            .read()
+            .map_err(|e| storage_poisoned("account_writes", e))?;
+        accounts
+            .into_iter()
+            .try_for_each(|account| self.store_account(account, triggered_by))
+    }
+
+    fn update_account(
+        &self,
+        id: ClientId,
+        triggered_by: TransactionId,
+        update: &mut dyn FnMut(&mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<()> {
+        tracing::debug!("Updating account for client with id {}", id);
+        let _writes = self
+            .account_writes
+            .read()
+            .map_err(|e| storage_poisoned("account_writes", e))?;
+        match self.accounts.entry(id) {
+            Entry::Occupied(mut entry) => {
+                let mut account = entry.get().clone();
+                update(&mut account)?;
+                check_balance_invariant(&account)?;
+                self.on_account_updated(Some(entry.get()), &mut account, triggered_by)?;
+                entry.insert(account);
+            }
+            Entry::Vacant(entry) => {
+                let mut account = Account::new(id);
+                update(&mut account)?;
+                check_balance_invariant(&account)?;
+                self.on_account_updated(None, &mut account, triggered_by)?;
+                entry.insert(account);
+            }
+        }
+        Ok(())
+    }
+
+    fn update_accounts(
+        &self,
+        from: ClientId,
+        to: ClientId,
+        triggered_by: TransactionId,
+        update: &mut dyn FnMut(&mut Account, &mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<()> {
+        tracing::debug!("Updating accounts of clients with ids {} and {}", from, to);
+        check_distinct_clients(from, to, triggered_by)?;
+        let _writes = self
+            .account_writes
+            .write()
+            .map_err(|e| storage_poisoned("account_writes", e))?;
+        let account = |id: ClientId| match self.accounts.get(&id) {
+            Some(account) => account.clone(),
+            None => Account::new(id),
+        };
+        let mut source = account(from);
+        let mut destination = account(to);
+        update(&mut source, &mut destination)?;
+        check_balance_invariant(&source)?;
+        check_balance_invariant(&destination)?;
+        self.store_account(source, triggered_by)?;
+        self.store_account(destination, triggered_by)
+    }
+
+    fn balance_history(&self, id: ClientId) -> ProcessingResult<Vec<BalanceSnapshot>> {
+        tracing::debug!("Retrieving balance history for client with id {}", id);
+        Ok(self
+            .balance_history
+            .get(&id)
+            .map(|snapshots| snapshots.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn get_account_versions(&self, id: ClientId) -> ProcessingResult<Vec<AccountVersion>> {
+        tracing::debug!("Retrieving account versions for client with id {}", id);
+        Ok(self
+            .account_versions
+            .get(&id)
+            .map(|versions| versions.clone())
+            .unwrap_or_default())
+    }
+
+    fn compact_account_history(&self, id: ClientId, keep_last: usize) -> ProcessingResult<()> {
+        tracing::debug!(
+            "Compacting account versions for client with id {} to last {}",
+            id,
+            keep_last
+        );
+        if let Some(mut versions) = self.account_versions.get_mut(&id) {
+            let overflow = versions.len().saturating_sub(keep_last);
+            versions.drain(..overflow);
+        }
+        Ok(())
+    }
+
+    fn subscribe_to_account_updates(
+        &self,
+        id: ClientId,
+    ) -> ProcessingResult<watch::Receiver<Account>> {
+        tracing::debug!("Subscribing to account updates for client with id {}", id);
+        // Read the account before locking the subscribers, account updates lock them in the
+        // opposite order.
+        let account = self.get_account(&id)?;
+        Ok(self
+            .account_subscribers
+            .entry(id)
+            .or_insert_with(|| watch::channel(account).0)
+            .subscribe())
+    }
+
+    fn clear(&self) -> ProcessingResult<()> {
+        tracing::debug!("Clearing all accounts and transactions");
+        self.accounts.clear();
+        *self
+            .balance_index
+            .write()
+            .map_err(|e| storage_poisoned("balance_index", e))? = BTreeSet::new();
+        self.transactions.clear();
+        *self
+            .amount_index
+            .write()
+            .map_err(|e| storage_poisoned("amount_index", e))? = BTreeMap::new();
+        *self
+            .journal
+            .write()
+            .map_err(|e| storage_poisoned("journal", e))? = Vec::new();
+        self.account_subscribers.clear();
+        *self
+            .delegations
+            .write()
+            .map_err(|e| storage_poisoned("delegations", e))? = HashMap::new();
+        self.balance_history.clear();
+        self.account_versions.clear();
+        Ok(())
+    }
+
+    fn snapshot(&self) -> ProcessingResult<Self> {
+        tracing::debug!("Taking a snapshot of all accounts and transactions");
+        Ok(Self {
+            accounts: Arc::new(self.accounts.as_ref().clone()),
+            account_writes: RwLock::new(()),
+            balance_index: RwLock::new(copy_locked(&self.balance_index, "balance_index")?),
+            transactions: self.transactions.clone(),
+            amount_index: RwLock::new(copy_locked(&self.amount_index, "amount_index")?),
+            journal: RwLock::new(copy_locked(&self.journal, "journal")?),
+            account_subscribers: DashMap::new(),
+            delegations: RwLock::new(copy_locked(&self.delegations, "delegations")?),
+            balance_history: self.balance_history.clone(),
+            history_depth: self.history_depth,
+            account_versions: self.account_versions.clone(),
+            max_account_versions: self.max_account_versions,
+        })
+    }
+
+    /// Unlike `State`, the maps are replaced one after the other, so concurrent readers may
+    /// see a mix of the previous and the restored contents until this returns.
+    fn restore(&self, snapshot: &Self) -> ProcessingResult<()> {
+        tracing::debug!("Restoring all accounts and transactions from a snapshot");
+        if std::ptr::eq(self, snapshot) {
+            return Ok(());
+        }
+        replace_map(&self.accounts, &snapshot.accounts);
+        *self
+            .balance_index
+            .write()
+            .map_err(|e| storage_poisoned("balance_index", e))? =
+            copy_locked(&snapshot.balance_index, "balance_index")?;
+        replace_map(&self.transactions, &snapshot.transactions);
+        *self
+            .amount_index
+            .write()
+            .map_err(|e| storage_poisoned("amount_index", e))? =
+            copy_locked(&snapshot.amount_index, "amount_index")?;
+        *self
+            .journal
+            .write()
+            .map_err(|e| storage_poisoned("journal", e))? =
+            copy_locked(&snapshot.journal, "journal")?;
+        *self
+            .delegations
+            .write()
+            .map_err(|e| storage_poisoned("delegations", e))? =
+            copy_locked(&snapshot.delegations, "delegations")?;
+        replace_map(&self.balance_history, &snapshot.balance_history);
+        replace_map(&self.account_versions, &snapshot.account_versions);
+        let subscribed = self
+            .account_subscribers
+            .iter()
+            .map(|sender| *sender.key())
+            .collect::<Vec<_>>();
+        for id in subscribed {
+            let account = self.get_account(&id)?;
+            if let Some(sender) = self.account_subscribers.get(&id) {
+                sender.send_replace(account);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn copy_locked<T: Clone>(lock: &RwLock<T>, context: &str) -> ProcessingResult<T> {
+    lock.read()
+        .map_err(|e| storage_poisoned(context, e))
+        .map(|value| value.clone())
+}
+
+fn replace_map<K: Copy + Eq + Hash, V: Clone>(map: &DashMap<K, V>, other: &DashMap<K, V>) {
+    map.clear();
+    for entry in other.iter() {
+        map.insert(*entry.key(), entry.value().clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        domain::{Transaction, TransactionType},
+        processor::TransactionProcessor,
+        state::State,
+    };
+
+    fn transactions() -> Vec<Transaction> {
+        vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(5))),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(dec!(3))),
+            Transaction::new(TransactionType::Withdrawal, 1, 3, Some(dec!(1))),
+            Transaction::new(TransactionType::Withdrawal, 2, 4, Some(dec!(9))),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 2, 1, None),
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(1))),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None),
+            Transaction::new(TransactionType::Deposit, 1, 5, Some(dec!(1))),
+            Transaction::new(TransactionType::Dispute, 2, 2, None),
+            Transaction::new(TransactionType::Resolve, 2, 2, None),
+        ]
+    }
+
+    #[test]
+    fn processes_like_state() {
+        let state = TransactionProcessor::builder(State::new())
+            .processor_id("node".to_string())
+            .build();
+        let concurrent = TransactionProcessor::builder(ConcurrentState::new())
+            .processor_id("node".to_string())
+            .build();
+
+        for transaction in transactions() {
+            assert_eq!(
+                concurrent.process_transaction(transaction.clone()),
+                state.process_transaction(transaction)
+            );
+        }
+
+        let mut expected = state.get_accounts().expect("accounts");
+        expected.sort();
+        let mut accounts = concurrent.get_accounts().expect("accounts");
+        accounts.sort();
+        assert_eq!(accounts, expected);
+        assert_eq!(concurrent.metrics(), state.metrics());
+        for client_id in [1, 2] {
+            assert_eq!(
+                concurrent
+                    .state()
+                    .get_account_versions(client_id)
+                    .map(|v| v.len()),
+                state
+                    .state()
+                    .get_account_versions(client_id)
+                    .map(|v| v.len())
+            );
+        }
+        let ids = |transactions: Vec<StoredTransaction>| {
+            let mut ids = transactions.iter().map(|tx| *tx.id()).collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        assert_eq!(
+            concurrent
+                .state()
+                .get_transactions_by_processor("node")
+                .map(ids),
+            state.state().get_transactions_by_processor("node").map(ids)
+        );
+        assert_eq!(
+            concurrent.state().get_transaction(1),
+            state.state().get_transaction(1)
+        );
+    }
+
+    #[test]
+    fn transfers_do_not_lose_concurrent_updates() {
+        let state = ConcurrentState::new();
+        let move_one = |from: &mut Account, to: &mut Account| {
+            from.available -= dec!(1);
+            from.total -= dec!(1);
+            to.available += dec!(1);
+            to.total += dec!(1);
+            Ok(())
+        };
+        let add_one = |account: &mut Account| {
+            account.available += dec!(1);
+            account.total += dec!(1);
+            Ok(())
+        };
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut add_one = add_one;
+                for tx in 0..500 {
+                    assert_eq!(state.update_account(1, tx, &mut add_one), Ok(()));
+                }
+            });
+            scope.spawn(|| {
+                let mut add_one = add_one;
+                for tx in 500..1000 {
+                    assert_eq!(state.update_account(2, tx, &mut add_one), Ok(()));
+                }
+            });
+            scope.spawn(|| {
+                let mut move_one = move_one;
+                for tx in 1000..1500 {
+                    while state.update_accounts(1, 2, tx, &mut move_one).is_err() {}
+                }
+            });
+        });
+
+        assert_eq!(state.get_account(&1).map(|a| a.total), Ok(dec!(0)));
+        assert_eq!(state.get_account(&2).map(|a| a.total), Ok(dec!(1000)));
+        assert_eq!(state.get_account(&1).map(|a| a.version), Ok(1000));
+    }
+}
@@ -1,158 +1,125 @@
-use rust_decimal::Decimal;
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
 
-pub type ClientId = u16;
-pub type TransactionId = u32;
-pub type Amount = Decimal;
+pub use transaction_processor_core::domain::*;
 
-const AMOUNT_PRECISION: u32 = 4;
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AccountVersion {
+    pub version: u32,
+    pub created_at: SystemTime,
+    pub account: Account,
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
-pub enum TransactionType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BalanceSnapshot {
+    pub timestamp: SystemTime,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub triggered_by_tx: TransactionId,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct Transaction {
-    #[serde(rename = "type")]
-    pub transaction_type: TransactionType,
-    pub client: ClientId,
-    pub tx: TransactionId,
-    pub amount: Option<Decimal>,
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct JournalEntry {
+    pub created_at: SystemTime,
+    pub transaction: StoredTransaction,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub enum StoredTransaction {
-    Deposit {
-        id: TransactionId,
-        client_id: ClientId,
-        amount: Amount,
-        under_dispute: bool,
-    },
-    Withdrawal {
-        id: TransactionId,
-        client_id: ClientId,
-        amount: Amount,
-    },
-    Dispute {
-        id: TransactionId,
-        client_id: ClientId,
-    },
-    Resolve {
-        id: TransactionId,
-        client_id: ClientId,
-    },
-    Chargeback {
-        id: TransactionId,
-        client_id: ClientId,
-    },
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ClosedAccountSummary {
+    pub client_id: ClientId,
+    /// Available balance moved to the closing fee destination
+    pub final_balance: Amount,
+    pub closed_at: SystemTime,
 }
 
-impl StoredTransaction {
-    pub const fn id(&self) -> &TransactionId {
-        match self {
-            Self::Deposit { id, .. }
-            | Self::Withdrawal { id, .. }
-            | Self::Dispute { id, .. }
-            | Self::Resolve { id, .. }
-            | Self::Chargeback { id, .. } => id,
-        }
-    }
+/// Inclusive period between two points in time, parsed from two RFC 3339 date times separated
+/// by `..`, e.g. `2022-07-01T00:00:00Z..2022-08-01T00:00:00Z`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateRange {
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
 
-    pub const fn client_id(&self) -> &ClientId {
-        match self {
-            Self::Deposit { client_id, .. }
-            | Self::Withdrawal { client_id, .. }
-            | Self::Dispute { client_id, .. }
-            | Self::Resolve { client_id, .. }
-            | Self::Chargeback { client_id, .. } => client_id,
-        }
+impl DateRange {
+    pub fn contains(&self, timestamp: SystemTime) -> bool {
+        self.start <= timestamp && timestamp <= self.end
     }
+}
 
-    pub fn is_not_valid(&self) -> bool {
-        match self {
-            Self::Deposit { amount, .. } => amount < &Amount::ZERO,
-            Self::Withdrawal { amount, .. } => amount < &Amount::ZERO,
-            _ => false,
-        }
-    }
+impl FromStr for DateRange {
+    type Err = String;
 
-    pub fn set_under_dispute(&mut self, is_under_dispute: bool) {
-        if let StoredTransaction::Deposit {
-            ref mut under_dispute,
-            ..
-        } = self
-        {
-            *under_dispute = is_under_dispute;
-        }
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let (start, end) = src
+            .split_once("..")
+            .ok_or_else(|| format!("Period '{}' must be formatted as <start>..<end>", src))?;
+        let parse = |timestamp: &str| {
+            humantime::parse_rfc3339_weak(timestamp.trim())
+                .map_err(|e| format!("Invalid date time '{}': {}", timestamp, e))
+        };
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
     }
 }
 
-impl From<Transaction> for StoredTransaction {
-    fn from(tx: Transaction) -> Self {
-        match tx.transaction_type {
-            TransactionType::Deposit => Self::Deposit {
-                id: tx.tx,
-                client_id: tx.client,
-                amount: tx.amount.unwrap_or_default(),
-                under_dispute: false,
-            },
-            TransactionType::Withdrawal => Self::Withdrawal {
-                id: tx.tx,
-                client_id: tx.client,
-                amount: tx.amount.unwrap_or_default(),
-            },
-            TransactionType::Dispute => Self::Dispute {
-                id: tx.tx,
-                client_id: tx.client,
-            },
-            TransactionType::Resolve => Self::Resolve {
-                id: tx.tx,
-                client_id: tx.client,
-            },
-            TransactionType::Chargeback => Self::Chargeback {
-                id: tx.tx,
-                client_id: tx.client,
-            },
-        }
+impl fmt::Display for DateRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}..{}",
+            humantime::format_rfc3339(self.start),
+            humantime::format_rfc3339(self.end)
+        )
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
-pub struct Account {
-    pub client: ClientId,
-    pub available: Amount,
-    pub held: Amount,
-    pub total: Amount,
-    pub locked: bool,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+    Xml,
 }
 
-impl Account {
-    pub const fn new(client: ClientId) -> Self {
-        Self {
-            client,
-            available: Amount::ZERO,
-            held: Amount::ZERO,
-            total: Amount::ZERO,
-            locked: false,
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "xml" => Ok(Self::Xml),
+            _ => Err(format!("Unknown report format '{}'", src)),
         }
     }
+}
 
-    pub fn scaled(&mut self) {
-        self.available = scale_to_amount_precision(self.available);
-        self.held = scale_to_amount_precision(self.held);
-        self.total = scale_to_amount_precision(self.total);
-    }
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct RegulatoryReport {
+    pub period_start: String,
+    pub period_end: String,
+    /// Clients with at least one transaction in the period
+    pub total_clients: usize,
+    pub total_deposits: Amount,
+    pub total_withdrawals: Amount,
+    pub total_chargebacks: usize,
+    /// Accounts locked at the time the report is generated
+    pub total_locked_accounts: usize,
+    pub top_clients: Vec<ClientVolume>,
 }
 
-fn scale_to_amount_precision(mut amount: Amount) -> Amount {
-    if amount.scale() > AMOUNT_PRECISION {
-        amount.rescale(AMOUNT_PRECISION);
-    }
-    amount
+/// Sum of the deposited, withdrawn and transferred amounts of a client
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ClientVolume {
+    pub client: ClientId,
+    pub volume: Amount,
 }
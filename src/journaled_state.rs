@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use tokio::sync::watch;
+
+use crate::{
+    api::{ErrorSource, ProcessingError, ProcessingResult},
+    domain::{
+        Account, AccountTier, AccountVersion, Amount, BalanceSnapshot, ClientId, JournalEntry,
+        RiskScore, StoredTransaction, TierConfig, TransactionId,
+    },
+    processor::TransactionProcessor,
+    state::{storage_poisoned, State, StateStorage},
+};
+
+/// Storage appending every inserted transaction to an NDJSON log, one `StoredTransaction`
+/// per line, so that the state can be rebuilt after a crash with
+/// [`replay_from`](JournaledState::replay_from). Only transactions are logged, client id
+/// migrations, delegations and account closures are lost on replay.
+pub struct JournaledState<S: StateStorage = State> {
+    state: S,
+    log: Option<Mutex<File>>,
+}
+
+impl JournaledState {
+    /// Rebuilds the state by processing the logged transactions with the default processor
+    /// settings and keeps appending to the log. Transactions that were rejected originally
+    /// are rejected again, the journal's timestamps are those of the replay.
+    pub fn replay_from(path: &Path) -> anyhow::Result<Self> {
+        let processor = TransactionProcessor::new(State::new());
+        if path.exists() {
+            let log = File::open(path)
+                .with_context(|| format!("Failed to open log {}", path.display()))?;
+            for (index, line) in BufReader::new(log).lines().enumerate() {
+                let line =
+                    line.with_context(|| format!("Failed to read log {}", path.display()))?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let transaction = serde_json::from_str::<StoredTransaction>(&line)
+                    .with_context(|| format!("Malformed log entry on line {}", index + 1))?;
+                if let Err(e) = processor.process(transaction) {
+                    tracing::debug!("Replayed transaction rejected: {}", e);
+                }
+            }
+        }
+        Self::with_log(processor.into_state(), path)
+    }
+}
+
+impl<S: StateStorage> JournaledState<S> {
+    /// Logs the transactions inserted from now on to `path`, appending if the file exists
+    pub fn with_log(state: S, path: &Path) -> anyhow::Result<Self> {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log {}", path.display()))?;
+        Ok(Self {
+            state,
+            log: Some(Mutex::new(log)),
+        })
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.state
+    }
+
+    /// Writes the transaction as a single line and syncs it to disk
+    fn append(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_string(transaction).map_err(log_error)?;
+        line.push('\n');
+        let mut log = log.lock().map_err(|e| storage_poisoned("log", e))?;
+        log.write_all(line.as_bytes())
+            .and_then(|_| log.sync_data())
+            .map_err(log_error)
+    }
+}
+
+fn log_error(error: impl std::error::Error + Send + Sync + 'static) -> ProcessingError {
+    tracing::error!("Failed to append to the transaction log: {}", error);
+    ProcessingError::InternalError {
+        source: ErrorSource::new(error),
+    }
+}
+
+impl<S: StateStorage> StateStorage for JournaledState<S> {
+    fn insert_transaction(
+        &self,
+        transaction: StoredTransaction,
+    ) -> ProcessingResult<StoredTransaction> {
+        let stored = self.state.insert_transaction(transaction.clone())?;
+        self.append(&transaction)?;
+        Ok(stored)
+    }
+
+    fn remove_transaction(&self, id: TransactionId) -> ProcessingResult<()> {
+        self.state.remove_transaction(id)
+    }
+
+    fn get_transaction(&self, id: TransactionId) -> ProcessingResult<StoredTransaction> {
+        self.state.get_transaction(id)
+    }
+
+    fn get_transaction_for_client(
+        &self,
+        id: TransactionId,
+        client_id: ClientId,
+    ) -> ProcessingResult<StoredTransaction> {
+        self.state.get_transaction_for_client(id, client_id)
+    }
+
+    fn contains_transaction(&self, id: TransactionId) -> ProcessingResult<bool> {
+        self.state.contains_transaction(id)
+    }
+
+    fn under_dispute(&self, id: TransactionId, under_dispute: bool) -> ProcessingResult<()> {
+        self.state.under_dispute(id, under_dispute)
+    }
+
+    fn release_hold(&self, id: TransactionId) -> ProcessingResult<()> {
+        self.state.release_hold(id)
+    }
+
+    fn approve_kyc(&self, client_id: ClientId) -> ProcessingResult<()> {
+        self.state.approve_kyc(client_id)
+    }
+
+    fn get_transactions_for_client(&self, id: ClientId) -> ProcessingResult<Vec<JournalEntry>> {
+        self.state.get_transactions_for_client(id)
+    }
+
+    fn get_journal_entries_until(&self, end: SystemTime) -> ProcessingResult<Vec<JournalEntry>> {
+        self.state.get_journal_entries_until(end)
+    }
+
+    fn get_clients_with_no_activity_since(
+        &self,
+        cutoff: SystemTime,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        self.state.get_clients_with_no_activity_since(cutoff)
+    }
+
+    fn get_transactions_in_amount_range(
+        &self,
+        min: Amount,
+        max: Amount,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.state.get_transactions_in_amount_range(min, max)
+    }
+
+    fn get_transactions_by_processor(
+        &self,
+        processor_id: &str,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.state.get_transactions_by_processor(processor_id)
+    }
+
+    fn get_orphaned_transactions(&self) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.state.get_orphaned_transactions()
+    }
+
+    fn transaction_count(&self) -> ProcessingResult<usize> {
+        self.state.transaction_count()
+    }
+
+    fn count_open_disputes(&self, client_id: ClientId) -> ProcessingResult<u32> {
+        self.state.count_open_disputes(client_id)
+    }
+
+    fn iter_transactions<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = ProcessingResult<StoredTransaction>> + 'a> {
+        self.state.iter_transactions()
+    }
+    fn get_all_accounts(&self) -> ProcessingResult<Vec<Account>> {
+        self.state.get_all_accounts()
+    }
+
+    fn get_accounts_sorted_by_risk(&self) -> ProcessingResult<Vec<(Account, RiskScore)>> {
+        self.state.get_accounts_sorted_by_risk()
+    }
+
+    fn get_account(&self, id: &ClientId) -> ProcessingResult<Account> {
+        self.state.get_account(id)
+    }
+
+    fn with_account<F, R>(&self, id: ClientId, f: F) -> ProcessingResult<R>
+    where
+        F: FnOnce(&Account) -> R,
+    {
+        self.state.with_account(id, f)
+    }
+
+    fn account_count(&self) -> ProcessingResult<usize> {
+        self.state.account_count()
+    }
+
+    fn get_accounts_with_zero_balance(&self) -> ProcessingResult<Vec<Account>> {
+        self.state.get_accounts_with_zero_balance()
+    }
+
+    fn get_clients_exceeding_balance_threshold(
+        &self,
+        threshold: Amount,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        self.state
+            .get_clients_exceeding_balance_threshold(threshold)
+    }
+
+    fn get_accounts_by_tier(
+        &self,
+        config: &TierConfig,
+    ) -> ProcessingResult<HashMap<AccountTier, Vec<Account>>> {
+        self.state.get_accounts_by_tier(config)
+    }
+
+    fn remove_account(&self, id: ClientId) -> ProcessingResult<()> {
+        self.state.remove_account(id)
+    }
+
+    fn migrate_client_id(
+        &self,
+        old_id: ClientId,
+        new_id: ClientId,
+        merge: bool,
+    ) -> ProcessingResult<()> {
+        self.state.migrate_client_id(old_id, new_id, merge)
+    }
+
+    fn register_delegation(&self, from: ClientId, to: ClientId) -> ProcessingResult<()> {
+        self.state.register_delegation(from, to)
+    }
+
+    fn resolve_delegation(&self, id: ClientId) -> ProcessingResult<ClientId> {
+        self.state.resolve_delegation(id)
+    }
+
+    fn upsert_account(
+        &self,
+        account: Account,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        self.state.upsert_account(account, triggered_by)
+    }
+
+    fn upsert_accounts(
+        &self,
+        accounts: Vec<Account>,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        self.state.upsert_accounts(accounts, triggered_by)
+    }
+
+    fn update_account(
+        &self,
+        id: ClientId,
+        triggered_by: TransactionId,
+        update: &mut dyn FnMut(&mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<()> {
+        self.state.update_account(id, triggered_by, update)
+    }
+
+    fn update_accounts(
+        &self,
+        from: ClientId,
+        to: ClientId,
+        triggered_by: TransactionId,
+        update: &mut dyn FnMut(&mut Account, &mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<()> {
+        self.state.update_accounts(from, to, triggered_by, update)
+    }
+
+    fn balance_history(&self, id: ClientId) -> ProcessingResult<Vec<BalanceSnapshot>> {
+        self.state.balance_history(id)
+    }
+
+    fn get_account_versions(&self, id: ClientId) -> ProcessingResult<Vec<AccountVersion>> {
+        self.state.get_account_versions(id)
+    }
+
+    fn compact_account_history(&self, id: ClientId, keep_last: usize) -> ProcessingResult<()> {
+        self.state.compact_account_history(id, keep_last)
+    }
+
+    fn subscribe_to_account_updates(
+        &self,
+        id: ClientId,
+    ) -> ProcessingResult<watch::Receiver<Account>> {
+        self.state.subscribe_to_account_updates(id)
+    }
+
+    fn clear(&self) -> ProcessingResult<()> {
+        self.state.clear()
+    }
+
+    /// The snapshot doesn't log, transactions inserted into it are logged when it's restored
+    fn snapshot(&self) -> ProcessingResult<Self> {
+        Ok(Self {
+            state: self.state.snapshot()?,
+            log: None,
+        })
+    }
+
+    fn restore(&self, snapshot: &Self) -> ProcessingResult<()> {
+        let now = SystemTime::now();
+        let logged = self.state.get_journal_entries_until(now)?.len();
+        let staged = snapshot.state.get_journal_entries_until(now)?;
+        self.state.restore(&snapshot.state)?;
+        staged
+            .iter()
+            .skip(logged)
+            .try_for_each(|entry| self.append(&entry.transaction))
+    }
+}
@@ -0,0 +1,21 @@
+// `ProcessingResult` is a `Result` and already `#[must_use]`, this also rejects dropping one
+// with `let _ = ...`
+#![deny(clippy::let_underscore_must_use)]
+
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+
+pub mod api;
+pub mod async_processor;
+pub mod async_state;
+pub mod concurrent_state;
+pub mod domain;
+#[cfg(feature = "serde")]
+pub mod journaled_state;
+pub mod memory_bounded_state;
+pub mod metrics;
+pub mod processor;
+pub use transaction_processor_core::sharding;
+pub mod state;
+pub mod testing;
@@ -1,44 +1,484 @@
-#[macro_use]
-extern crate serde_derive;
-
 use std::env::current_dir;
-use std::fs::File;
-use std::io;
-use std::io::Stdout;
-
-use anyhow::Ok;
-use csv::{Reader, ReaderBuilder, Trim, Writer};
-use domain::Transaction;
-use processor::TransactionProcessor;
-use state::State;
+use std::fs::{self, File};
+use std::io::{self, BufRead as _, BufReader, Write as _};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use csv::{ReaderBuilder, Trim, Writer};
+use serde::Serialize;
 use structopt::StructOpt;
+use trasaction_processor::{
+    api::{ProcessingError, ProcessingResult},
+    domain::{
+        Account, Amount, ClientId, DateRange, ProcessingStats, ReportFormat, Transaction,
+        TransactionId, TransactionType,
+    },
+    processor::TransactionProcessor,
+    state::State,
+};
+
+const EXIT_SUCCESS: u8 = 0;
+const EXIT_IO_ERROR: u8 = 2;
 
-mod api;
-mod domain;
-mod processor;
-mod state;
+/// Written at the start of files by some tools exporting UTF-8
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
 
 #[derive(Debug, StructOpt)]
 pub struct Config {
     #[structopt(parse(from_os_str))]
     pub path: std::path::PathBuf,
+    /// Input format, `csv` or `ndjson` with one JSON transaction per line
+    #[structopt(long, default_value = "csv", possible_values = &["csv", "ndjson"])]
+    pub input_format: InputFormat,
+    /// Single ASCII character separating CSV fields, use `\t` for tab separated files
+    #[structopt(long, default_value = ",", parse(try_from_str = parse_delimiter))]
+    pub delimiter: u8,
+    /// Write account balances to the given file instead of stdout, truncating it if it exists
+    #[structopt(long, parse(from_os_str))]
+    pub output: Option<std::path::PathBuf>,
+    /// Write rejected transactions with an additional `error` column to the given file
+    #[structopt(long, parse(from_os_str))]
+    pub error_output: Option<std::path::PathBuf>,
+    /// Move accounts with zero balance to the given file instead of the balances output
+    #[structopt(long, parse(from_os_str))]
+    pub archive_zero_balance: Option<std::path::PathBuf>,
+    /// Output mode, `balances` of all accounts or `statement` of a single `--client`
+    #[structopt(long, default_value = "balances", possible_values = &["balances", "statement"])]
+    pub mode: OutputMode,
+    /// Client whose transactions with running balances are output in `statement` mode
+    #[structopt(long, required_if("mode", "statement"))]
+    pub client: Option<ClientId>,
+    /// Compare computed balances against the given CSV and output the differences instead
+    #[structopt(long, parse(from_os_str))]
+    pub reconcile: Option<std::path::PathBuf>,
+    /// Report clients whose total balance exceeds the given amount to `--aml-output`
+    #[structopt(long, requires = "aml-output")]
+    pub aml_threshold: Option<Amount>,
+    /// Write the clients exceeding `--aml-threshold` to the given file
+    #[structopt(long, parse(from_os_str), requires = "aml-threshold")]
+    pub aml_output: Option<std::path::PathBuf>,
+    /// Report clients without transactions since the given RFC 3339 date time to `--dormant-output`
+    #[structopt(long, parse(try_from_str = humantime::parse_rfc3339_weak), requires = "dormant-output")]
+    pub dormant_since: Option<SystemTime>,
+    /// Write the clients dormant since `--dormant-since` to the given file
+    #[structopt(long, parse(from_os_str), requires = "dormant-since")]
+    pub dormant_output: Option<std::path::PathBuf>,
+    /// Output a regulatory report for the given `<start>..<end>` RFC 3339 period instead of the balances
+    #[structopt(long)]
+    pub regulatory_report: Option<DateRange>,
+    /// Format of the `--regulatory-report`, `csv`, `json` or `xml`, CSV by default
+    #[structopt(long, possible_values = &["csv", "json", "xml"], requires = "regulatory-report")]
+    pub format: Option<ReportFormat>,
+    /// Only output the balance of the given client, all transactions are still processed
+    #[structopt(long)]
+    pub client_filter: Option<ClientId>,
+    /// Balance columns order, `standard` or `total-first` for `client,total,available,held,...`
+    #[structopt(long, default_value = "standard", possible_values = &["standard", "total-first"])]
+    pub column_order: ColumnOrder,
+    /// Append a `TOTAL` row with aggregated balances and the count of locked accounts
+    #[structopt(long)]
+    pub summary: bool,
+    /// Output balances as they were at the given RFC 3339 date time, e.g. `2022-07-01T12:00:00Z`
+    #[structopt(long, parse(try_from_str = humantime::parse_rfc3339_weak))]
+    pub as_of: Option<SystemTime>,
+    /// Reject deposits into locked accounts as well
+    #[structopt(long)]
+    pub strict_lock: bool,
+    /// Reject disputes, resolves and chargebacks carrying an amount instead of ignoring the amount
+    #[structopt(long)]
+    pub strict_amounts: bool,
+    /// Reject resolves of transactions that aren't under dispute instead of ignoring them
+    #[structopt(long)]
+    pub strict_resolve: bool,
+    /// Reject disputes that would leave a client with more than the given number of open disputes
+    #[structopt(long)]
+    pub max_open_disputes: Option<u32>,
+    /// Maximum number of balance versions kept per account
+    #[structopt(long, default_value = "10000")]
+    pub max_account_versions: usize,
+    /// Discard account versions older than the given number of days before producing the output
+    #[structopt(long)]
+    pub compact_history_older_than_days: Option<u64>,
+    /// Validate state consistency after processing and exit with an error on violations
+    #[structopt(long)]
+    pub validate: bool,
+    /// Exit with an error if any stored transaction has no client account
+    #[structopt(long)]
+    pub check_orphans: bool,
+    /// Only report how many transactions would succeed or fail, without writing any balances
+    #[structopt(long)]
+    pub dry_run: bool,
+}
+
+impl Config {
+    /// Checks the arguments before any processing starts, so mistakes fail fast with a
+    /// clear message instead of after part of the input was processed
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let path = current_dir()?.join(&self.path);
+        anyhow::ensure!(path.is_file(), "Input {} is not a file", path.display());
+        // Already enforced by `parse_delimiter`, but a `Config` may be built without parsing
+        anyhow::ensure!(
+            self.delimiter.is_ascii(),
+            "Delimiter must be a single ASCII character, got byte {}",
+            self.delimiter
+        );
+        if let Some(output) = &self.output {
+            let output = current_dir()?.join(output);
+            let parent = output.parent().unwrap_or(&output);
+            let metadata = fs::metadata(parent).with_context(|| {
+                format!("Output directory {} is not accessible", parent.display())
+            })?;
+            anyhow::ensure!(
+                metadata.is_dir() && !metadata.permissions().readonly(),
+                "Output directory {} is not writable",
+                parent.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Csv,
+    Ndjson,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src {
+            "csv" => Ok(Self::Csv),
+            "ndjson" => Ok(Self::Ndjson),
+            _ => Err(format!("Unknown input format '{}'", src)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    Balances,
+    Statement,
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src {
+            "balances" => Ok(Self::Balances),
+            "statement" => Ok(Self::Statement),
+            _ => Err(format!("Unknown output mode '{}'", src)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnOrder {
+    Standard,
+    TotalFirst,
+}
+
+impl FromStr for ColumnOrder {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src {
+            "standard" => Ok(Self::Standard),
+            "total-first" => Ok(Self::TotalFirst),
+            _ => Err(format!("Unknown column order '{}'", src)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AccountsSummary {
+    client: &'static str,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: usize,
+    chargebacks: u32,
+}
+
+impl Default for AccountsSummary {
+    fn default() -> Self {
+        Self {
+            client: "TOTAL",
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            total: Amount::ZERO,
+            locked: 0,
+            chargebacks: 0,
+        }
+    }
+}
+
+impl AccountsSummary {
+    /// Fails instead of panicking when a sum no longer fits an `Amount`
+    fn add(&mut self, account: &Account) -> anyhow::Result<()> {
+        let sum = |lhs: Amount, rhs: Amount| {
+            lhs.checked_add(rhs)
+                .context("Summary balances exceed the maximum amount")
+        };
+        self.available = sum(self.available, account.available)?;
+        self.held = sum(self.held, account.held)?;
+        self.total = sum(self.total, account.total)?;
+        if account.locked {
+            self.locked += 1;
+        }
+        self.chargebacks += account.chargeback_count;
+        Ok(())
+    }
+}
+
+/// Balance row with `total` before `available` and `held` for `--column-order total-first`,
+/// of an account or of the `TOTAL` summary
+#[derive(Debug, Serialize)]
+struct AccountOutputView<C, L> {
+    client: C,
+    total: Amount,
+    available: Amount,
+    held: Amount,
+    locked: L,
+    chargebacks: u32,
+}
+
+impl From<&Account> for AccountOutputView<ClientId, bool> {
+    fn from(account: &Account) -> Self {
+        Self {
+            client: account.client,
+            total: account.total,
+            available: account.available,
+            held: account.held,
+            locked: account.locked,
+            chargebacks: account.chargeback_count,
+        }
+    }
+}
+
+impl From<&AccountsSummary> for AccountOutputView<&'static str, usize> {
+    fn from(summary: &AccountsSummary) -> Self {
+        Self {
+            client: summary.client,
+            total: summary.total,
+            available: summary.available,
+            held: summary.held,
+            locked: summary.locked,
+            chargebacks: summary.chargebacks,
+        }
+    }
+}
+
+/// Counts of a completed run, logged once processing finishes
+#[derive(Debug, Default)]
+struct ProcessingSummary {
+    processed: usize,
+    malformed: usize,
+    errors: usize,
+    accounts_created: usize,
+    accounts_locked: usize,
+    code: u8,
+}
+
+#[derive(Debug, Serialize)]
+struct ClientReportEntry {
+    client: ClientId,
+}
+
+#[derive(Debug, Serialize)]
+struct RejectedTransaction {
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Option<Amount>,
+    to_client: Option<ClientId>,
+    reason: Option<String>,
+    authority: Option<String>,
+    error: String,
 }
 
-fn main() -> anyhow::Result<()> {
+impl RejectedTransaction {
+    fn new(transaction: Transaction, error: &ProcessingError) -> Self {
+        Self {
+            transaction_type: transaction.transaction_type,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+            to_client: transaction.to_client,
+            reason: transaction.reason,
+            authority: transaction.authority,
+            error: error.to_string(),
+        }
+    }
+}
+
+fn main() {
     dotenv::dotenv().ok();
     init_logging();
     tracing::info!("Starting transactions processor...");
     let config = Config::from_args();
-    let transactions_path = current_dir()?.join(config.path);
-    let mut reader = ReaderBuilder::new()
-        .flexible(true)
-        .trim(Trim::All)
-        .from_path(transactions_path)?;
-    let mut writer = Writer::from_writer(io::stdout());
-    process(&mut reader, &mut writer)?;
-    Ok(())
+    let code = match config.validate().and_then(|()| run(&config)) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            match e.downcast::<ProcessingError>() {
+                Ok(e) => u8::from(e),
+                Err(_) => EXIT_IO_ERROR,
+            }
+        }
+    };
+    std::process::exit(code.into());
+}
+
+/// Returns the exit code, the highest of the codes of all rejected transactions or
+/// `EXIT_IO_ERROR` if records were skipped as malformed
+fn run(config: &Config) -> anyhow::Result<u8> {
+    let input = Input::open(config)?;
+    let error_output = match &config.error_output {
+        Some(path) => {
+            Some(Box::new(File::create(current_dir()?.join(path))?) as Box<dyn io::Write>)
+        }
+        None => None,
+    };
+    let mut malformed = 0;
+    let code = if config.dry_run {
+        dry_run(
+            config,
+            input.into_transactions(&mut malformed),
+            error_output,
+        )?
+    } else {
+        let output: Box<dyn io::Write> = match &config.output {
+            Some(path) => Box::new(File::create(current_dir()?.join(path))?),
+            None => Box::new(io::stdout()),
+        };
+        let summary = process(config, input, output, error_output)?;
+        tracing::info!(
+            "Finished processing {} transactions with {} errors, {} accounts created, {} accounts locked",
+            summary.processed,
+            summary.errors,
+            summary.accounts_created,
+            summary.accounts_locked
+        );
+        malformed = summary.malformed;
+        summary.code
+    };
+    if malformed > 0 {
+        tracing::error!("Skipped {} malformed records", malformed);
+        return Ok(code.max(EXIT_IO_ERROR));
+    }
+    Ok(code)
+}
+
+fn parse_delimiter(src: &str) -> Result<u8, String> {
+    match src {
+        "\\t" | "tab" => Ok(b'\t'),
+        _ if src.len() == 1 && src.is_ascii() => Ok(src.as_bytes()[0]),
+        _ => Err(format!(
+            "Delimiter must be a single ASCII character, got '{}'",
+            src
+        )),
+    }
+}
+
+/// Opened input, the header of CSV input is already validated
+enum Input {
+    Csv(csv::Reader<BufReader<File>>),
+    Ndjson(BufReader<File>),
+}
+
+impl Input {
+    fn open(config: &Config) -> anyhow::Result<Self> {
+        let input = skip_bom(File::open(current_dir()?.join(&config.path))?)?;
+        match config.input_format {
+            InputFormat::Csv => {
+                let mut reader = ReaderBuilder::new()
+                    .flexible(true)
+                    .trim(Trim::All)
+                    .delimiter(config.delimiter)
+                    .from_reader(input);
+                // An unreadable header is an I/O error rather than one without columns
+                reader.headers()?;
+                CsvColumnValidator::validate(&mut reader)?;
+                Ok(Self::Csv(reader))
+            }
+            InputFormat::Ndjson => Ok(Self::Ndjson(input)),
+        }
+    }
+
+    fn process(self, processor: &TransactionProcessor<State>) -> ProcessingResult<ProcessingStats> {
+        match self {
+            Self::Csv(mut reader) => processor.process_reader(&mut reader),
+            // The stream can't be resynchronized after a malformed line, so it ends there
+            Self::Ndjson(input) => processor.process_records(
+                serde_json::Deserializer::from_reader(input).into_iter::<Transaction>(),
+            ),
+        }
+    }
+
+    /// Skips malformed records, counting them in `malformed`
+    fn into_transactions(self, malformed: &mut usize) -> impl Iterator<Item = Transaction> + '_ {
+        let records: Box<dyn Iterator<Item = anyhow::Result<Transaction>>> = match self {
+            Self::Csv(reader) => Box::new(reader.into_deserialize().map(|r| Ok(r?))),
+            Self::Ndjson(input) => Box::new(
+                serde_json::Deserializer::from_reader(input)
+                    .into_iter()
+                    .map(|r| Ok(r?)),
+            ),
+        };
+        records.filter_map(|record| {
+            record
+                .map_err(|e| {
+                    tracing::warn!("Skipping malformed record: {}", e);
+                    *malformed += 1;
+                })
+                .ok()
+        })
+    }
+}
+
+/// Skips a UTF-8 byte-order mark, which would otherwise become part of the first CSV header
+/// or fail the first NDJSON record
+fn skip_bom(input: File) -> io::Result<BufReader<File>> {
+    let mut input = BufReader::new(input);
+    if input.fill_buf()?.starts_with(UTF8_BOM) {
+        input.consume(UTF8_BOM.len());
+    }
+    Ok(input)
+}
+
+/// Checks the CSV header up front, otherwise a missing column fails every record with the
+/// same deserialization error
+struct CsvColumnValidator;
+
+impl CsvColumnValidator {
+    /// Columns every transaction needs, `amount` and the other columns are optional
+    const REQUIRED_COLUMNS: [&'static str; 3] = ["type", "client", "tx"];
+
+    /// An unreadable header counts as one without columns
+    fn validate(reader: &mut csv::Reader<impl io::Read>) -> Result<(), MissingColumns> {
+        let headers = reader.headers().cloned().unwrap_or_default();
+        let missing = Self::REQUIRED_COLUMNS
+            .into_iter()
+            .filter(|column| !headers.iter().any(|header| header == *column))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            tracing::error!("Input is missing required columns {:?}", missing);
+            Err(MissingColumns(missing))
+        }
+    }
 }
 
+#[derive(Debug, thiserror::Error)]
+#[error("Input is missing required columns: {}", .0.join(", "))]
+struct MissingColumns(Vec<&'static str>);
+
 fn init_logging() {
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
@@ -46,22 +486,204 @@ fn init_logging() {
         .init();
 }
 
-fn process(reader: &mut Reader<File>, writer: &mut Writer<Stdout>) -> Result<(), anyhow::Error> {
-    let processor = TransactionProcessor::new(State::new());
+fn build_processor(config: &Config) -> TransactionProcessor<State> {
+    let builder = TransactionProcessor::builder(
+        State::new().with_max_account_versions(config.max_account_versions),
+    )
+    .with_strict_lock(config.strict_lock)
+    .with_strict_amounts(config.strict_amounts)
+    .with_lenient_resolve(!config.strict_resolve);
+    match config.max_open_disputes {
+        Some(max) => builder.with_max_open_disputes(max),
+        None => builder,
+    }
+    .build()
+}
+
+fn dry_run(
+    config: &Config,
+    transactions: impl Iterator<Item = Transaction>,
+    error_output: Option<Box<dyn io::Write>>,
+) -> Result<u8, anyhow::Error> {
+    let report = build_processor(config).dry_run(transactions);
+    let failed = report.rejected.len();
+    let code = report
+        .rejected
+        .iter()
+        .map(|(_, e)| u8::from(e.clone()))
+        .max()
+        .unwrap_or(EXIT_SUCCESS);
+    if let Some(error_output) = error_output {
+        let mut error_writer = Writer::from_writer(error_output);
+        for (transaction, e) in report.rejected {
+            error_writer.serialize(RejectedTransaction::new(transaction, &e))?;
+        }
+        error_writer.flush()?;
+    }
+    println!(
+        "Dry run: {} transactions would succeed, {} would fail",
+        report.succeeded, failed
+    );
+    Ok(code)
+}
+
+fn process(
+    config: &Config,
+    input: Input,
+    output: Box<dyn io::Write>,
+    error_output: Option<Box<dyn io::Write>>,
+) -> Result<ProcessingSummary, anyhow::Error> {
+    let mut summary = ProcessingSummary::default();
+    let mut writer = Writer::from_writer(output);
+    let mut error_writer = error_output.map(Writer::from_writer);
+    let processor = build_processor(config);
+
+    let stats = input.process(&processor)?;
+    summary.processed = stats.attempted - stats.malformed;
+    summary.malformed = stats.malformed;
+    summary.errors = stats.rejected.len();
+    for (transaction, e) in stats.rejected {
+        summary.code = summary.code.max(u8::from(e.clone()));
+        if let Some(error_writer) = error_writer.as_mut() {
+            error_writer.serialize(RejectedTransaction::new(transaction, &e))?;
+        }
+    }
+    // Counted before zero balance accounts are archived away
+    let accounts = processor.get_accounts()?;
+    summary.accounts_created = accounts.len();
+    summary.accounts_locked = accounts.iter().filter(|account| account.locked).count();
 
-    for record in reader.deserialize::<Transaction>() {
-        let _ = record
-            .map(|transaction| processor.process(transaction.into()))
-            .map_err(anyhow::Error::from);
+    if let Some(path) = &config.archive_zero_balance {
+        let mut archive = Writer::from_path(current_dir()?.join(path))?;
+        // A locked account would come back unlocked with the client's next transaction
+        let archived = processor
+            .get_accounts_with_zero_balance()?
+            .into_iter()
+            .filter(|account| !account.locked)
+            .collect::<Vec<_>>();
+        processor.archive_accounts(
+            &archived
+                .iter()
+                .map(|account| account.client)
+                .collect::<Vec<_>>(),
+        )?;
+        for mut account in archived {
+            account.scaled();
+            match config.column_order {
+                ColumnOrder::Standard => archive.serialize(account)?,
+                ColumnOrder::TotalFirst => archive.serialize(AccountOutputView::from(&account))?,
+            }
+        }
+        archive.flush()?;
     }
 
-    let mut balances = processor.get_accounts()?.into_iter();
-    while let Some(mut balance) = balances.next() {
-        balance.scaled();
-        writer.serialize(balance)?;
+    if let Some(days) = config.compact_history_older_than_days {
+        processor.compact_account_history_older_than(Duration::from_secs(days * 24 * 60 * 60))?;
     }
 
-    writer.flush()?;
+    if let (Some(threshold), Some(path)) = (config.aml_threshold, &config.aml_output) {
+        let mut report = Writer::from_path(current_dir()?.join(path))?;
+        for client in processor.get_clients_exceeding_balance_threshold(threshold)? {
+            report.serialize(ClientReportEntry { client })?;
+        }
+        report.flush()?;
+    }
+
+    if let (Some(cutoff), Some(path)) = (config.dormant_since, &config.dormant_output) {
+        let mut report = Writer::from_path(current_dir()?.join(path))?;
+        for client in processor.get_clients_with_no_activity_since(cutoff)? {
+            report.serialize(ClientReportEntry { client })?;
+        }
+        report.flush()?;
+    }
+
+    if let Some(period) = config.regulatory_report {
+        let format = config.format.unwrap_or(ReportFormat::Csv);
+        let report = processor.generate_regulatory_report(period, format)?;
+        let mut output = writer
+            .into_inner()
+            .map_err(|e| anyhow::anyhow!("{}", e.error()))?;
+        output.write_all(report.as_bytes())?;
+        output.flush()?;
+    } else {
+        if let (OutputMode::Statement, Some(client)) = (config.mode, config.client) {
+            for mut entry in processor.get_statement(client)? {
+                entry.scaled();
+                writer.serialize(entry)?;
+            }
+        } else if let Some(path) = &config.reconcile {
+            let expected = fs::read_to_string(current_dir()?.join(path))?;
+            for diff in processor.get_accounts_diff(&expected)? {
+                writer.serialize(diff)?;
+            }
+        } else {
+            let mut balances = match config.as_of {
+                Some(timestamp) => processor.get_snapshot_at(timestamp)?,
+                None => processor.get_accounts()?,
+            };
+            balances.retain(|balance| {
+                config
+                    .client_filter
+                    .is_none_or(|client| balance.client == client)
+            });
+            balances.iter_mut().for_each(Account::scaled);
+            // Summed before any row is written, so an overflow leaves no partial output
+            let mut totals = AccountsSummary::default();
+            if config.summary {
+                for balance in &balances {
+                    totals.add(balance)?;
+                }
+            }
+            for balance in balances {
+                match config.column_order {
+                    ColumnOrder::Standard => writer.serialize(balance)?,
+                    ColumnOrder::TotalFirst => {
+                        writer.serialize(AccountOutputView::from(&balance))?
+                    }
+                }
+            }
+            if config.summary {
+                match config.column_order {
+                    ColumnOrder::Standard => writer.serialize(totals)?,
+                    ColumnOrder::TotalFirst => {
+                        writer.serialize(AccountOutputView::from(&totals))?
+                    }
+                }
+            }
+        }
+
+        writer.flush()?;
+    }
+    if let Some(error_writer) = error_writer.as_mut() {
+        error_writer.flush()?;
+    }
+    tracing::info!("Processing metrics: {:?}", processor.metrics());
+
+    if config.validate {
+        let errors = processor.state().validate_consistency();
+        if !errors.is_empty() {
+            for error in &errors {
+                tracing::error!("Consistency error: {}", error);
+            }
+            return Err(ProcessingError::InvariantViolation {
+                reason: format!("found {} state consistency errors", errors.len()),
+            }
+            .into());
+        }
+    }
+
+    if config.check_orphans {
+        let orphans = processor.get_orphaned_transactions()?;
+        if !orphans.is_empty() {
+            for orphan in &orphans {
+                tracing::error!("Orphaned transaction: {:?}", orphan);
+            }
+            return Err(ProcessingError::InvariantViolation {
+                reason: format!("found {} orphaned transactions", orphans.len()),
+            }
+            .into());
+        }
+    }
 
-    Ok(())
+    Ok(summary)
 }
@@ -0,0 +1,346 @@
+use std::collections::{HashMap, VecDeque};
+use std::mem::size_of;
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use tokio::sync::watch;
+
+use crate::{
+    api::{ProcessingError, ProcessingResult},
+    domain::{
+        Account, AccountTier, AccountVersion, Amount, BalanceSnapshot, ClientId, JournalEntry,
+        RiskScore, StoredTransaction, TierConfig, TransactionId,
+    },
+    state::{storage_poisoned, State, StateStorage},
+};
+
+/// Storage bounding the estimated memory of the stored transactions to `max_bytes` by
+/// evicting the least recently used deposits and withdrawals. Transactions under dispute
+/// are never evicted, they are queued again as recently used instead. Evicted transactions
+/// can't be disputed anymore and their ids are no longer rejected as duplicates; the
+/// journal, accounts and histories aren't bounded.
+pub struct MemoryBoundedState<S: StateStorage = State> {
+    state: S,
+    max_bytes: usize,
+    usage: Mutex<Usage>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Usage {
+    bytes: usize,
+    /// Ids of the stored deposits and withdrawals, the least recently used first
+    evictable: VecDeque<TransactionId>,
+}
+
+impl<S: StateStorage> MemoryBoundedState<S> {
+    /// Transactions already in `state` aren't accounted for
+    pub fn new(state: S, max_bytes: usize) -> Self {
+        Self {
+            state,
+            max_bytes,
+            usage: Mutex::new(Usage::default()),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.state
+    }
+
+    /// Estimated size of the transactions inserted through this storage and still stored
+    pub fn estimated_bytes(&self) -> ProcessingResult<usize> {
+        self.usage().map(|usage| usage.bytes)
+    }
+
+    fn usage(&self) -> ProcessingResult<MutexGuard<'_, Usage>> {
+        self.usage.lock().map_err(|e| storage_poisoned("usage", e))
+    }
+
+    fn evict(&self, usage: &mut Usage) -> ProcessingResult<()> {
+        let mut requeued = 0;
+        while usage.bytes > self.max_bytes && requeued < usage.evictable.len() {
+            let Some(id) = usage.evictable.pop_front() else {
+                break;
+            };
+            match self.state.get_transaction(id) {
+                Ok(transaction) if transaction.is_under_dispute() => {
+                    usage.evictable.push_back(id);
+                    requeued += 1;
+                }
+                Ok(transaction) => {
+                    tracing::debug!("Evicting transaction with id {}", id);
+                    self.state.remove_transaction(id)?;
+                    usage.bytes = usage.bytes.saturating_sub(estimated_size(&transaction));
+                }
+                Err(ProcessingError::TransactionNotFound { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Size of the map entry and the strings the transaction owns
+fn estimated_size(transaction: &StoredTransaction) -> usize {
+    let owned = match transaction {
+        StoredTransaction::Deposit { processor_id, .. }
+        | StoredTransaction::Withdrawal { processor_id, .. } => {
+            processor_id.as_ref().map_or(0, String::len)
+        }
+        StoredTransaction::RegulatoryHold {
+            reason, authority, ..
+        } => reason.len() + authority.len(),
+        _ => 0,
+    };
+    size_of::<TransactionId>() + size_of::<StoredTransaction>() + owned
+}
+
+impl<S: StateStorage> StateStorage for MemoryBoundedState<S> {
+    fn insert_transaction(
+        &self,
+        transaction: StoredTransaction,
+    ) -> ProcessingResult<StoredTransaction> {
+        let stored = self.state.insert_transaction(transaction)?;
+        let mut usage = self.usage()?;
+        match &stored {
+            StoredTransaction::Deposit { id, .. } | StoredTransaction::Withdrawal { id, .. } => {
+                usage.bytes += estimated_size(&stored);
+                usage.evictable.push_back(*id);
+            }
+            StoredTransaction::Transfer { .. }
+            | StoredTransaction::RegulatoryHold { .. }
+            | StoredTransaction::Refund { .. } => usage.bytes += estimated_size(&stored),
+            _ => {}
+        }
+        self.evict(&mut usage)?;
+        Ok(stored)
+    }
+
+    /// The id stays queued for eviction and is skipped once it's reached
+    fn remove_transaction(&self, id: TransactionId) -> ProcessingResult<()> {
+        let mut usage = self.usage()?;
+        match self.state.get_transaction(id) {
+            Ok(removed) => {
+                self.state.remove_transaction(id)?;
+                usage.bytes = usage.bytes.saturating_sub(estimated_size(&removed));
+                Ok(())
+            }
+            Err(ProcessingError::TransactionNotFound { .. }) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_transaction(&self, id: TransactionId) -> ProcessingResult<StoredTransaction> {
+        self.state.get_transaction(id)
+    }
+
+    fn get_transaction_for_client(
+        &self,
+        id: TransactionId,
+        client_id: ClientId,
+    ) -> ProcessingResult<StoredTransaction> {
+        self.state.get_transaction_for_client(id, client_id)
+    }
+
+    fn contains_transaction(&self, id: TransactionId) -> ProcessingResult<bool> {
+        self.state.contains_transaction(id)
+    }
+
+    fn under_dispute(&self, id: TransactionId, under_dispute: bool) -> ProcessingResult<()> {
+        self.state.under_dispute(id, under_dispute)
+    }
+
+    fn release_hold(&self, id: TransactionId) -> ProcessingResult<()> {
+        self.state.release_hold(id)
+    }
+
+    fn approve_kyc(&self, client_id: ClientId) -> ProcessingResult<()> {
+        self.state.approve_kyc(client_id)
+    }
+
+    fn get_transactions_for_client(&self, id: ClientId) -> ProcessingResult<Vec<JournalEntry>> {
+        self.state.get_transactions_for_client(id)
+    }
+
+    fn get_journal_entries_until(&self, end: SystemTime) -> ProcessingResult<Vec<JournalEntry>> {
+        self.state.get_journal_entries_until(end)
+    }
+
+    fn get_clients_with_no_activity_since(
+        &self,
+        cutoff: SystemTime,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        self.state.get_clients_with_no_activity_since(cutoff)
+    }
+
+    fn get_transactions_in_amount_range(
+        &self,
+        min: Amount,
+        max: Amount,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.state.get_transactions_in_amount_range(min, max)
+    }
+
+    fn get_transactions_by_processor(
+        &self,
+        processor_id: &str,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.state.get_transactions_by_processor(processor_id)
+    }
+
+    fn get_orphaned_transactions(&self) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.state.get_orphaned_transactions()
+    }
+
+    fn transaction_count(&self) -> ProcessingResult<usize> {
+        self.state.transaction_count()
+    }
+
+    fn count_open_disputes(&self, client_id: ClientId) -> ProcessingResult<u32> {
+        self.state.count_open_disputes(client_id)
+    }
+
+    fn iter_transactions<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = ProcessingResult<StoredTransaction>> + 'a> {
+        self.state.iter_transactions()
+    }
+    fn get_all_accounts(&self) -> ProcessingResult<Vec<Account>> {
+        self.state.get_all_accounts()
+    }
+
+    fn get_accounts_sorted_by_risk(&self) -> ProcessingResult<Vec<(Account, RiskScore)>> {
+        self.state.get_accounts_sorted_by_risk()
+    }
+
+    fn get_account(&self, id: &ClientId) -> ProcessingResult<Account> {
+        self.state.get_account(id)
+    }
+
+    fn with_account<F, R>(&self, id: ClientId, f: F) -> ProcessingResult<R>
+    where
+        F: FnOnce(&Account) -> R,
+    {
+        self.state.with_account(id, f)
+    }
+
+    fn account_count(&self) -> ProcessingResult<usize> {
+        self.state.account_count()
+    }
+
+    fn get_accounts_with_zero_balance(&self) -> ProcessingResult<Vec<Account>> {
+        self.state.get_accounts_with_zero_balance()
+    }
+
+    fn get_clients_exceeding_balance_threshold(
+        &self,
+        threshold: Amount,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        self.state
+            .get_clients_exceeding_balance_threshold(threshold)
+    }
+
+    fn get_accounts_by_tier(
+        &self,
+        config: &TierConfig,
+    ) -> ProcessingResult<HashMap<AccountTier, Vec<Account>>> {
+        self.state.get_accounts_by_tier(config)
+    }
+
+    fn remove_account(&self, id: ClientId) -> ProcessingResult<()> {
+        self.state.remove_account(id)
+    }
+
+    fn migrate_client_id(
+        &self,
+        old_id: ClientId,
+        new_id: ClientId,
+        merge: bool,
+    ) -> ProcessingResult<()> {
+        self.state.migrate_client_id(old_id, new_id, merge)
+    }
+
+    fn register_delegation(&self, from: ClientId, to: ClientId) -> ProcessingResult<()> {
+        self.state.register_delegation(from, to)
+    }
+
+    fn resolve_delegation(&self, id: ClientId) -> ProcessingResult<ClientId> {
+        self.state.resolve_delegation(id)
+    }
+
+    fn upsert_account(
+        &self,
+        account: Account,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        self.state.upsert_account(account, triggered_by)
+    }
+
+    fn upsert_accounts(
+        &self,
+        accounts: Vec<Account>,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        self.state.upsert_accounts(accounts, triggered_by)
+    }
+
+    fn update_account(
+        &self,
+        id: ClientId,
+        triggered_by: TransactionId,
+        update: &mut dyn FnMut(&mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<()> {
+        self.state.update_account(id, triggered_by, update)
+    }
+
+    fn update_accounts(
+        &self,
+        from: ClientId,
+        to: ClientId,
+        triggered_by: TransactionId,
+        update: &mut dyn FnMut(&mut Account, &mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<()> {
+        self.state.update_accounts(from, to, triggered_by, update)
+    }
+
+    fn balance_history(&self, id: ClientId) -> ProcessingResult<Vec<BalanceSnapshot>> {
+        self.state.balance_history(id)
+    }
+
+    fn get_account_versions(&self, id: ClientId) -> ProcessingResult<Vec<AccountVersion>> {
+        self.state.get_account_versions(id)
+    }
+
+    fn compact_account_history(&self, id: ClientId, keep_last: usize) -> ProcessingResult<()> {
+        self.state.compact_account_history(id, keep_last)
+    }
+
+    fn subscribe_to_account_updates(
+        &self,
+        id: ClientId,
+    ) -> ProcessingResult<watch::Receiver<Account>> {
+        self.state.subscribe_to_account_updates(id)
+    }
+
+    fn clear(&self) -> ProcessingResult<()> {
+        let mut usage = self.usage()?;
+        self.state.clear()?;
+        *usage = Usage::default();
+        Ok(())
+    }
+
+    fn snapshot(&self) -> ProcessingResult<Self> {
+        let usage = self.usage()?;
+        Ok(Self {
+            state: self.state.snapshot()?,
+            max_bytes: self.max_bytes,
+            usage: Mutex::new(usage.clone()),
+        })
+    }
+
+    fn restore(&self, snapshot: &Self) -> ProcessingResult<()> {
+        let mut usage = self.usage()?;
+        self.state.restore(&snapshot.state)?;
+        *usage = snapshot.usage()?.clone();
+        Ok(())
+    }
+}
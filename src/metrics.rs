@@ -0,0 +1,91 @@
+use crate::domain::{Amount, StoredTransaction};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessingMetrics {
+    pub deposits_processed: u64,
+    pub deposits_rejected: u64,
+    pub withdrawals_processed: u64,
+    pub withdrawals_rejected: u64,
+    pub transfers_processed: u64,
+    pub transfers_rejected: u64,
+    pub disputes_opened: u64,
+    pub disputes_rejected: u64,
+    pub resolves_applied: u64,
+    pub chargebacks_applied: u64,
+    pub regulatory_holds_applied: u64,
+    pub regulatory_releases_applied: u64,
+    pub kyc_approvals_applied: u64,
+    pub refunds_processed: u64,
+    pub refunds_rejected: u64,
+    pub fees_charged: u64,
+    pub total_deposited: Amount,
+    pub total_withdrawn: Amount,
+    pub total_transferred: Amount,
+    pub total_refunded: Amount,
+    pub total_fees: Amount,
+    /// Set once one of the totals no longer fit an `Amount`, it then stays at `Amount::MAX`
+    pub totals_saturated: bool,
+}
+
+impl ProcessingMetrics {
+    pub fn record_processed(&mut self, transaction: &StoredTransaction) {
+        match transaction {
+            StoredTransaction::Deposit { amount, .. } => {
+                self.deposits_processed += 1;
+                self.totals_saturated |= accumulate(&mut self.total_deposited, amount);
+            }
+            StoredTransaction::Withdrawal { amount, .. } => {
+                self.withdrawals_processed += 1;
+                self.totals_saturated |= accumulate(&mut self.total_withdrawn, amount);
+            }
+            StoredTransaction::Transfer { amount, .. } => {
+                self.transfers_processed += 1;
+                self.totals_saturated |= accumulate(&mut self.total_transferred, amount);
+            }
+            StoredTransaction::Dispute { .. } => self.disputes_opened += 1,
+            StoredTransaction::Resolve { .. } => self.resolves_applied += 1,
+            StoredTransaction::Chargeback { .. } => self.chargebacks_applied += 1,
+            StoredTransaction::RegulatoryHold { .. } => self.regulatory_holds_applied += 1,
+            StoredTransaction::RegulatoryRelease { .. } => self.regulatory_releases_applied += 1,
+            StoredTransaction::KycApproved { .. } => self.kyc_approvals_applied += 1,
+            StoredTransaction::Refund { amount, .. } => {
+                self.refunds_processed += 1;
+                self.totals_saturated |= accumulate(&mut self.total_refunded, amount);
+            }
+            StoredTransaction::Fee { amount, .. } => {
+                self.fees_charged += 1;
+                self.totals_saturated |= accumulate(&mut self.total_fees, amount);
+            }
+        }
+    }
+
+    pub fn record_rejected(&mut self, transaction: &StoredTransaction) {
+        match transaction {
+            StoredTransaction::Deposit { .. } => self.deposits_rejected += 1,
+            StoredTransaction::Withdrawal { .. } => self.withdrawals_rejected += 1,
+            StoredTransaction::Transfer { .. } => self.transfers_rejected += 1,
+            StoredTransaction::Dispute { .. } => self.disputes_rejected += 1,
+            StoredTransaction::Refund { .. } => self.refunds_rejected += 1,
+            StoredTransaction::Resolve { .. }
+            | StoredTransaction::Chargeback { .. }
+            | StoredTransaction::RegulatoryHold { .. }
+            | StoredTransaction::RegulatoryRelease { .. }
+            | StoredTransaction::KycApproved { .. }
+            | StoredTransaction::Fee { .. } => {}
+        }
+    }
+}
+
+/// Adds `amount` to `total`, saturating at `Amount::MAX`. Returns whether it saturated.
+fn accumulate(total: &mut Amount, amount: &Amount) -> bool {
+    match total.checked_add(*amount) {
+        Some(sum) => {
+            *total = sum;
+            false
+        }
+        None => {
+            *total = Amount::MAX;
+            true
+        }
+    }
+}
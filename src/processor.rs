@@ -1,85 +1,1724 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "serde")]
+use csv::{ReaderBuilder, Trim, WriterBuilder};
+use futures::{Stream, StreamExt};
+use rayon::prelude::*;
 use rust_decimal::Decimal;
+use tokio::sync::watch;
 
+#[cfg(feature = "serde")]
+use crate::{api::ErrorSource, domain::ReportFormat};
 use crate::{
     api::{ProcessingError, ProcessingResult},
-    domain::{Account, StoredTransaction, TransactionId},
-    state::StateStorage,
+    domain::{
+        Account, AccountDelta, AccountTier, AccountVersion, Amount, BalanceSnapshot, ClientId,
+        ClientVolume, ClosedAccountSummary, DateRange, DryRunReport, FeeSchedule, JournalEntry,
+        ProcessingStats, ReconciliationDiff, RegulatoryReport, RiskScore, SequenceWarning,
+        StatementEntry, StoredTransaction, TierConfig, Transaction, TransactionId, TransactionType,
+        VelocityMetrics, RESERVED_CLIENT_ID,
+    },
+    metrics::ProcessingMetrics,
+    state::{State, StateStorage, StateTransaction},
 };
 
+const REGULATORY_REPORT_TOP_CLIENTS: usize = 10;
+/// Recorded as the triggering transaction of account closures, which aren't transactions
+const ACCOUNT_CLOSURE: TransactionId = 0;
+const PROGRESS_LOG_INTERVAL: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackoffStrategy {
+    Immediate,
+    Fixed(Duration),
+    Exponential {
+        initial: Duration,
+        factor: f64,
+        max: Duration,
+    },
+}
+
+impl BackoffStrategy {
+    fn delay(&self, retry: u32) -> Duration {
+        match self {
+            Self::Immediate => Duration::ZERO,
+            Self::Fixed(delay) => *delay,
+            Self::Exponential {
+                initial,
+                factor,
+                max,
+            } => initial.mul_f64(factor.powi(retry as i32)).min(*max),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    pub backoff: BackoffStrategy,
+}
+
+pub type PreProcessHook = Arc<dyn Fn(&mut StoredTransaction) + Send + Sync>;
+pub type PostProcessHook = Arc<dyn Fn(&StoredTransaction, &Account) + Send + Sync>;
+
+pub struct ProcessorBuilder<S: StateStorage> {
+    state: S,
+    pre_process_hooks: Vec<PreProcessHook>,
+    post_process_hooks: Vec<PostProcessHook>,
+    strict_lock: bool,
+    strict_amounts: bool,
+    lenient_resolve: bool,
+    fee_schedule: Option<FeeSchedule>,
+    closing_fee_destination: ClientId,
+    velocity_limit: Option<(usize, Duration)>,
+    max_open_disputes: Option<u32>,
+    processor_id: Option<String>,
+}
+
+impl<S: StateStorage> ProcessorBuilder<S> {
+    pub fn new(state: S) -> Self {
+        Self {
+            state,
+            pre_process_hooks: Vec::new(),
+            post_process_hooks: Vec::new(),
+            strict_lock: false,
+            strict_amounts: false,
+            lenient_resolve: true,
+            fee_schedule: None,
+            closing_fee_destination: RESERVED_CLIENT_ID,
+            velocity_limit: None,
+            max_open_disputes: None,
+            processor_id: None,
+        }
+    }
+
+    pub fn with_pre_process_hook(
+        mut self,
+        hook: impl Fn(&mut StoredTransaction) + Send + Sync + 'static,
+    ) -> Self {
+        self.pre_process_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Called with the transaction and each account it updated after each successfully processed
+    /// transaction, so twice for a transfer
+    pub fn with_post_process_hook(
+        mut self,
+        hook: impl Fn(&StoredTransaction, &Account) + Send + Sync + 'static,
+    ) -> Self {
+        self.post_process_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Rejects deposits into locked accounts as well, not only outgoing transactions
+    pub fn with_strict_lock(mut self, strict_lock: bool) -> Self {
+        self.strict_lock = strict_lock;
+        self
+    }
+
+    /// Rejects transactions referencing other transactions that carry an amount instead of only warning
+    pub fn with_strict_amounts(mut self, strict_amounts: bool) -> Self {
+        self.strict_amounts = strict_amounts;
+        self
+    }
+
+    /// Ignores resolves of deposits that aren't under dispute, which is the default, instead of
+    /// rejecting them with `TransactionNotUnderDispute`
+    pub fn with_lenient_resolve(mut self, lenient_resolve: bool) -> Self {
+        self.lenient_resolve = lenient_resolve;
+        self
+    }
+
+    /// Charges the fee on every deposit, recording it as a `Fee` transaction. Deposits less
+    /// than their fee are rejected.
+    pub fn with_fee_schedule(mut self, fee_schedule: FeeSchedule) -> Self {
+        self.fee_schedule = Some(fee_schedule);
+        self
+    }
+
+    /// Client whose account receives the remaining available funds of closed accounts, the
+    /// reserved client `0` by default, whose account only ever receives these funds
+    pub fn with_closing_fee_destination(mut self, client_id: ClientId) -> Self {
+        self.closing_fee_destination = client_id;
+        self
+    }
+
+    /// Rejects transactions of clients who already made `max_count` transactions within the window.
+    /// Only transactions stored through this processor since it was built count.
+    pub fn with_velocity_limit(mut self, max_count: usize, window: Duration) -> Self {
+        self.velocity_limit = Some((max_count, window));
+        self
+    }
+
+    /// Rejects disputes that would leave the client with more than `max` open disputes
+    pub fn with_max_open_disputes(mut self, max: u32) -> Self {
+        self.max_open_disputes = Some(max);
+        self
+    }
+
+    /// Identifies this instance on the deposits and withdrawals it records, e.g. a hostname
+    pub fn processor_id(mut self, id: String) -> Self {
+        self.processor_id = Some(id);
+        self
+    }
+
+    pub fn build(self) -> TransactionProcessor<S> {
+        TransactionProcessor {
+            state: self.state,
+            metrics: Arc::new(Mutex::new(ProcessingMetrics::default())),
+            pending_commits: Arc::new(Mutex::new(HashMap::new())),
+            recent_transactions: Arc::new(Mutex::new(HashMap::new())),
+            pre_process_hooks: self.pre_process_hooks,
+            post_process_hooks: self.post_process_hooks,
+            strict_lock: self.strict_lock,
+            strict_amounts: self.strict_amounts,
+            lenient_resolve: self.lenient_resolve,
+            fee_schedule: self.fee_schedule,
+            closing_fee_destination: self.closing_fee_destination,
+            velocity_limit: self.velocity_limit,
+            max_open_disputes: self.max_open_disputes,
+            processor_id: self.processor_id,
+        }
+    }
+}
+
+type PendingCommits = HashMap<TransactionId, (StoredTransaction, AccountDelta)>;
+/// When each client's transactions were stored, oldest first, for the velocity limit
+type RecentTransactions = HashMap<ClientId, VecDeque<SystemTime>>;
+
 pub struct TransactionProcessor<S: StateStorage> {
     state: S,
+    metrics: Arc<Mutex<ProcessingMetrics>>,
+    pending_commits: Arc<Mutex<PendingCommits>>,
+    recent_transactions: Arc<Mutex<RecentTransactions>>,
+    pre_process_hooks: Vec<PreProcessHook>,
+    post_process_hooks: Vec<PostProcessHook>,
+    strict_lock: bool,
+    strict_amounts: bool,
+    lenient_resolve: bool,
+    fee_schedule: Option<FeeSchedule>,
+    closing_fee_destination: ClientId,
+    velocity_limit: Option<(usize, Duration)>,
+    max_open_disputes: Option<u32>,
+    processor_id: Option<String>,
+}
+
+impl<S: StateStorage + Clone> Clone for TransactionProcessor<S> {
+    fn clone(&self) -> Self {
+        let pending_commits = match self.pending_commits.lock() {
+            Ok(pending_commits) => pending_commits.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        let recent_transactions = match self.recent_transactions.lock() {
+            Ok(recent_transactions) => recent_transactions.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        Self {
+            state: self.state.clone(),
+            metrics: Arc::new(Mutex::new(self.metrics())),
+            pending_commits: Arc::new(Mutex::new(pending_commits)),
+            recent_transactions: Arc::new(Mutex::new(recent_transactions)),
+            pre_process_hooks: self.pre_process_hooks.clone(),
+            post_process_hooks: self.post_process_hooks.clone(),
+            strict_lock: self.strict_lock,
+            strict_amounts: self.strict_amounts,
+            lenient_resolve: self.lenient_resolve,
+            fee_schedule: self.fee_schedule,
+            closing_fee_destination: self.closing_fee_destination,
+            velocity_limit: self.velocity_limit,
+            max_open_disputes: self.max_open_disputes,
+            processor_id: self.processor_id.clone(),
+        }
+    }
+}
+
+impl<S: StateStorage + Default> Default for TransactionProcessor<S> {
+    fn default() -> Self {
+        Self::new(S::default())
+    }
 }
 
 impl<S: StateStorage> TransactionProcessor<S> {
     pub fn new(state: S) -> Self {
-        Self { state }
+        ProcessorBuilder::new(state).build()
+    }
+
+    pub fn builder(state: S) -> ProcessorBuilder<S> {
+        ProcessorBuilder::new(state)
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn into_state(self) -> S {
+        self.state
     }
 
-    pub fn process(&self, transaction: StoredTransaction) -> ProcessingResult<()> {
+    /// Clears the state, metrics and pending commits, leaving the configuration as is.
+    pub fn reset(&mut self) -> ProcessingResult<()> {
+        self.state.clear()?;
+        self.update_metrics(|metrics| *metrics = ProcessingMetrics::default());
+        self.pending_commits
+            .lock()
+            .map_err(|e| ProcessingError::StoragePoisoned {
+                context: format!("pending_commits: {}", e),
+            })?
+            .clear();
+        self.recent_transactions
+            .lock()
+            .map_err(|e| ProcessingError::StoragePoisoned {
+                context: format!("recent_transactions: {}", e),
+            })?
+            .clear();
+        Ok(())
+    }
+
+    pub fn process_transaction(&self, transaction: Transaction) -> ProcessingResult<()> {
+        if transaction.has_unexpected_amount() {
+            if self.strict_amounts {
+                tracing::error!("Transaction carries unexpected amount: {:?}", transaction);
+                let id = transaction.tx;
+                self.update_metrics(|metrics| metrics.record_rejected(&transaction.into()));
+                return Err(ProcessingError::UnexpectedAmount { id });
+            }
+            tracing::warn!("Ignoring amount of transaction: {:?}", transaction);
+        }
+        self.process(transaction.into())
+    }
+
+    pub fn process(&self, mut transaction: StoredTransaction) -> ProcessingResult<()> {
+        if let Some(processor_id) = &self.processor_id {
+            transaction.set_processor_id(processor_id);
+        }
+        for hook in &self.pre_process_hooks {
+            hook(&mut transaction);
+        }
+        let _span = tracing::info_span!(
+            "process_transaction",
+            tx_id = %transaction.id(),
+            client_id = %transaction.client_id()
+        )
+        .entered();
         if transaction.is_not_valid() {
             tracing::error!("Transaction is not valid: {:?}", transaction);
+            self.update_metrics(|metrics| metrics.record_rejected(&transaction));
             return Err(ProcessingError::TransactionIsNotValid {
-                id: transaction.id().clone(),
+                id: *transaction.id(),
             });
         }
+        if let Err(e) = check_not_reserved(&transaction) {
+            self.update_metrics(|metrics| metrics.record_rejected(&transaction));
+            return Err(e);
+        }
         tracing::debug!("Processing: {:?}", transaction);
-        let _ = self
+        let mut applied = true;
+        let result = self
+            .check_velocity(&transaction)
+            .and_then(|_| self.check_not_duplicate(&transaction))
+            .and_then(|_| self.state.insert_transaction(transaction.clone()))
+            .and_then(|tx| {
+                self.record_recent(&tx)?;
+                self.update_account(&tx, |account| {
+                    applied = self.adjust_account(account, &tx)?;
+                    Ok(())
+                })
+                .or_else(|e| self.forget_rejected(&tx, e))
+            });
+        match result {
+            Ok(updated) => {
+                if applied {
+                    self.update_metrics(|metrics| metrics.record_processed(&transaction));
+                }
+                self.run_post_process_hooks(&transaction, &updated);
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!("Processing error {}", e);
+                self.update_metrics(|metrics| metrics.record_rejected(&transaction));
+                Err(e)
+            }
+        }
+    }
+
+    /// Deserializes and processes the reader's records in order, see
+    /// [`process_records`](Self::process_records)
+    #[cfg(feature = "serde")]
+    pub fn process_reader<R: std::io::Read>(
+        &self,
+        reader: &mut csv::Reader<R>,
+    ) -> ProcessingResult<ProcessingStats> {
+        self.process_records(reader.deserialize::<Transaction>())
+    }
+
+    /// Processes deserialized records in order. Malformed records are logged and skipped,
+    /// rejected transactions are kept in the stats with their errors. Only a poisoned
+    /// storage stops processing since no later record could be processed either.
+    pub fn process_records<E: std::fmt::Display>(
+        &self,
+        records: impl IntoIterator<Item = Result<Transaction, E>>,
+    ) -> ProcessingResult<ProcessingStats> {
+        let mut stats = ProcessingStats::default();
+        for record in records {
+            if stats.attempted > 0 && stats.attempted % PROGRESS_LOG_INTERVAL == 0 {
+                tracing::info!(
+                    "Processed {} records, {} transactions stored, {} accounts",
+                    stats.attempted,
+                    self.transaction_count()?,
+                    self.account_count()?
+                );
+            }
+            stats.attempted += 1;
+            let transaction = match record {
+                Ok(transaction) => transaction,
+                Err(e) => {
+                    tracing::warn!("Skipping malformed record: {}", e);
+                    stats.failed += 1;
+                    stats.malformed += 1;
+                    continue;
+                }
+            };
+            match self.process_transaction(transaction.clone()) {
+                Ok(()) => stats.succeeded += 1,
+                Err(e @ ProcessingError::StoragePoisoned { .. }) => return Err(e),
+                Err(e) => {
+                    stats.failed += 1;
+                    stats.rejected.push((transaction, e));
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Processes `transaction` on the staging storage of `txn`, leaving this processor's
+    /// state untouched until the transaction is committed. Post-process hooks aren't run
+    /// since the changes may still be rolled back.
+    pub fn process_in_txn(
+        &self,
+        txn: &mut StateTransaction<'_, S>,
+        transaction: Transaction,
+    ) -> ProcessingResult<()>
+    where
+        S: Default,
+    {
+        let staging = TransactionProcessor {
+            state: std::mem::take(txn.staging_mut()),
+            metrics: self.metrics.clone(),
+            pending_commits: Arc::new(Mutex::new(HashMap::new())),
+            recent_transactions: self.recent_transactions.clone(),
+            pre_process_hooks: self.pre_process_hooks.clone(),
+            post_process_hooks: Vec::new(),
+            strict_lock: self.strict_lock,
+            strict_amounts: self.strict_amounts,
+            lenient_resolve: self.lenient_resolve,
+            fee_schedule: self.fee_schedule,
+            closing_fee_destination: self.closing_fee_destination,
+            velocity_limit: self.velocity_limit,
+            max_open_disputes: self.max_open_disputes,
+            processor_id: self.processor_id.clone(),
+        };
+        let result = staging.process_transaction(transaction);
+        *txn.staging_mut() = staging.state;
+        result
+    }
+
+    pub fn register_delegation(&self, from: ClientId, to: ClientId) -> ProcessingResult<()> {
+        self.state.register_delegation(from, to)
+    }
+
+    /// Processes the transaction against the account `delegate_to` is delegated to,
+    /// following the whole delegation chain.
+    pub fn process_delegation(
+        &self,
+        mut transaction: StoredTransaction,
+        delegate_to: ClientId,
+    ) -> ProcessingResult<()> {
+        let target = self.state.resolve_delegation(delegate_to)?;
+        tracing::debug!("Processing on behalf of client {}", target);
+        transaction.set_client_id(*transaction.client_id(), target);
+        self.process(transaction)
+    }
+
+    pub fn process_with_retry(
+        &self,
+        transaction: StoredTransaction,
+        policy: RetryPolicy,
+    ) -> ProcessingResult<()> {
+        let mut retry = 0;
+        loop {
+            let result = self.process(transaction.clone());
+            match &result {
+                Err(e) if e.is_transient() && (retry as usize) + 1 < policy.max_attempts => {
+                    let delay = policy.backoff.delay(retry);
+                    tracing::warn!("Retrying transaction {} in {:?}", transaction.id(), delay);
+                    std::thread::sleep(delay);
+                    retry += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    /// Processes the transactions of different clients in parallel, keeping each client's
+    /// transactions in order. Like [shards](crate::sharding), this relies on transactions
+    /// only touching their own client's account, except for transfers.
+    pub fn process_parallel(
+        &self,
+        transactions: Vec<StoredTransaction>,
+    ) -> Vec<ProcessingResult<()>> {
+        let mut results = Vec::with_capacity(transactions.len());
+        let mut batch = Vec::new();
+        for transaction in transactions {
+            if let StoredTransaction::Transfer { .. } = transaction {
+                // Transfers touch two clients, so they are processed in order between batches
+                results.extend(self.process_batch(std::mem::take(&mut batch)));
+                results.push(self.process(transaction));
+            } else {
+                batch.push(transaction);
+            }
+        }
+        results.extend(self.process_batch(batch));
+        results
+    }
+
+    fn process_batch(&self, batch: Vec<StoredTransaction>) -> Vec<ProcessingResult<()>> {
+        let mut partitions = HashMap::<ClientId, Vec<(usize, StoredTransaction)>>::new();
+        for (index, transaction) in batch.into_iter().enumerate() {
+            partitions
+                .entry(*transaction.client_id())
+                .or_default()
+                .push((index, transaction));
+        }
+        let mut results = partitions
+            .into_par_iter()
+            .flat_map_iter(|(_, partition)| {
+                partition
+                    .into_iter()
+                    .map(|(index, transaction)| (index, self.process(transaction)))
+            })
+            .collect::<Vec<_>>();
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    pub fn stream_process<'a, St>(
+        &'a self,
+        stream: St,
+    ) -> impl Stream<Item = ProcessingResult<()>> + 'a
+    where
+        St: Stream<Item = StoredTransaction> + 'a,
+    {
+        stream.map(move |transaction| self.process(transaction))
+    }
+
+    /// Scans the sequence for transactions that can't be applied in the given order,
+    /// e.g. a chargeback without a preceding dispute. Doesn't touch the state.
+    pub fn validate_transaction_sequence(
+        &self,
+        transactions: &[StoredTransaction],
+    ) -> Vec<SequenceWarning> {
+        let mut funded = BTreeSet::new();
+        let mut deposits = BTreeSet::new();
+        let mut disputed = BTreeSet::new();
+        let mut charged_back = BTreeSet::new();
+        let mut warnings = Vec::new();
+        for (index, transaction) in transactions.iter().enumerate() {
+            let id = *transaction.id();
+            let client_id = *transaction.client_id();
+            let warning = match transaction {
+                StoredTransaction::Deposit { .. } => {
+                    funded.insert(client_id);
+                    deposits.insert(id);
+                    None
+                }
+                StoredTransaction::Refund { .. } => {
+                    funded.insert(client_id);
+                    None
+                }
+                StoredTransaction::Withdrawal { .. } | StoredTransaction::Transfer { .. }
+                    if !funded.contains(&client_id) =>
+                {
+                    Some(format!(
+                        "{:?} before any deposit of client {}",
+                        transaction.transaction_type(),
+                        client_id
+                    ))
+                }
+                StoredTransaction::Transfer { to_client, .. } => {
+                    funded.insert(*to_client);
+                    None
+                }
+                StoredTransaction::Dispute { .. } if !deposits.contains(&id) => {
+                    Some(format!("Dispute of unknown deposit {}", id))
+                }
+                StoredTransaction::Dispute { .. } if charged_back.contains(&id) => {
+                    Some(format!("Dispute after chargeback of transaction {}", id))
+                }
+                StoredTransaction::Dispute { .. } => {
+                    if disputed.insert(id) {
+                        None
+                    } else {
+                        Some(format!("Transaction {} is already under dispute", id))
+                    }
+                }
+                StoredTransaction::Resolve { .. } | StoredTransaction::Chargeback { .. }
+                    if charged_back.contains(&id) =>
+                {
+                    Some(format!(
+                        "{:?} after chargeback of transaction {}",
+                        transaction.transaction_type(),
+                        id
+                    ))
+                }
+                StoredTransaction::Resolve { .. } | StoredTransaction::Chargeback { .. }
+                    if !disputed.remove(&id) =>
+                {
+                    Some(format!(
+                        "{:?} without a preceding dispute of transaction {}",
+                        transaction.transaction_type(),
+                        id
+                    ))
+                }
+                StoredTransaction::Chargeback { .. } => {
+                    charged_back.insert(id);
+                    None
+                }
+                _ => None,
+            };
+            if let Some(warning) = warning {
+                warnings.push(SequenceWarning {
+                    index,
+                    tx_id: id,
+                    warning,
+                });
+            }
+        }
+        warnings
+    }
+
+    pub fn batch_dispute(
+        &self,
+        tx_ids: &[TransactionId],
+        client_id: ClientId,
+    ) -> Vec<ProcessingResult<()>> {
+        let mut account = match self.get_unlocked_account(&client_id) {
+            Ok(account) => account,
+            Err(e) => return tx_ids.iter().map(|_| Err(e.clone())).collect(),
+        };
+        let mut disputed = Vec::new();
+        let mut results = tx_ids
+            .iter()
+            .map(|id| {
+                if disputed.contains(id) {
+                    tracing::error!("Transaction already under dispute");
+                    return Err(ProcessingError::TransactionAlreadyUnderDispute { id: *id });
+                }
+                let applied = self.dispute(&mut account, id, disputed.len() as u32)?;
+                if applied {
+                    disputed.push(*id);
+                }
+                Ok(())
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(last) = disputed.last() {
+            let stored = self.state.upsert_account(account, *last).and_then(|_| {
+                disputed
+                    .iter()
+                    .try_for_each(|id| self.set_under_dispute(*id, client_id, true))
+            });
+            if let Err(e) = stored {
+                tracing::error!("Processing error {}", e);
+                for result in results.iter_mut().filter(|result| result.is_ok()) {
+                    *result = Err(e.clone());
+                }
+            }
+        }
+        for (id, result) in tx_ids.iter().zip(&results) {
+            let dispute = StoredTransaction::Dispute { id: *id, client_id };
+            match result {
+                Ok(()) if disputed.contains(id) => {
+                    self.update_metrics(|metrics| metrics.record_processed(&dispute))
+                }
+                Ok(()) => {}
+                Err(_) => self.update_metrics(|metrics| metrics.record_rejected(&dispute)),
+            }
+        }
+        results
+    }
+
+    pub fn process_noop(
+        &self,
+        mut transaction: StoredTransaction,
+    ) -> ProcessingResult<AccountDelta> {
+        if let Some(processor_id) = &self.processor_id {
+            transaction.set_processor_id(processor_id);
+        }
+        if transaction.is_not_valid() {
+            tracing::error!("Transaction is not valid: {:?}", transaction);
+            return Err(ProcessingError::TransactionIsNotValid {
+                id: *transaction.id(),
+            });
+        }
+        tracing::debug!("Processing without committing: {:?}", transaction);
+        self.check_not_duplicate(&transaction)?;
+        let before = self.get_account_for(&transaction)?;
+        let mut after = before.clone();
+        self.adjust_account(&mut after, &transaction)?;
+        if let StoredTransaction::Transfer { to_client, .. } = &transaction {
+            self.get_unlocked_account(to_client)?;
+        }
+        let delta = AccountDelta::between(&before, &after);
+        self.pending_commits
+            .lock()
+            .map_err(|e| ProcessingError::StoragePoisoned {
+                context: format!("pending_commits: {}", e),
+            })?
+            .insert(*transaction.id(), (transaction, delta.clone()));
+        Ok(delta)
+    }
+
+    pub fn process_commit(&self, id: TransactionId) -> ProcessingResult<()> {
+        let (transaction, delta) = self
+            .pending_commits
+            .lock()
+            .map_err(|e| ProcessingError::StoragePoisoned {
+                context: format!("pending_commits: {}", e),
+            })?
+            .remove(&id)
+            .ok_or(ProcessingError::TransactionNotPendingCommit { id })?;
+        tracing::debug!("Committing: {:?}", transaction);
+        let result = self
             .state
             .insert_transaction(transaction.clone())
-            .map(|tx| {
-                let mut account = self.state.get_account(tx.client_id())?;
-                if account.locked {
-                    tracing::error!("Account is locked: {:?}", account);
-                    return Err(ProcessingError::AccountIsLocked {
-                        client_id: account.client,
+            .and_then(|tx| {
+                self.record_recent(&tx)?;
+                self.update_account(&tx, |account| {
+                    account.available =
+                        checked_add(account.client, account.available, delta.available)?;
+                    account.held = checked_add(account.client, account.held, delta.held)?;
+                    account.total = checked_add(account.client, account.total, delta.total)?;
+                    account.locked |= delta.locked;
+                    account.chargeback_count += delta.chargeback_count;
+                    account.kyc_pending =
+                        checked_add(account.client, account.kyc_pending, delta.kyc_pending)?;
+                    Ok(())
+                })
+                .or_else(|e| self.forget_rejected(&tx, e))
+            });
+        match result {
+            Ok(updated) => {
+                self.update_metrics(|metrics| metrics.record_processed(&transaction));
+                self.run_post_process_hooks(&transaction, &updated);
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!("Processing error {}", e);
+                self.update_metrics(|metrics| metrics.record_rejected(&transaction));
+                Err(e)
+            }
+        }
+    }
+
+    pub fn apply_regulatory_hold(
+        &self,
+        id: TransactionId,
+        client_id: ClientId,
+        amount: Amount,
+        reason: String,
+        authority: String,
+    ) -> ProcessingResult<()> {
+        self.process(StoredTransaction::RegulatoryHold {
+            id,
+            client_id,
+            amount,
+            reason,
+            authority,
+            released: false,
+        })
+    }
+
+    pub fn get_accounts(&self) -> ProcessingResult<Vec<Account>> {
+        self.state.get_all_accounts()
+    }
+
+    #[deprecated(since = "0.2.0", note = "use `get_accounts`, which returns a `Vec`")]
+    #[allow(clippy::box_collection)]
+    pub fn get_accounts_boxed(&self) -> ProcessingResult<Box<Vec<Account>>> {
+        self.get_accounts().map(Box::new)
+    }
+
+    /// Calls `f` with the client's account without cloning it, e.g. to read a single balance
+    pub fn with_account<R>(
+        &self,
+        client_id: ClientId,
+        f: impl FnOnce(&Account) -> R,
+    ) -> ProcessingResult<R> {
+        self.state.with_account(client_id, f)
+    }
+
+    /// Accounts with their risk scores for the risk review, the riskiest first
+    pub fn get_accounts_sorted_by_risk(&self) -> ProcessingResult<Vec<(Account, RiskScore)>> {
+        self.state.get_accounts_sorted_by_risk()
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn get_accounts_diff(
+        &self,
+        expected_csv: &str,
+    ) -> ProcessingResult<Vec<ReconciliationDiff>> {
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(expected_csv.as_bytes());
+        let mut expected = HashMap::new();
+        for record in reader.deserialize::<Account>() {
+            let account = record.map_err(|e| ProcessingError::ReconciliationInputIsNotValid {
+                reason: e.to_string(),
+            })?;
+            expected.insert(account.client, account);
+        }
+        self.reconcile(&expected)
+    }
+
+    pub fn reconcile(
+        &self,
+        expected: &HashMap<ClientId, Account>,
+    ) -> ProcessingResult<Vec<ReconciliationDiff>> {
+        let actual = self
+            .get_accounts()?
+            .into_iter()
+            .map(|account| (account.client, account))
+            .collect::<HashMap<_, _>>();
+        let clients = expected
+            .keys()
+            .chain(actual.keys())
+            .copied()
+            .collect::<BTreeSet<_>>();
+
+        let mut diffs = Vec::new();
+        for client_id in clients {
+            let mut expected = expected
+                .get(&client_id)
+                .cloned()
+                .unwrap_or_else(|| Account::new(client_id));
+            let mut actual = actual
+                .get(&client_id)
+                .cloned()
+                .unwrap_or_else(|| Account::new(client_id));
+            expected.scaled();
+            actual.scaled();
+            let fields = [
+                ("available", expected.available, actual.available),
+                ("held", expected.held, actual.held),
+                ("total", expected.total, actual.total),
+            ];
+            for (field, expected, actual) in fields {
+                if expected != actual {
+                    diffs.push(ReconciliationDiff {
+                        client_id,
+                        field: field.to_string(),
+                        expected: expected.to_string(),
+                        actual: actual.to_string(),
                     });
                 }
-                self.adjust_account(&mut account, &tx)?;
-                self.state.upsert_account(account)?;
-                Ok(())
+            }
+            if expected.locked != actual.locked {
+                diffs.push(ReconciliationDiff {
+                    client_id,
+                    field: "locked".to_string(),
+                    expected: expected.locked.to_string(),
+                    actual: actual.locked.to_string(),
+                });
+            }
+        }
+        Ok(diffs)
+    }
+
+    pub fn transaction_count(&self) -> ProcessingResult<usize> {
+        self.state.transaction_count()
+    }
+
+    pub fn account_count(&self) -> ProcessingResult<usize> {
+        self.state.account_count()
+    }
+
+    pub fn get_accounts_with_zero_balance(&self) -> ProcessingResult<Vec<Account>> {
+        self.state.get_accounts_with_zero_balance()
+    }
+
+    pub fn get_clients_exceeding_balance_threshold(
+        &self,
+        threshold: Amount,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        self.state
+            .get_clients_exceeding_balance_threshold(threshold)
+    }
+
+    pub fn get_transactions_by_processor(
+        &self,
+        processor_id: &str,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.state.get_transactions_by_processor(processor_id)
+    }
+
+    pub fn get_clients_with_no_activity_since(
+        &self,
+        cutoff: SystemTime,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        self.state.get_clients_with_no_activity_since(cutoff)
+    }
+
+    pub fn get_transactions_in_amount_range(
+        &self,
+        min: Amount,
+        max: Amount,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.state.get_transactions_in_amount_range(min, max)
+    }
+
+    pub fn get_accounts_by_tier(
+        &self,
+        config: &TierConfig,
+    ) -> ProcessingResult<HashMap<AccountTier, Vec<Account>>> {
+        self.state.get_accounts_by_tier(config)
+    }
+
+    pub fn migrate_client_id(
+        &self,
+        old_id: ClientId,
+        new_id: ClientId,
+        merge: bool,
+    ) -> ProcessingResult<()> {
+        self.state.migrate_client_id(old_id, new_id, merge)
+    }
+
+    /// Closes an account without open disputes, held funds or a lock, moving its available
+    /// funds to the closing fee destination. Closed accounts reject all further transactions.
+    pub fn close_account(&self, client_id: ClientId) -> ProcessingResult<ClosedAccountSummary> {
+        self.close(client_id, false)
+    }
+
+    /// Closes an account like [`close_account`](Self::close_account) even if it's locked
+    pub fn force_close_account(
+        &self,
+        client_id: ClientId,
+    ) -> ProcessingResult<ClosedAccountSummary> {
+        self.close(client_id, true)
+    }
+
+    pub fn remove_account(&self, client_id: ClientId) -> ProcessingResult<()> {
+        self.state.remove_account(client_id)
+    }
+
+    /// Removes the accounts together with their clients' stored transactions, which would
+    /// otherwise be left without an account. Journal entries and history are kept.
+    pub fn archive_accounts(&self, client_ids: &[ClientId]) -> ProcessingResult<()> {
+        let clients = client_ids.iter().collect::<BTreeSet<_>>();
+        let transactions = self
+            .state
+            .iter_transactions()
+            .filter(|tx| {
+                tx.as_ref()
+                    .map_or(true, |tx| clients.contains(tx.client_id()))
             })
-            .map_err(|e| tracing::error!("Processing error {}", e));
+            .map(|tx| tx.map(|tx| *tx.id()))
+            .collect::<ProcessingResult<Vec<_>>>()?;
+        for id in transactions {
+            self.state.remove_transaction(id)?;
+        }
+        for client_id in client_ids {
+            self.state.remove_account(*client_id)?;
+        }
         Ok(())
     }
 
-    pub fn get_accounts(&self) -> ProcessingResult<Box<Vec<Account>>> {
-        self.state.get_all_accounts()
+    pub fn get_balance_history(
+        &self,
+        client_id: ClientId,
+    ) -> ProcessingResult<Vec<BalanceSnapshot>> {
+        self.state.balance_history(client_id)
+    }
+
+    pub fn get_account_versions(
+        &self,
+        client_id: ClientId,
+    ) -> ProcessingResult<Vec<AccountVersion>> {
+        self.state.get_account_versions(client_id)
+    }
+
+    pub fn compact_account_history(
+        &self,
+        client_id: ClientId,
+        keep_last: usize,
+    ) -> ProcessingResult<()> {
+        self.state.compact_account_history(client_id, keep_last)
+    }
+
+    /// Discards account versions created more than `age` ago for all clients.
+    pub fn compact_account_history_older_than(&self, age: Duration) -> ProcessingResult<()> {
+        let cutoff = SystemTime::now()
+            .checked_sub(age)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        for account in self.get_accounts()?.into_iter() {
+            let keep_last = self
+                .state
+                .get_account_versions(account.client)?
+                .iter()
+                .filter(|version| version.created_at >= cutoff)
+                .count();
+            self.state
+                .compact_account_history(account.client, keep_last)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_snapshot_at(&self, timestamp: SystemTime) -> ProcessingResult<Vec<Account>> {
+        let mut snapshot = Vec::new();
+        for account in self.get_accounts()?.into_iter() {
+            let version = self
+                .state
+                .get_account_versions(account.client)?
+                .into_iter()
+                .rev()
+                .find(|version| version.created_at <= timestamp);
+            snapshot.push(match version {
+                Some(version) => version.account,
+                None => Account::new(account.client),
+            });
+        }
+        Ok(snapshot)
+    }
+
+    pub fn get_velocity_metrics(
+        &self,
+        client_id: ClientId,
+        window: Duration,
+    ) -> ProcessingResult<VelocityMetrics> {
+        let since = SystemTime::now()
+            .checked_sub(window)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut metrics = VelocityMetrics::default();
+        let mut amounts = BTreeSet::new();
+        for entry in self.state.get_transactions_for_client(client_id)? {
+            if entry.created_at <= since {
+                continue;
+            }
+            metrics.transaction_count += 1;
+            if let StoredTransaction::Deposit { amount, .. } = &entry.transaction {
+                metrics.deposit_count += 1;
+                metrics.total_deposited = checked_add(client_id, metrics.total_deposited, *amount)?;
+            }
+            if let Some(amount) = entry.transaction.amount() {
+                amounts.insert(amount.normalize());
+            }
+        }
+        metrics.unique_amounts = amounts.len();
+        Ok(metrics)
+    }
+
+    /// Disputes, resolves, chargebacks and regulatory releases count with the amount of the
+    /// transaction they reference.
+    pub fn get_average_transaction_amount(
+        &self,
+        client_id: ClientId,
+        tx_type: TransactionType,
+    ) -> ProcessingResult<Amount> {
+        let mut average = Amount::ZERO;
+        let mut count = 0u32;
+        for JournalEntry { transaction, .. } in self.state.get_transactions_for_client(client_id)? {
+            if transaction.transaction_type() != tx_type || transaction.client_id() != &client_id {
+                continue;
+            }
+            let amount = match transaction.amount() {
+                Some(amount) => *amount,
+                None => match self.state.get_transaction(*transaction.id()) {
+                    Ok(referenced) => match referenced.amount() {
+                        Some(amount) => *amount,
+                        None => continue,
+                    },
+                    Err(ProcessingError::TransactionNotFound { .. }) => continue,
+                    Err(e) => return Err(e),
+                },
+            };
+            count += 1;
+            average += (amount - average) / Amount::from(count);
+        }
+        if count == 0 {
+            tracing::error!("Client {} has no {:?} transactions", client_id, tx_type);
+            return Err(ProcessingError::NoTransactionsOfType { client_id, tx_type });
+        }
+        Ok(average)
+    }
+
+    /// Replays the client's transactions in processing order against an empty state
+    /// and returns the balance after each applied transaction.
+    pub fn get_statement(&self, client_id: ClientId) -> ProcessingResult<Vec<StatementEntry>> {
+        let replay = TransactionProcessor::builder(
+            State::new()
+                .with_history_depth(0)
+                .with_max_account_versions(0),
+        )
+        .with_strict_lock(self.strict_lock)
+        .build();
+        let mut statement = Vec::new();
+        for JournalEntry { transaction, .. } in self.state.get_transactions_for_client(client_id)? {
+            let result = match &transaction {
+                StoredTransaction::Transfer {
+                    id,
+                    client_id: from,
+                    amount,
+                    ..
+                } if *from != client_id => {
+                    replay
+                        .get_unlocked_account(&client_id)
+                        .and_then(|mut account| {
+                            replay.state.insert_transaction(transaction.clone())?;
+                            replay.deposit(&mut account, amount)?;
+                            replay.state.upsert_account(account, *id)
+                        })
+                }
+                _ => replay.process(transaction.clone()),
+            };
+            if result.is_ok() {
+                let account = replay.state.get_account(&client_id)?;
+                statement.push(StatementEntry::new(&transaction, &account));
+            }
+        }
+        Ok(statement)
+    }
+
+    pub fn get_disputed_amount_by_client(&self, client_id: ClientId) -> ProcessingResult<Amount> {
+        let mut disputed = Amount::ZERO;
+        for JournalEntry { transaction, .. } in self.state.get_transactions_for_client(client_id)? {
+            if let StoredTransaction::Deposit { id, .. }
+            | StoredTransaction::Withdrawal { id, .. } = transaction
+            {
+                match self.state.get_transaction(id)? {
+                    StoredTransaction::Deposit {
+                        client_id: owner_id,
+                        amount,
+                        under_dispute: true,
+                        ..
+                    }
+                    | StoredTransaction::Withdrawal {
+                        client_id: owner_id,
+                        amount,
+                        under_dispute: true,
+                        ..
+                    } if owner_id == client_id => disputed += amount,
+                    _ => {}
+                }
+            }
+        }
+        Ok(disputed)
+    }
+
+    pub fn get_regulatory_report(&self, period: &DateRange) -> ProcessingResult<RegulatoryReport> {
+        let mut clients = BTreeSet::new();
+        let mut volumes = HashMap::<ClientId, Amount>::new();
+        let mut total_deposits = Amount::ZERO;
+        let mut total_withdrawals = Amount::ZERO;
+        let mut total_chargebacks = 0;
+        for entry in self.state.get_journal_entries_until(period.end)? {
+            if !period.contains(entry.created_at) {
+                continue;
+            }
+            let client_id = *entry.transaction.client_id();
+            clients.insert(client_id);
+            let amount = match &entry.transaction {
+                StoredTransaction::Deposit { amount, .. } => {
+                    total_deposits = checked_add(client_id, total_deposits, *amount)?;
+                    amount
+                }
+                StoredTransaction::Withdrawal { amount, .. } => {
+                    total_withdrawals = checked_add(client_id, total_withdrawals, *amount)?;
+                    amount
+                }
+                StoredTransaction::Transfer {
+                    to_client, amount, ..
+                } => {
+                    clients.insert(*to_client);
+                    amount
+                }
+                StoredTransaction::Chargeback { .. } => {
+                    total_chargebacks += 1;
+                    continue;
+                }
+                _ => continue,
+            };
+            let volume = volumes.entry(client_id).or_default();
+            *volume = checked_add(client_id, *volume, *amount)?;
+        }
+
+        let mut top_clients = volumes
+            .into_iter()
+            .map(|(client, volume)| ClientVolume { client, volume })
+            .collect::<Vec<_>>();
+        top_clients.sort_by(|a, b| b.volume.cmp(&a.volume).then(a.client.cmp(&b.client)));
+        top_clients.truncate(REGULATORY_REPORT_TOP_CLIENTS);
+        Ok(RegulatoryReport {
+            period_start: humantime::format_rfc3339(period.start).to_string(),
+            period_end: humantime::format_rfc3339(period.end).to_string(),
+            total_clients: clients.len(),
+            total_deposits,
+            total_withdrawals,
+            total_chargebacks,
+            total_locked_accounts: self
+                .get_accounts()?
+                .iter()
+                .filter(|account| account.locked)
+                .count(),
+            top_clients,
+        })
+    }
+
+    /// Aggregates the transactions in `period` for compliance. CSV reports have one
+    /// `metric,client,value` row per total and per top client.
+    #[cfg(feature = "serde")]
+    pub fn generate_regulatory_report(
+        &self,
+        period: DateRange,
+        format: ReportFormat,
+    ) -> ProcessingResult<String> {
+        let report = self.get_regulatory_report(&period)?;
+        match format {
+            ReportFormat::Json => serde_json::to_string_pretty(&report).map_err(report_error),
+            ReportFormat::Xml => quick_xml::se::to_string_with_root("regulatory_report", &report)
+                .map_err(report_error),
+            ReportFormat::Csv => {
+                let mut writer = WriterBuilder::new().from_writer(Vec::new());
+                let totals = [
+                    ("period_start", report.period_start),
+                    ("period_end", report.period_end),
+                    ("total_clients", report.total_clients.to_string()),
+                    ("total_deposits", report.total_deposits.to_string()),
+                    ("total_withdrawals", report.total_withdrawals.to_string()),
+                    ("total_chargebacks", report.total_chargebacks.to_string()),
+                    (
+                        "total_locked_accounts",
+                        report.total_locked_accounts.to_string(),
+                    ),
+                ];
+                writer
+                    .write_record(["metric", "client", "value"])
+                    .map_err(report_error)?;
+                for (metric, value) in totals {
+                    writer
+                        .write_record([metric, "", &value])
+                        .map_err(report_error)?;
+                }
+                for top_client in report.top_clients {
+                    writer
+                        .write_record([
+                            "client_volume",
+                            &top_client.client.to_string(),
+                            &top_client.volume.to_string(),
+                        ])
+                        .map_err(report_error)?;
+                }
+                let csv = writer.into_inner().map_err(report_error)?;
+                String::from_utf8(csv).map_err(report_error)
+            }
+        }
+    }
+
+    /// Net change of held funds caused by disputes in `[start, end]`: the disputed amount
+    /// is added when a dispute opens and subtracted when it is resolved or charged back.
+    /// A positive result means more funds were put on hold than released.
+    pub fn get_net_held_change_in_period(
+        &self,
+        start: SystemTime,
+        end: SystemTime,
+    ) -> ProcessingResult<Amount> {
+        let mut disputed = BTreeSet::new();
+        let mut change = Amount::ZERO;
+        for entry in self.state.get_journal_entries_until(end)? {
+            let (id, opens) = match &entry.transaction {
+                StoredTransaction::Dispute { id, .. } => (*id, true),
+                StoredTransaction::Resolve { id, .. }
+                | StoredTransaction::Chargeback { id, .. } => (*id, false),
+                _ => continue,
+            };
+            let client_id = *entry.transaction.client_id();
+            let amount = match self.state.get_transaction(id) {
+                Ok(StoredTransaction::Deposit {
+                    client_id: owner_id,
+                    amount,
+                    ..
+                })
+                | Ok(StoredTransaction::Withdrawal {
+                    client_id: owner_id,
+                    amount,
+                    ..
+                }) if owner_id == client_id => amount,
+                Ok(_) | Err(ProcessingError::TransactionNotFound { .. }) => continue,
+                Err(e) => return Err(e),
+            };
+            // Repeated disputes and resolves of a deposit that isn't disputed move no funds.
+            let changed = if opens {
+                disputed.insert(id)
+            } else {
+                disputed.remove(&id)
+            };
+            if !changed || entry.created_at < start {
+                continue;
+            }
+            change = if opens {
+                checked_add(client_id, change, amount)?
+            } else {
+                checked_sub(client_id, change, amount)?
+            };
+        }
+        Ok(change)
+    }
+
+    /// Checks that balances add up, are not negative, cover the disputed deposits and that
+    /// every stored transaction has an account, failing with the first violation found.
+    /// Rejected transactions aren't stored, so they never count as missing an account.
+    pub fn assert_state_invariants(&self) -> ProcessingResult<()> {
+        let violation = |reason: String| {
+            tracing::error!("State invariant violated: {}", reason);
+            Err(ProcessingError::InvariantViolation { reason })
+        };
+        for account in self.get_accounts()?.into_iter() {
+            if account.available + account.held != account.total {
+                return violation(format!(
+                    "client {} available {} plus held {} is not equal to total {}",
+                    account.client, account.available, account.held, account.total
+                ));
+            }
+            if account.total < Amount::ZERO {
+                return violation(format!(
+                    "client {} total {} is negative",
+                    account.client, account.total
+                ));
+            }
+            let disputed = self.get_disputed_amount_by_client(account.client)?;
+            if account.held < disputed {
+                return violation(format!(
+                    "client {} held {} does not cover disputed transactions of {}",
+                    account.client, account.held, disputed
+                ));
+            }
+        }
+        if let Some(orphan) = self.get_orphaned_transactions()?.first() {
+            return violation(format!(
+                "transaction {} references missing client {} account",
+                orphan.id(),
+                orphan.client_id()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn subscribe_to_account_updates(
+        &self,
+        client_id: ClientId,
+    ) -> ProcessingResult<watch::Receiver<Account>> {
+        self.state.subscribe_to_account_updates(client_id)
+    }
+
+    pub fn get_orphaned_transactions(&self) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.state.get_orphaned_transactions()
+    }
+
+    pub fn metrics(&self) -> ProcessingMetrics {
+        match self.metrics.lock() {
+            Ok(metrics) => metrics.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    fn update_metrics(&self, update: impl FnOnce(&mut ProcessingMetrics)) {
+        match self.metrics.lock() {
+            Ok(mut metrics) => update(&mut metrics),
+            Err(poisoned) => update(&mut poisoned.into_inner()),
+        }
+    }
+
+    fn get_account_for(&self, transaction: &StoredTransaction) -> ProcessingResult<Account> {
+        let account = self.state.get_account(transaction.client_id())?;
+        self.check_account_for(transaction, &account)?;
+        Ok(account)
+    }
+
+    fn check_account_for(
+        &self,
+        transaction: &StoredTransaction,
+        account: &Account,
+    ) -> ProcessingResult<()> {
+        check_open(account)?;
+        match transaction {
+            StoredTransaction::Deposit { .. } | StoredTransaction::Refund { .. }
+                if !self.strict_lock =>
+            {
+                Ok(())
+            }
+            _ => check_unlocked(account),
+        }
+    }
+
+    /// Runs after the accounts are stored, so it can't fail the transaction
+    fn run_post_process_hooks(&self, transaction: &StoredTransaction, updated: &[Account]) {
+        for account in updated {
+            for hook in &self.post_process_hooks {
+                hook(transaction, account);
+            }
+        }
+    }
+
+    /// Disputes of other clients' transactions are ignored, so they must not flag them
+    fn set_under_dispute(
+        &self,
+        id: TransactionId,
+        client_id: ClientId,
+        under_dispute: bool,
+    ) -> ProcessingResult<()> {
+        match self.state.get_transaction_for_client(id, client_id) {
+            Ok(_) => self.state.under_dispute(id, under_dispute),
+            Err(ProcessingError::TransactionNotFound { .. }) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn check_open_disputes(&self, client_id: ClientId, pending: u32) -> ProcessingResult<()> {
+        if let Some(max) = self.max_open_disputes {
+            if self.state.count_open_disputes(client_id)? + pending >= max {
+                tracing::error!("Client {} has too many open disputes", client_id);
+                return Err(ProcessingError::TooManyOpenDisputes { client_id });
+            }
+        }
+        Ok(())
+    }
+
+    fn check_velocity(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        if let Some((max_count, window)) = self.velocity_limit {
+            let client_id = *transaction.client_id();
+            let since = SystemTime::now()
+                .checked_sub(window)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let mut recent_transactions =
+                self.recent_transactions
+                    .lock()
+                    .map_err(|e| ProcessingError::StoragePoisoned {
+                        context: format!("recent_transactions: {}", e),
+                    })?;
+            let stored_at = recent_transactions.entry(client_id).or_default();
+            while let Some(oldest) = stored_at.front() {
+                if *oldest > since {
+                    break;
+                }
+                stored_at.pop_front();
+            }
+            if stored_at.len() >= max_count {
+                tracing::error!("Client {} exceeded the velocity limit", client_id);
+                return Err(ProcessingError::VelocityLimitExceeded { client_id });
+            }
+        }
+        Ok(())
     }
 
+    /// Counts the stored transaction towards the velocity limit of its client, and of the
+    /// receiving client of a transfer.
+    fn record_recent(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        if self.velocity_limit.is_none() {
+            return Ok(());
+        }
+        let now = SystemTime::now();
+        let mut recent_transactions =
+            self.recent_transactions
+                .lock()
+                .map_err(|e| ProcessingError::StoragePoisoned {
+                    context: format!("recent_transactions: {}", e),
+                })?;
+        recent_transactions
+            .entry(*transaction.client_id())
+            .or_default()
+            .push_back(now);
+        if let StoredTransaction::Transfer { to_client, .. } = transaction {
+            recent_transactions
+                .entry(*to_client)
+                .or_default()
+                .push_back(now);
+        }
+        Ok(())
+    }
+
+    /// Removes a transaction that was stored but then rejected, so that it can't be disputed
+    /// and isn't reported as an orphan when its client has no account. The journal keeps it.
+    fn forget_rejected<T>(
+        &self,
+        transaction: &StoredTransaction,
+        error: ProcessingError,
+    ) -> ProcessingResult<T> {
+        if transaction.is_stored() {
+            self.state.remove_transaction(*transaction.id())?;
+        }
+        Err(error)
+    }
+
+    /// Disputes, resolves, chargebacks and regulatory releases reference an existing
+    /// transaction by its id, so only transactions that are stored are checked.
+    fn check_not_duplicate(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        match transaction {
+            StoredTransaction::Deposit { id, .. }
+            | StoredTransaction::Withdrawal { id, .. }
+            | StoredTransaction::Transfer { id, .. }
+            | StoredTransaction::RegulatoryHold { id, .. }
+            | StoredTransaction::Refund { id, .. } => {
+                if self.state.contains_transaction(*id)? {
+                    tracing::error!("Duplicate transaction id {}", id);
+                    return Err(ProcessingError::TransactionAlreadyExists { id: *id });
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn get_unlocked_account(&self, client_id: &ClientId) -> ProcessingResult<Account> {
+        let account = self.state.get_account(client_id)?;
+        check_open(&account)?;
+        check_unlocked(&account)?;
+        Ok(account)
+    }
+
+    fn close(&self, client_id: ClientId, force: bool) -> ProcessingResult<ClosedAccountSummary> {
+        let mut account = self.state.get_account(&client_id)?;
+        check_open(&account)?;
+        if !force {
+            check_unlocked(&account)?;
+        }
+        if self.get_disputed_amount_by_client(client_id)? > Amount::ZERO {
+            tracing::error!("Account has open disputes: {}", account);
+            return Err(ProcessingError::AccountHasOpenDisputes { client_id });
+        }
+        if account.held > Amount::ZERO {
+            tracing::error!("Account has held funds: {}", account);
+            return Err(ProcessingError::AccountHasHeldFunds { client_id });
+        }
+        let final_balance = account.available;
+        account.available = Amount::ZERO;
+        account.total = Amount::ZERO;
+        account.closed = true;
+        let mut accounts = vec![account];
+        if final_balance > Amount::ZERO {
+            if self.closing_fee_destination == client_id {
+                tracing::error!("Closing fee destination is the closed account");
+                return Err(ProcessingError::InvalidClosingFeeDestination { client_id });
+            }
+            let mut destination = self.get_unlocked_account(&self.closing_fee_destination)?;
+            self.deposit(&mut destination, &final_balance)?;
+            accounts.push(destination);
+        }
+        self.state.upsert_accounts(accounts, ACCOUNT_CLOSURE)?;
+        Ok(ClosedAccountSummary {
+            client_id,
+            final_balance,
+            closed_at: SystemTime::now(),
+        })
+    }
+
+    /// Applies `update` to the transaction's account and stores it, returning the updated
+    /// accounts. Transfers update both accounts in one `StateStorage::update_accounts` call;
+    /// everything else goes through a single `StateStorage::update_account` call, which also
+    /// records the fee of a deposit.
+    fn update_account(
+        &self,
+        transaction: &StoredTransaction,
+        mut update: impl FnMut(&mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<Vec<Account>> {
+        let mut updated = Vec::new();
+        if let StoredTransaction::Transfer {
+            to_client, amount, ..
+        } = transaction
+        {
+            self.state.update_accounts(
+                *transaction.client_id(),
+                *to_client,
+                *transaction.id(),
+                &mut |account, destination| {
+                    self.check_account_for(transaction, account)?;
+                    update(account)?;
+                    check_open(destination)?;
+                    check_unlocked(destination)?;
+                    self.deposit(destination, amount)?;
+                    updated = vec![account.clone(), destination.clone()];
+                    Ok(())
+                },
+            )?;
+        } else {
+            self.state.update_account(
+                *transaction.client_id(),
+                *transaction.id(),
+                &mut |account| {
+                    self.check_account_for(transaction, account)?;
+                    update(account)?;
+                    self.record_fee(transaction)?;
+                    updated = vec![account.clone()];
+                    Ok(())
+                },
+            )?;
+        }
+        match transaction {
+            StoredTransaction::Dispute { id, client_id } => {
+                self.set_under_dispute(*id, *client_id, true)
+            }
+            StoredTransaction::Resolve { id, client_id }
+            | StoredTransaction::Chargeback { id, client_id } => {
+                self.set_under_dispute(*id, *client_id, false)
+            }
+            StoredTransaction::RegulatoryRelease { id, .. } => self.state.release_hold(*id),
+            StoredTransaction::KycApproved { client_id, .. } => self.state.approve_kyc(*client_id),
+            _ => Ok(()),
+        }
+        .map(|()| updated)
+    }
+
+    fn deposit_fee(
+        &self,
+        id: TransactionId,
+        client_id: ClientId,
+        amount: &Amount,
+    ) -> ProcessingResult<Amount> {
+        let fee = match &self.fee_schedule {
+            Some(fee_schedule) => fee_schedule.fee_for(amount).ok_or_else(|| {
+                tracing::error!("Fee of deposit {} overflows", id);
+                ProcessingError::AmountOverflow { client_id }
+            })?,
+            None => return Ok(Amount::ZERO),
+        };
+        if *amount < fee {
+            tracing::error!("Deposit is less than its fee of {}", fee);
+            return Err(ProcessingError::DepositLessThanFee { id, fee });
+        }
+        Ok(fee)
+    }
+
+    fn record_fee(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        let (id, client_id, amount) = match transaction {
+            StoredTransaction::Deposit {
+                id,
+                client_id,
+                amount,
+                ..
+            } => (*id, *client_id, amount),
+            _ => return Ok(()),
+        };
+        let fee = self.deposit_fee(id, client_id, amount)?;
+        if fee == Amount::ZERO {
+            return Ok(());
+        }
+        let fee = StoredTransaction::Fee {
+            id,
+            client_id,
+            amount: fee,
+        };
+        self.state.insert_transaction(fee.clone())?;
+        self.record_recent(&fee)?;
+        self.update_metrics(|metrics| metrics.record_processed(&fee));
+        Ok(())
+    }
+
+    /// Returns whether the transaction was applied: disputes of unknown transactions and
+    /// settlements of undisputed ones are ignored.
     fn adjust_account(
         &self,
         account: &mut Account,
         transaction: &StoredTransaction,
-    ) -> ProcessingResult<()> {
+    ) -> ProcessingResult<bool> {
         match transaction {
-            StoredTransaction::Deposit { amount, .. } => self.deposit(account, amount),
+            StoredTransaction::Deposit {
+                id,
+                amount,
+                requires_kyc,
+                ..
+            } => {
+                let fee = self.deposit_fee(*id, account.client, amount)?;
+                let amount = checked_sub(account.client, *amount, fee)?;
+                if *requires_kyc {
+                    self.deposit_pending_kyc(account, &amount)
+                } else {
+                    self.deposit(account, &amount)
+                }
+            }
             StoredTransaction::Withdrawal { amount, .. } => self.withdraw(account, amount),
-            StoredTransaction::Dispute { id, .. } => self.dispute(account, id),
-            StoredTransaction::Resolve { id, .. } => self.resolve(account, id),
-            StoredTransaction::Chargeback { id, .. } => self.chargeback(account, id),
-        }
+            StoredTransaction::Transfer { amount, .. } => self.withdraw(account, amount),
+            StoredTransaction::Dispute { id, .. } => return self.dispute(account, id, 0),
+            StoredTransaction::Resolve { id, .. } => return self.resolve(account, id),
+            StoredTransaction::Chargeback { id, .. } => return self.chargeback(account, id),
+            StoredTransaction::RegulatoryHold { amount, .. } => {
+                self.regulatory_hold(account, amount)
+            }
+            StoredTransaction::RegulatoryRelease { id, .. } => self.regulatory_release(account, id),
+            StoredTransaction::KycApproved { .. } => self.approve_kyc(account),
+            StoredTransaction::Refund { amount, .. } => self.deposit(account, amount),
+            StoredTransaction::Fee { amount, .. } => self.charge_fee(account, amount),
+        }?;
+        Ok(true)
     }
 
     fn deposit(&self, account: &mut Account, amount: &Decimal) -> ProcessingResult<()> {
-        account.available += amount;
-        account.total += amount;
+        account.available = checked_add(account.client, account.available, *amount)?;
+        account.total = checked_add(account.client, account.total, *amount)?;
+        Ok(())
+    }
+
+    fn deposit_pending_kyc(&self, account: &mut Account, amount: &Decimal) -> ProcessingResult<()> {
+        account.held = checked_add(account.client, account.held, *amount)?;
+        account.total = checked_add(account.client, account.total, *amount)?;
+        account.kyc_pending = checked_add(account.client, account.kyc_pending, *amount)?;
+        Ok(())
+    }
+
+    fn approve_kyc(&self, account: &mut Account) -> ProcessingResult<()> {
+        account.held = checked_sub(account.client, account.held, account.kyc_pending)?;
+        account.available = checked_add(account.client, account.available, account.kyc_pending)?;
+        account.kyc_pending = Amount::ZERO;
         Ok(())
     }
 
     fn withdraw(&self, account: &mut Account, amount: &Decimal) -> ProcessingResult<()> {
-        if account.available < amount.clone() {
+        if account.kyc_pending > Amount::ZERO {
+            tracing::error!("Client has deposits pending KYC verification");
+            return Err(ProcessingError::KycRequired {
+                client_id: account.client,
+            });
+        }
+        if account.available < *amount {
+            tracing::error!("Insufficient available funds in client's account");
+            return Err(ProcessingError::AccountInsufficientAvailableFunds {
+                client_id: account.client,
+            });
+        }
+        account.available = checked_sub(account.client, account.available, *amount)?;
+        account.total = checked_sub(account.client, account.total, *amount)?;
+        Ok(())
+    }
+
+    fn charge_fee(&self, account: &mut Account, amount: &Decimal) -> ProcessingResult<()> {
+        if account.available < *amount {
             tracing::error!("Insufficient available funds in client's account");
             return Err(ProcessingError::AccountInsufficientAvailableFunds {
-                client_id: account.client.clone(),
+                client_id: account.client,
             });
         }
-        account.available -= amount;
-        account.total -= amount;
+        account.available = checked_sub(account.client, account.available, *amount)?;
+        account.total = checked_sub(account.client, account.total, *amount)?;
         Ok(())
     }
 
-    fn dispute(&self, account: &mut Account, id: &TransactionId) -> ProcessingResult<()> {
-        let tx = self.state.get_transaction(id.clone());
+    /// `pending` disputes of the client were already applied to `account` but aren't
+    /// stored yet, as in a batch dispute.
+    fn dispute(
+        &self,
+        account: &mut Account,
+        id: &TransactionId,
+        pending: u32,
+    ) -> ProcessingResult<bool> {
+        let tx = self.state.get_transaction_for_client(*id, account.client);
         match tx {
             Ok(tx) => {
                 if let StoredTransaction::Deposit {
@@ -87,43 +1726,60 @@ impl<S: StateStorage> TransactionProcessor<S> {
                     client_id,
                     amount,
                     under_dispute,
+                    requires_kyc: false,
+                    ..
                 } = tx
                 {
-                    if account.client != client_id {
-                        tracing::error!("Transaction can't be accessed by client");
-                        return Err(ProcessingError::TransactionAccessDenied { id, client_id });
-                    }
                     if under_dispute {
                         tracing::error!("Transaction already under dispute");
                         return Err(ProcessingError::TransactionAlreadyUnderDispute { id });
                     }
+                    self.check_open_disputes(account.client, pending)?;
                     if account.available < amount {
                         tracing::error!("Insufficient available funds in client's account");
                         return Err(ProcessingError::AccountInsufficientAvailableFunds {
                             client_id,
                         });
                     }
-                    account.available -= amount;
-                    account.held += amount;
-                    self.state.under_dispute(id, true)?;
-                    Ok(())
+                    account.available = checked_sub(account.client, account.available, amount)?;
+                    account.held = checked_add(account.client, account.held, amount)?;
+                    Ok(true)
+                } else if let StoredTransaction::Withdrawal {
+                    id,
+                    amount,
+                    under_dispute,
+                    ..
+                } = tx
+                {
+                    if under_dispute {
+                        tracing::error!("Transaction already under dispute");
+                        return Err(ProcessingError::TransactionAlreadyUnderDispute { id });
+                    }
+                    self.check_open_disputes(account.client, pending)?;
+                    // The withdrawn funds are gone, the provisional reversal is held until the
+                    // dispute is settled.
+                    account.held = checked_add(account.client, account.held, amount)?;
+                    account.total = checked_add(account.client, account.total, amount)?;
+                    Ok(true)
                 } else {
-                    tracing::error!("Transaction {} is not a deposit", tx.id());
-                    Err(ProcessingError::TransactionIsNotDisputable {
-                        id: tx.id().clone(),
-                    })
+                    tracing::error!("Transaction {} is not a deposit or withdrawal", tx.id());
+                    Err(ProcessingError::TransactionIsNotDisputable { id: *tx.id() })
                 }
             }
             Err(ProcessingError::TransactionNotFound { id }) => {
-                tracing::info!("Ignoring dispute for non existing transaction {}.", id);
-                Ok(())
+                tracing::warn!(
+                    "Ignoring dispute for transaction {} not seen for client {}.",
+                    id,
+                    account.client
+                );
+                Ok(false)
             }
-            Err(e) => Err(ProcessingError::UnknownError(e.to_string())),
+            Err(e) => Err(e),
         }
     }
 
-    fn resolve(&self, account: &mut Account, id: &TransactionId) -> ProcessingResult<()> {
-        let tx = self.state.get_transaction(id.clone());
+    fn resolve(&self, account: &mut Account, id: &TransactionId) -> ProcessingResult<bool> {
+        let tx = self.state.get_transaction_for_client(*id, account.client);
         match tx {
             Ok(tx) => {
                 if let StoredTransaction::Deposit {
@@ -131,57 +1787,78 @@ impl<S: StateStorage> TransactionProcessor<S> {
                     client_id,
                     amount,
                     under_dispute,
+                    requires_kyc: false,
+                    ..
                 } = tx
                 {
-                    if account.client != client_id {
-                        tracing::error!("Transaction can't be accessed by client");
-                        return Err(ProcessingError::TransactionAccessDenied { id, client_id });
+                    if !under_dispute {
+                        tracing::error!("Transaction is not under dispute");
+                        if self.lenient_resolve {
+                            return Ok(false);
+                        }
+                        return Err(ProcessingError::TransactionNotUnderDispute { id });
+                    }
+                    if account.held < amount {
+                        tracing::error!("Insufficient held funds in client's account");
+                        return Err(ProcessingError::AccountInsufficientHeldFunds { client_id });
                     }
+                    account.available = checked_add(account.client, account.available, amount)?;
+                    account.held = checked_sub(account.client, account.held, amount)?;
+                    Ok(true)
+                } else if let StoredTransaction::Withdrawal {
+                    id,
+                    client_id,
+                    amount,
+                    under_dispute,
+                    ..
+                } = tx
+                {
                     if !under_dispute {
                         tracing::error!("Transaction is not under dispute");
-                        return Ok(());
+                        if self.lenient_resolve {
+                            return Ok(false);
+                        }
+                        return Err(ProcessingError::TransactionNotUnderDispute { id });
                     }
                     if account.held < amount {
                         tracing::error!("Insufficient held funds in client's account");
                         return Err(ProcessingError::AccountInsufficientHeldFunds { client_id });
                     }
-                    account.available += amount;
-                    account.held -= amount;
-                    self.state.under_dispute(id, false)?;
-                    Ok(())
+                    account.held = checked_sub(account.client, account.held, amount)?;
+                    account.total = checked_sub(account.client, account.total, amount)?;
+                    Ok(true)
                 } else {
-                    tracing::error!("Transaction {} is not a deposit", tx.id());
-                    Err(ProcessingError::TransactionIsNotDisputable {
-                        id: tx.id().clone(),
-                    })
+                    tracing::error!("Transaction {} is not a deposit or withdrawal", tx.id());
+                    Err(ProcessingError::TransactionIsNotDisputable { id: *tx.id() })
                 }
             }
             Err(ProcessingError::TransactionNotFound { id }) => {
-                tracing::info!("Ignoring dispute for non existing transaction {}.", id);
-                Ok(())
+                tracing::warn!(
+                    "Ignoring dispute for transaction {} not seen for client {}.",
+                    id,
+                    account.client
+                );
+                Ok(false)
             }
-            Err(e) => Err(ProcessingError::UnknownError(e.to_string())),
+            Err(e) => Err(e),
         }
     }
 
-    fn chargeback(&self, account: &mut Account, id: &TransactionId) -> ProcessingResult<()> {
-        let tx = self.state.get_transaction(id.clone());
+    fn chargeback(&self, account: &mut Account, id: &TransactionId) -> ProcessingResult<bool> {
+        let tx = self.state.get_transaction_for_client(*id, account.client);
         match tx {
             Ok(tx) => {
                 if let StoredTransaction::Deposit {
-                    id,
                     client_id,
                     amount,
                     under_dispute,
+                    requires_kyc: false,
+                    ..
                 } = tx
                 {
-                    if account.client != client_id {
-                        tracing::error!("Transaction can't be accessed by client");
-                        return Err(ProcessingError::TransactionAccessDenied { id, client_id });
-                    }
                     if !under_dispute {
                         tracing::error!("Transaction is not under dispute");
-                        return Ok(());
+                        return Ok(false);
                     }
                     if account.held < amount {
                         tracing::error!("Insufficient held funds in client's account");
@@ -189,23 +1866,987 @@ impl<S: StateStorage> TransactionProcessor<S> {
                             client_id,
                         });
                     }
-                    account.held -= amount;
-                    account.total -= amount;
+                    account.held = checked_sub(account.client, account.held, amount)?;
+                    account.total = checked_sub(account.client, account.total, amount)?;
                     account.locked = true;
-                    self.state.under_dispute(id, false)?;
-                    Ok(())
+                    account.chargeback_count += 1;
+                    Ok(true)
+                } else if let StoredTransaction::Withdrawal {
+                    client_id,
+                    amount,
+                    under_dispute,
+                    ..
+                } = tx
+                {
+                    if !under_dispute {
+                        tracing::error!("Transaction is not under dispute");
+                        return Ok(false);
+                    }
+                    if account.held < amount {
+                        tracing::error!("Insufficient held funds in client's account");
+                        return Err(ProcessingError::AccountInsufficientHeldFunds { client_id });
+                    }
+                    // The withdrawal is reversed, the held reversal becomes available.
+                    account.held = checked_sub(account.client, account.held, amount)?;
+                    account.available = checked_add(account.client, account.available, amount)?;
+                    account.locked = true;
+                    account.chargeback_count += 1;
+                    Ok(true)
                 } else {
-                    tracing::error!("Transaction {} is not a deposit", tx.id());
-                    Err(ProcessingError::TransactionIsNotDisputable {
-                        id: tx.id().clone(),
-                    })
+                    tracing::error!("Transaction {} is not a deposit or withdrawal", tx.id());
+                    Err(ProcessingError::TransactionIsNotDisputable { id: *tx.id() })
                 }
             }
             Err(ProcessingError::TransactionNotFound { id }) => {
-                tracing::info!("Ignoring dispute for non existing transaction {}.", id);
-                Ok(())
+                tracing::warn!(
+                    "Ignoring dispute for transaction {} not seen for client {}.",
+                    id,
+                    account.client
+                );
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn regulatory_hold(&self, account: &mut Account, amount: &Decimal) -> ProcessingResult<()> {
+        if account.available < *amount {
+            tracing::error!("Insufficient available funds in client's account for regulatory hold");
+            return Err(ProcessingError::InsufficientAvailableForRegulatoryHold {
+                client_id: account.client,
+            });
+        }
+        account.available = checked_sub(account.client, account.available, *amount)?;
+        account.held = checked_add(account.client, account.held, *amount)?;
+        Ok(())
+    }
+
+    fn regulatory_release(
+        &self,
+        account: &mut Account,
+        id: &TransactionId,
+    ) -> ProcessingResult<()> {
+        let tx = self.state.get_transaction(*id)?;
+        if let StoredTransaction::RegulatoryHold {
+            id,
+            client_id,
+            amount,
+            released,
+            ..
+        } = tx
+        {
+            if account.client != client_id {
+                tracing::error!("Transaction can't be accessed by client");
+                return Err(ProcessingError::TransactionAccessDenied {
+                    id,
+                    client_id: account.client,
+                    owner_id: client_id,
+                });
+            }
+            if released {
+                tracing::error!("Regulatory hold already released");
+                return Err(ProcessingError::TransactionIsNotRegulatoryHold { id });
+            }
+            if account.held < amount {
+                tracing::error!("Insufficient held funds in client's account");
+                return Err(ProcessingError::AccountInsufficientHeldFunds { client_id });
+            }
+            account.held = checked_sub(account.client, account.held, amount)?;
+            account.available = checked_add(account.client, account.available, amount)?;
+            Ok(())
+        } else {
+            tracing::error!("Transaction {} is not a regulatory hold", tx.id());
+            Err(ProcessingError::TransactionIsNotRegulatoryHold { id: *tx.id() })
+        }
+    }
+}
+
+impl<S: StateStorage + Clone> TransactionProcessor<S> {
+    /// Processes `transactions` against a copy of the current state with the same checks as
+    /// `process_transaction`, so later transactions see the effects of earlier ones, while
+    /// this processor's state, metrics and post-process hooks are left untouched.
+    pub fn dry_run(&self, transactions: impl IntoIterator<Item = Transaction>) -> DryRunReport {
+        let scratch = TransactionProcessor {
+            post_process_hooks: Vec::new(),
+            ..self.clone()
+        };
+        let mut report = DryRunReport::default();
+        for transaction in transactions {
+            match scratch.process_transaction(transaction.clone()) {
+                Ok(()) => report.succeeded += 1,
+                Err(e) => report.rejected.push((transaction, e)),
+            }
+        }
+        report
+    }
+}
+
+#[cfg(feature = "serde")]
+fn report_error(error: impl std::error::Error + Send + Sync + 'static) -> ProcessingError {
+    tracing::error!("Failed to format regulatory report: {}", error);
+    ProcessingError::InternalError {
+        source: ErrorSource::new(error),
+    }
+}
+
+fn check_not_reserved(transaction: &StoredTransaction) -> ProcessingResult<()> {
+    let to_client = match transaction {
+        StoredTransaction::Transfer { to_client, .. } => Some(*to_client),
+        _ => None,
+    };
+    for id in [Some(*transaction.client_id()), to_client]
+        .into_iter()
+        .flatten()
+    {
+        if id == RESERVED_CLIENT_ID {
+            tracing::error!("Transaction uses the reserved client id: {:?}", transaction);
+            return Err(ProcessingError::ReservedClientId { id });
+        }
+    }
+    Ok(())
+}
+
+fn check_open(account: &Account) -> ProcessingResult<()> {
+    if account.closed {
+        tracing::error!("Account is closed: {}", account);
+        return Err(ProcessingError::AccountIsClosed {
+            client_id: account.client,
+        });
+    }
+    Ok(())
+}
+
+fn check_unlocked(account: &Account) -> ProcessingResult<()> {
+    if account.locked {
+        tracing::error!("Account is locked: {}", account);
+        return Err(ProcessingError::AccountIsLocked {
+            client_id: account.client,
+        });
+    }
+    Ok(())
+}
+
+fn checked_add(client_id: ClientId, lhs: Amount, rhs: Amount) -> ProcessingResult<Amount> {
+    lhs.checked_add(rhs).ok_or_else(|| {
+        tracing::error!("Amount overflow in client's account");
+        ProcessingError::AmountOverflow { client_id }
+    })
+}
+
+fn checked_sub(client_id: ClientId, lhs: Amount, rhs: Amount) -> ProcessingResult<Amount> {
+    lhs.checked_sub(rhs).ok_or_else(|| {
+        tracing::error!("Amount overflow in client's account");
+        ProcessingError::AmountOverflow { client_id }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::testing::{MockStateStorage, StorageCall};
+
+    fn transaction(
+        transaction_type: TransactionType,
+        client_id: ClientId,
+        id: TransactionId,
+        amount: Option<Amount>,
+    ) -> StoredTransaction {
+        StoredTransaction::from((transaction_type, client_id, id, amount))
+    }
+
+    #[test]
+    fn rejected_transaction_of_unknown_client_keeps_invariants() {
+        let processor = TransactionProcessor::new(State::new());
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(1)));
+        let withdrawal = transaction(TransactionType::Withdrawal, 2, 2, Some(dec!(5)));
+
+        assert_eq!(processor.process(deposit), Ok(()));
+        assert!(processor.process(withdrawal).is_err());
+        assert_eq!(processor.get_orphaned_transactions(), Ok(Vec::new()));
+        assert_eq!(processor.assert_state_invariants(), Ok(()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn process_reader_counts_succeeded_malformed_and_rejected_records() {
+        let processor = TransactionProcessor::new(State::new());
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,2.0\n\
+                     withdrawal,1,2,5.0\n\
+                     bogus,1,3,1.0\n\
+                     deposit,2,4,1.0\n";
+        let mut reader = csv::Reader::from_reader(input.as_bytes());
+
+        let stats = processor.process_reader(&mut reader).expect("stats");
+
+        assert_eq!(stats.attempted, 4);
+        assert_eq!(stats.succeeded, 2);
+        assert_eq!(stats.failed, 2);
+        assert_eq!(stats.malformed, 1);
+        assert_eq!(stats.rejected.len(), 1);
+        assert_eq!(stats.rejected[0].0.tx, 2);
+        assert_eq!(
+            stats.rejected[0].1,
+            ProcessingError::AccountInsufficientAvailableFunds { client_id: 1 }
+        );
+        assert_eq!(processor.account_count(), Ok(2));
+    }
+
+    #[test]
+    fn ignored_disputes_and_settlements_are_not_counted() {
+        let processor = TransactionProcessor::new(State::new());
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(5)));
+        let unknown_dispute = transaction(TransactionType::Dispute, 1, 9, None);
+        let undisputed_chargeback = transaction(TransactionType::Chargeback, 1, 1, None);
+        let dispute = transaction(TransactionType::Dispute, 1, 1, None);
+
+        assert_eq!(processor.process(deposit), Ok(()));
+        assert_eq!(processor.process(unknown_dispute), Ok(()));
+        assert_eq!(processor.process(undisputed_chargeback), Ok(()));
+        assert_eq!(processor.metrics().disputes_opened, 0);
+        assert_eq!(processor.metrics().chargebacks_applied, 0);
+
+        assert_eq!(processor.process(dispute), Ok(()));
+        assert_eq!(processor.metrics().disputes_opened, 1);
+    }
+
+    #[test]
+    fn batch_dispute_counts_only_applied_disputes() {
+        let processor = TransactionProcessor::new(State::new());
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(5)));
+        assert_eq!(processor.process(deposit), Ok(()));
+
+        let results = processor.batch_dispute(&[1, 9], 1);
+
+        assert_eq!(results, vec![Ok(()), Ok(())]);
+        assert_eq!(processor.metrics().disputes_opened, 1);
+        assert_eq!(processor.metrics().disputes_rejected, 0);
+    }
+
+    #[test]
+    fn deposit_fee_is_recorded_before_the_account_is_stored() {
+        let fee_schedule = FeeSchedule {
+            flat: dec!(1),
+            percentage: dec!(0),
+        };
+        let processor = TransactionProcessor::builder(MockStateStorage::new())
+            .with_fee_schedule(fee_schedule)
+            .build();
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(10)));
+        let below_fee = transaction(TransactionType::Deposit, 1, 2, Some(dec!(0.5)));
+
+        assert_eq!(processor.process(deposit.clone()), Ok(()));
+        assert!(processor.process(below_fee).is_err());
+
+        let writes = processor.state.calls_matching(|call| {
+            matches!(
+                call,
+                StorageCall::InsertTransaction { .. } | StorageCall::UpsertAccount { .. }
+            )
+        });
+        let fee = StoredTransaction::Fee {
+            id: 1,
+            client_id: 1,
+            amount: dec!(1),
+        };
+        assert!(matches!(
+            writes.as_slice(),
+            [
+                StorageCall::InsertTransaction { transaction: first },
+                StorageCall::InsertTransaction { transaction: second },
+                StorageCall::UpsertAccount { account, .. },
+                StorageCall::InsertTransaction { .. },
+            ] if *first == deposit && *second == fee && account.available == dec!(9)
+        ));
+    }
+
+    #[test]
+    fn velocity_limit_counts_stored_transactions_of_both_transfer_clients() {
+        let processor = TransactionProcessor::builder(State::new())
+            .with_velocity_limit(2, Duration::from_secs(60))
+            .build();
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(5)));
+        let transfer = StoredTransaction::Transfer {
+            id: 2,
+            client_id: 1,
+            to_client: 2,
+            amount: dec!(1),
+        };
+        let second_deposit = transaction(TransactionType::Deposit, 2, 3, Some(dec!(1)));
+        let third_deposit = transaction(TransactionType::Deposit, 2, 4, Some(dec!(1)));
+        let other_client = transaction(TransactionType::Deposit, 3, 5, Some(dec!(1)));
+
+        assert_eq!(processor.process(deposit), Ok(()));
+        assert_eq!(processor.process(transfer), Ok(()));
+        assert_eq!(processor.process(second_deposit), Ok(()));
+        assert_eq!(
+            processor.process(third_deposit),
+            Err(ProcessingError::VelocityLimitExceeded { client_id: 2 })
+        );
+        assert_eq!(processor.process(other_client), Ok(()));
+    }
+
+    #[test]
+    fn stream_process_yields_results_in_order() {
+        use futures::FutureExt;
+
+        let processor = TransactionProcessor::new(State::new());
+        let transactions = (1..=1000).map(|id| {
+            if id % 10 == 0 {
+                transaction(TransactionType::Withdrawal, 1, id, Some(dec!(1000)))
+            } else {
+                transaction(TransactionType::Deposit, 1, id, Some(dec!(1)))
+            }
+        });
+
+        let results = processor
+            .stream_process(futures::stream::iter(transactions))
+            .collect::<Vec<_>>()
+            .now_or_never()
+            .expect("an iterator stream is always ready");
+
+        assert_eq!(results.len(), 1000);
+        for (index, result) in results.iter().enumerate() {
+            if (index + 1) % 10 == 0 {
+                assert_eq!(
+                    result,
+                    &Err(ProcessingError::AccountInsufficientAvailableFunds { client_id: 1 })
+                );
+            } else {
+                assert_eq!(result, &Ok(()));
+            }
+        }
+        assert_eq!(
+            processor.state.get_account(&1).map(|account| account.total),
+            Ok(dec!(900))
+        );
+    }
+
+    #[test]
+    fn disputes_of_another_clients_transaction_are_ignored() {
+        let processor = TransactionProcessor::new(State::new());
+        let victim_deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(3)));
+        let attacker_deposit = transaction(TransactionType::Deposit, 2, 2, Some(dec!(1)));
+        assert_eq!(processor.process(victim_deposit), Ok(()));
+        assert_eq!(processor.process(attacker_deposit), Ok(()));
+
+        for transaction_type in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            for attacker in [2, 3] {
+                let attack = transaction(transaction_type, attacker, 1, None);
+                assert_eq!(processor.process(attack), Ok(()));
             }
-            Err(e) => Err(ProcessingError::UnknownError(e.to_string())),
         }
+
+        let victim = processor.state.get_account(&1).expect("victim account");
+        assert_eq!(
+            (victim.available, victim.held, victim.locked),
+            (dec!(3), dec!(0), false)
+        );
+        let attacker = processor.state.get_account(&2).expect("attacker account");
+        assert_eq!(
+            (attacker.available, attacker.held, attacker.locked),
+            (dec!(1), dec!(0), false)
+        );
+        assert_eq!(processor.metrics().disputes_opened, 0);
+
+        let dispute = transaction(TransactionType::Dispute, 1, 1, None);
+        assert_eq!(processor.process(dispute), Ok(()));
+        assert_eq!(
+            processor.state.get_account(&1).map(|account| account.held),
+            Ok(dec!(3))
+        );
+    }
+
+    fn held_by_regulator() -> TransactionProcessor<State> {
+        let processor = TransactionProcessor::new(State::new());
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(3)));
+        assert_eq!(processor.process(deposit), Ok(()));
+        let hold = processor.apply_regulatory_hold(
+            2,
+            1,
+            dec!(2),
+            "sanctions screening".to_string(),
+            "regulator".to_string(),
+        );
+        assert_eq!(hold, Ok(()));
+        processor
+    }
+
+    #[test]
+    fn release_of_another_clients_regulatory_hold_is_denied() {
+        let processor = held_by_regulator();
+
+        let release = StoredTransaction::RegulatoryRelease {
+            id: 2,
+            client_id: 4,
+        };
+
+        assert_eq!(
+            processor.process(release),
+            Err(ProcessingError::TransactionAccessDenied {
+                id: 2,
+                client_id: 4,
+                owner_id: 1,
+            })
+        );
+        assert_eq!(
+            processor.state.get_account(&1).map(|account| account.held),
+            Ok(dec!(2))
+        );
+    }
+
+    #[test]
+    fn regulatory_hold_moves_available_to_held() {
+        let processor = held_by_regulator();
+
+        assert_eq!(balances(&processor, 1), (dec!(1), dec!(2), dec!(3), false));
+    }
+
+    #[test]
+    fn regulatory_release_moves_held_to_available_once() {
+        let processor = held_by_regulator();
+        let release = StoredTransaction::RegulatoryRelease {
+            id: 2,
+            client_id: 1,
+        };
+
+        assert_eq!(processor.process(release.clone()), Ok(()));
+        assert_eq!(balances(&processor, 1), (dec!(3), dec!(0), dec!(3), false));
+        assert_eq!(
+            processor.process(release),
+            Err(ProcessingError::TransactionIsNotRegulatoryHold { id: 2 })
+        );
+        assert_eq!(balances(&processor, 1), (dec!(3), dec!(0), dec!(3), false));
+    }
+
+    #[test]
+    fn regulatory_hold_beyond_available_funds_is_rejected() {
+        let processor = held_by_regulator();
+
+        let hold = processor.apply_regulatory_hold(
+            3,
+            1,
+            dec!(2),
+            "sanctions screening".to_string(),
+            "regulator".to_string(),
+        );
+
+        assert_eq!(
+            hold,
+            Err(ProcessingError::InsufficientAvailableForRegulatoryHold { client_id: 1 })
+        );
+        assert_eq!(balances(&processor, 1), (dec!(1), dec!(2), dec!(3), false));
+    }
+
+    /// Balances the post-process hook was called with
+    type SeenBalances = Arc<Mutex<Vec<(ClientId, Amount)>>>;
+
+    fn hooked_processor() -> (TransactionProcessor<State>, SeenBalances) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&seen);
+        let processor = TransactionProcessor::builder(State::new())
+            .with_post_process_hook(move |_, account| {
+                recorded
+                    .lock()
+                    .expect("hook records")
+                    .push((account.client, account.available));
+            })
+            .build();
+        (processor, seen)
+    }
+
+    #[test]
+    fn post_process_hooks_see_the_updated_accounts() {
+        let (processor, seen) = hooked_processor();
+        let transfer = StoredTransaction::Transfer {
+            id: 2,
+            client_id: 1,
+            to_client: 2,
+            amount: dec!(1),
+        };
+
+        process_all(
+            &processor,
+            vec![
+                transaction(TransactionType::Deposit, 1, 1, Some(dec!(3))),
+                transfer,
+            ],
+        );
+
+        assert_eq!(
+            *seen.lock().expect("hook records"),
+            vec![(1, dec!(3)), (1, dec!(2)), (2, dec!(1))]
+        );
+    }
+
+    #[test]
+    fn post_process_hooks_run_for_committed_transactions_only() {
+        let (processor, seen) = hooked_processor();
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(3)));
+        let withdrawal = transaction(TransactionType::Withdrawal, 1, 2, Some(dec!(5)));
+
+        assert_eq!(processor.process_noop(deposit).map(|_| ()), Ok(()));
+        assert!(seen.lock().expect("hook records").is_empty());
+        assert_eq!(processor.process_commit(1), Ok(()));
+        assert!(processor.process(withdrawal).is_err());
+
+        assert_eq!(*seen.lock().expect("hook records"), vec![(1, dec!(3))]);
+    }
+
+    #[test]
+    fn closing_the_closing_fee_destination_is_rejected() {
+        let processor = TransactionProcessor::builder(State::new())
+            .with_closing_fee_destination(1)
+            .build();
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(3)));
+        assert_eq!(processor.process(deposit), Ok(()));
+
+        assert_eq!(
+            processor.close_account(1),
+            Err(ProcessingError::InvalidClosingFeeDestination { client_id: 1 })
+        );
+        assert!(!processor.state.get_account(&1).expect("account").closed);
+    }
+
+    #[test]
+    fn process_with_retry_retries_transient_failures() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff: BackoffStrategy::Immediate,
+        };
+        let processor = TransactionProcessor::new(MockStateStorage::new().with_failing_inserts(2));
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(5)));
+
+        assert_eq!(processor.process_with_retry(deposit, policy), Ok(()));
+        let inserts = processor
+            .state
+            .calls_matching(|call| matches!(call, StorageCall::InsertTransaction { .. }));
+        assert_eq!(inserts.len(), 3);
+        assert_eq!(
+            processor.state.get_account(&1).map(|account| account.total),
+            Ok(dec!(5))
+        );
+    }
+
+    #[test]
+    fn process_with_retry_gives_up_after_max_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            backoff: BackoffStrategy::Fixed(Duration::from_millis(1)),
+        };
+        let processor = TransactionProcessor::new(MockStateStorage::new().with_failing_inserts(2));
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(5)));
+
+        let result = processor.process_with_retry(deposit, policy);
+
+        assert!(matches!(
+            result,
+            Err(ProcessingError::StoragePoisoned { .. })
+        ));
+        let inserts = processor
+            .state
+            .calls_matching(|call| matches!(call, StorageCall::InsertTransaction { .. }));
+        assert_eq!(inserts.len(), 2);
+    }
+
+    #[test]
+    fn process_with_retry_does_not_retry_rejections() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff: BackoffStrategy::Immediate,
+        };
+        let processor = TransactionProcessor::new(MockStateStorage::new());
+        let withdrawal = transaction(TransactionType::Withdrawal, 1, 1, Some(dec!(5)));
+
+        assert!(processor.process_with_retry(withdrawal, policy).is_err());
+        let inserts = processor
+            .state
+            .calls_matching(|call| matches!(call, StorageCall::InsertTransaction { .. }));
+        assert_eq!(inserts.len(), 1);
+    }
+
+    fn process_all(processor: &TransactionProcessor<State>, transactions: Vec<StoredTransaction>) {
+        for transaction in transactions {
+            assert_eq!(processor.process(transaction), Ok(()));
+        }
+    }
+
+    #[test]
+    fn average_deposit_amount_of_a_client() {
+        let processor = TransactionProcessor::new(State::new());
+        process_all(
+            &processor,
+            vec![
+                transaction(TransactionType::Deposit, 1, 1, Some(dec!(1))),
+                transaction(TransactionType::Deposit, 1, 2, Some(dec!(2))),
+                transaction(TransactionType::Deposit, 2, 3, Some(dec!(100))),
+                transaction(TransactionType::Deposit, 1, 4, Some(dec!(6))),
+            ],
+        );
+
+        assert_eq!(
+            processor.get_average_transaction_amount(1, TransactionType::Deposit),
+            Ok(dec!(3))
+        );
+    }
+
+    #[test]
+    fn average_withdrawal_amount_of_a_client() {
+        let processor = TransactionProcessor::new(State::new());
+        process_all(
+            &processor,
+            vec![
+                transaction(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+                transaction(TransactionType::Withdrawal, 1, 2, Some(dec!(1))),
+                transaction(TransactionType::Withdrawal, 1, 3, Some(dec!(2))),
+            ],
+        );
+
+        assert_eq!(
+            processor.get_average_transaction_amount(1, TransactionType::Withdrawal),
+            Ok(dec!(1.5))
+        );
+    }
+
+    #[test]
+    fn average_chargeback_amount_uses_the_charged_back_transaction() {
+        let processor = TransactionProcessor::new(State::new());
+        process_all(
+            &processor,
+            vec![
+                transaction(TransactionType::Deposit, 1, 1, Some(dec!(4))),
+                transaction(TransactionType::Deposit, 1, 2, Some(dec!(8))),
+                transaction(TransactionType::Chargeback, 1, 9, None),
+                transaction(TransactionType::Dispute, 1, 1, None),
+                transaction(TransactionType::Chargeback, 1, 1, None),
+            ],
+        );
+
+        assert_eq!(
+            processor.get_average_transaction_amount(1, TransactionType::Chargeback),
+            Ok(dec!(4))
+        );
+    }
+
+    #[test]
+    fn average_of_a_type_without_transactions_is_an_error() {
+        let processor = TransactionProcessor::new(State::new());
+        process_all(
+            &processor,
+            vec![transaction(TransactionType::Deposit, 1, 1, Some(dec!(4)))],
+        );
+
+        assert_eq!(
+            processor.get_average_transaction_amount(1, TransactionType::Chargeback),
+            Err(ProcessingError::NoTransactionsOfType {
+                client_id: 1,
+                tx_type: TransactionType::Chargeback,
+            })
+        );
+    }
+
+    #[test]
+    fn process_delegation_follows_a_two_level_chain() {
+        let processor = TransactionProcessor::new(State::new());
+        assert_eq!(processor.register_delegation(3, 2), Ok(()));
+        assert_eq!(processor.register_delegation(2, 1), Ok(()));
+        let deposit = transaction(TransactionType::Deposit, 3, 1, Some(dec!(5)));
+
+        assert_eq!(processor.process_delegation(deposit, 3), Ok(()));
+
+        assert_eq!(
+            processor.state.get_account(&1).map(|account| account.total),
+            Ok(dec!(5))
+        );
+        assert_eq!(processor.state.account_count(), Ok(1));
+        let stored = processor.state.get_transaction(1).expect("deposit");
+        assert_eq!(stored.client_id(), &1);
+    }
+
+    #[test]
+    fn circular_delegation_is_rejected() {
+        let processor = TransactionProcessor::new(State::new());
+        assert_eq!(processor.register_delegation(1, 2), Ok(()));
+        assert_eq!(processor.register_delegation(2, 3), Ok(()));
+
+        assert_eq!(
+            processor.register_delegation(3, 1),
+            Err(ProcessingError::CircularDelegation {
+                chain: vec![3, 1, 2, 3],
+            })
+        );
+
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(5)));
+        assert_eq!(processor.process_delegation(deposit, 1), Ok(()));
+        assert_eq!(
+            processor.state.get_account(&3).map(|account| account.total),
+            Ok(dec!(5))
+        );
+    }
+
+    #[test]
+    fn processors_sharing_a_storage_record_their_own_ids() {
+        let state = Arc::new(State::new());
+        let first = TransactionProcessor::builder(state.clone())
+            .processor_id("first".to_string())
+            .build();
+        let second = TransactionProcessor::builder(state.clone())
+            .processor_id("second".to_string())
+            .build();
+
+        let first_deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(5)));
+        let second_deposit = transaction(TransactionType::Deposit, 1, 2, Some(dec!(3)));
+        let withdrawal = transaction(TransactionType::Withdrawal, 1, 3, Some(dec!(2)));
+        let dispute = transaction(TransactionType::Dispute, 1, 1, None);
+        assert_eq!(first.process(first_deposit), Ok(()));
+        assert_eq!(second.process(second_deposit), Ok(()));
+        assert_eq!(first.process(withdrawal), Ok(()));
+        assert_eq!(second.process(dispute), Ok(()));
+
+        let ids = |processor_id| {
+            let mut ids = state
+                .get_transactions_by_processor(processor_id)
+                .expect("transactions")
+                .iter()
+                .map(|transaction| *transaction.id())
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids("first"), vec![1, 3]);
+        assert_eq!(ids("second"), vec![2]);
+        let account = state.get_account(&1).expect("account");
+        assert_eq!((account.available, account.held), (dec!(1), dec!(5)));
+    }
+
+    #[cfg(all(feature = "serde", feature = "large_client_ids"))]
+    #[test]
+    fn client_ids_beyond_u16_are_processed() {
+        let processor = TransactionProcessor::new(State::new());
+        let input = "type,client,tx,amount\n\
+                     deposit,65535,1,1.0\n\
+                     deposit,65536,2,2.0\n\
+                     deposit,4294967295,3,3.0\n";
+        let mut reader = csv::Reader::from_reader(input.as_bytes());
+
+        let stats = processor.process_reader(&mut reader).expect("stats");
+
+        assert_eq!((stats.succeeded, stats.malformed), (3, 0));
+        let mut accounts = processor.get_accounts().expect("accounts");
+        accounts.sort();
+        let clients = accounts
+            .iter()
+            .map(|account| account.client)
+            .collect::<Vec<_>>();
+        assert_eq!(clients, vec![65535, 65536, u32::MAX]);
+        assert_eq!(accounts[1].total, dec!(2));
+    }
+
+    #[cfg(all(feature = "serde", not(feature = "large_client_ids")))]
+    #[test]
+    fn client_ids_beyond_u16_are_malformed() {
+        let processor = TransactionProcessor::new(State::new());
+        let input = "type,client,tx,amount\n\
+                     deposit,65535,1,1.0\n\
+                     deposit,65536,2,2.0\n";
+        let mut reader = csv::Reader::from_reader(input.as_bytes());
+
+        let stats = processor.process_reader(&mut reader).expect("stats");
+
+        assert_eq!((stats.succeeded, stats.malformed), (1, 1));
+        assert_eq!(processor.account_count(), Ok(1));
+    }
+
+    /// (available, held, total, locked) of the client's account
+    fn balances(
+        processor: &TransactionProcessor<State>,
+        client_id: ClientId,
+    ) -> (Amount, Amount, Amount, bool) {
+        let account = processor.state.get_account(&client_id).expect("account");
+        (
+            account.available,
+            account.held,
+            account.total,
+            account.locked,
+        )
+    }
+
+    fn withdrawn_account() -> TransactionProcessor<State> {
+        let processor = TransactionProcessor::new(State::new());
+        process_all(
+            &processor,
+            vec![
+                transaction(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+                transaction(TransactionType::Withdrawal, 1, 2, Some(dec!(4))),
+            ],
+        );
+        assert_eq!(balances(&processor, 1), (dec!(6), dec!(0), dec!(6), false));
+        processor
+    }
+
+    #[test]
+    fn withdrawal_dispute_then_chargeback_restores_the_funds_and_locks() {
+        let processor = withdrawn_account();
+
+        process_all(
+            &processor,
+            vec![transaction(TransactionType::Dispute, 1, 2, None)],
+        );
+        assert_eq!(balances(&processor, 1), (dec!(6), dec!(4), dec!(10), false));
+        assert_eq!(
+            processor
+                .state
+                .get_transaction(2)
+                .map(|tx| tx.is_under_dispute()),
+            Ok(true)
+        );
+
+        process_all(
+            &processor,
+            vec![transaction(TransactionType::Chargeback, 1, 2, None)],
+        );
+        assert_eq!(balances(&processor, 1), (dec!(10), dec!(0), dec!(10), true));
+        assert_eq!(
+            processor.state.get_account(&1).map(|a| a.chargeback_count),
+            Ok(1)
+        );
+        assert_eq!(
+            processor
+                .state
+                .get_transaction(2)
+                .map(|tx| tx.is_under_dispute()),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn withdrawal_dispute_then_resolve_keeps_the_withdrawal() {
+        let processor = withdrawn_account();
+
+        process_all(
+            &processor,
+            vec![
+                transaction(TransactionType::Dispute, 1, 2, None),
+                transaction(TransactionType::Resolve, 1, 2, None),
+            ],
+        );
+        assert_eq!(balances(&processor, 1), (dec!(6), dec!(0), dec!(6), false));
+        assert_eq!(
+            processor
+                .state
+                .get_transaction(2)
+                .map(|tx| tx.is_under_dispute()),
+            Ok(false)
+        );
+
+        process_all(
+            &processor,
+            vec![
+                transaction(TransactionType::Resolve, 1, 2, None),
+                transaction(TransactionType::Chargeback, 1, 2, None),
+            ],
+        );
+        assert_eq!(balances(&processor, 1), (dec!(6), dec!(0), dec!(6), false));
+
+        process_all(
+            &processor,
+            vec![transaction(TransactionType::Dispute, 1, 2, None)],
+        );
+        assert_eq!(balances(&processor, 1), (dec!(6), dec!(4), dec!(10), false));
+    }
+
+    #[test]
+    fn withdrawal_dispute_cannot_be_opened_twice() {
+        let processor = withdrawn_account();
+        process_all(
+            &processor,
+            vec![transaction(TransactionType::Dispute, 1, 2, None)],
+        );
+
+        assert_eq!(
+            processor.process(transaction(TransactionType::Dispute, 1, 2, None)),
+            Err(ProcessingError::TransactionAlreadyUnderDispute { id: 2 })
+        );
+        assert_eq!(balances(&processor, 1), (dec!(6), dec!(4), dec!(10), false));
+    }
+
+    #[test]
+    fn metrics_totals_saturate_instead_of_overflowing() {
+        let processor = TransactionProcessor::new(State::new());
+        let amount = dec!(50000000000000000000000000000);
+
+        assert_eq!(
+            processor.process(transaction(TransactionType::Deposit, 1, 1, Some(amount))),
+            Ok(())
+        );
+        assert_eq!(
+            processor.process(transaction(TransactionType::Deposit, 2, 2, Some(amount))),
+            Ok(())
+        );
+
+        let metrics = processor.metrics();
+        assert_eq!(metrics.deposits_processed, 2);
+        assert_eq!(metrics.total_deposited, Amount::MAX);
+        assert!(metrics.totals_saturated);
+    }
+
+    #[test]
+    fn percentage_fee_of_a_large_deposit_does_not_overflow() {
+        let fee_schedule = FeeSchedule {
+            flat: dec!(0),
+            percentage: dec!(2),
+        };
+        let processor = TransactionProcessor::builder(State::new())
+            .with_fee_schedule(fee_schedule)
+            .build();
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(5e28)));
+
+        assert_eq!(processor.process(deposit), Ok(()));
+        assert_eq!(
+            processor.state.get_account(&1).map(|account| account.total),
+            Ok(dec!(49000000000000000000000000000))
+        );
+    }
+
+    #[test]
+    fn fee_beyond_the_maximum_amount_is_an_overflow() {
+        let fee_schedule = FeeSchedule {
+            flat: dec!(0),
+            percentage: dec!(300),
+        };
+        let processor = TransactionProcessor::builder(State::new())
+            .with_fee_schedule(fee_schedule)
+            .build();
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(dec!(5e28)));
+
+        assert_eq!(
+            processor.process(deposit),
+            Err(ProcessingError::AmountOverflow { client_id: 1 })
+        );
+        assert_eq!(processor.state.contains_transaction(1), Ok(false));
+    }
+
+    #[test]
+    fn velocity_metrics_report_an_overflowing_deposit_total() {
+        let processor = TransactionProcessor::new(State::new());
+        process_all(
+            &processor,
+            vec![
+                transaction(TransactionType::Deposit, 1, 1, Some(dec!(5e28))),
+                transaction(TransactionType::Withdrawal, 1, 2, Some(dec!(5e28))),
+                transaction(TransactionType::Deposit, 1, 3, Some(dec!(5e28))),
+            ],
+        );
+
+        assert_eq!(
+            processor.get_velocity_metrics(1, Duration::from_secs(60)),
+            Err(ProcessingError::AmountOverflow { client_id: 1 })
+        );
     }
 }
@@ -1,44 +1,789 @@
-use std::collections::HashMap;
-use std::sync::RwLock;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::ops::Bound;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::SystemTime;
+
+use tokio::sync::watch;
 
 use crate::{
-    api::{ProcessingError, ProcessingResult},
-    domain::{Account, ClientId, StoredTransaction, TransactionId},
+    api::{ConsistencyError, ProcessingError, ProcessingResult},
+    domain::{
+        Account, AccountTier, AccountVersion, Amount, BalanceSnapshot, ClientId, JournalEntry,
+        RiskScore, StoredTransaction, TierConfig, TransactionId,
+    },
 };
 
-pub trait StateStorage {
+pub const DEFAULT_BALANCE_HISTORY_DEPTH: usize = 1000;
+pub const DEFAULT_MAX_ACCOUNT_VERSIONS: usize = 10_000;
+
+pub trait StateStorage: Send + Sync {
     fn get_transaction(&self, id: TransactionId) -> ProcessingResult<StoredTransaction>;
+    /// Like [`get_transaction`](Self::get_transaction), but another client's transaction
+    /// is reported as not found, so callers can't probe for transaction ids they don't own.
+    fn get_transaction_for_client(
+        &self,
+        id: TransactionId,
+        client_id: ClientId,
+    ) -> ProcessingResult<StoredTransaction>;
+    fn contains_transaction(&self, id: TransactionId) -> ProcessingResult<bool>;
     fn insert_transaction(
         &self,
         transaction: StoredTransaction,
     ) -> ProcessingResult<StoredTransaction>;
+    /// Forgets the transaction, its journal entry is kept
+    fn remove_transaction(&self, id: TransactionId) -> ProcessingResult<()>;
     fn under_dispute(&self, id: TransactionId, under_dispute: bool) -> ProcessingResult<()>;
-
-    fn get_all_accounts(&self) -> ProcessingResult<Box<Vec<Account>>>;
+    fn release_hold(&self, id: TransactionId) -> ProcessingResult<()>;
+    fn approve_kyc(&self, client_id: ClientId) -> ProcessingResult<()>;
+    fn get_transactions_for_client(&self, id: ClientId) -> ProcessingResult<Vec<JournalEntry>>;
+    fn get_journal_entries_until(&self, end: SystemTime) -> ProcessingResult<Vec<JournalEntry>>;
+    fn get_clients_with_no_activity_since(
+        &self,
+        cutoff: SystemTime,
+    ) -> ProcessingResult<Vec<ClientId>>;
+    fn get_transactions_in_amount_range(
+        &self,
+        min: Amount,
+        max: Amount,
+    ) -> ProcessingResult<Vec<StoredTransaction>>;
+    fn get_transactions_by_processor(
+        &self,
+        processor_id: &str,
+    ) -> ProcessingResult<Vec<StoredTransaction>>;
+    fn get_orphaned_transactions(&self) -> ProcessingResult<Vec<StoredTransaction>>;
+    fn transaction_count(&self) -> ProcessingResult<usize>;
+    /// Number of the client's deposits and withdrawals currently under dispute
+    fn count_open_disputes(&self, client_id: ClientId) -> ProcessingResult<u32>;
+    /// Streams all stored transactions, cloning one at a time. Storages may hold their
+    /// read lock until the iterator is dropped, so it must not outlive writes to `self`.
+    fn iter_transactions<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = ProcessingResult<StoredTransaction>> + 'a>;
+    fn get_all_accounts(&self) -> ProcessingResult<Vec<Account>>;
+    #[deprecated(
+        since = "0.2.0",
+        note = "use `get_all_accounts`, which returns a `Vec`"
+    )]
+    #[allow(clippy::box_collection)]
+    fn get_all_accounts_boxed(&self) -> ProcessingResult<Box<Vec<Account>>> {
+        self.get_all_accounts().map(Box::new)
+    }
+    /// Accounts with their risk scores, the riskiest first. Uses the scores cached in the
+    /// accounts when they were last stored.
+    fn get_accounts_sorted_by_risk(&self) -> ProcessingResult<Vec<(Account, RiskScore)>>;
     fn get_account(&self, id: &ClientId) -> ProcessingResult<Account>;
-    fn upsert_account(&self, account: Account) -> ProcessingResult<()>;
+
+    /// Calls `f` with the client's account, or a new one if there is none. Storages override
+    /// the default to hold their lock for the duration of `f` instead of cloning the account.
+    fn with_account<F, R>(&self, id: ClientId, f: F) -> ProcessingResult<R>
+    where
+        F: FnOnce(&Account) -> R,
+        Self: Sized,
+    {
+        self.get_account(&id).map(|account| f(&account))
+    }
+    fn account_count(&self) -> ProcessingResult<usize>;
+    fn get_accounts_with_zero_balance(&self) -> ProcessingResult<Vec<Account>>;
+    fn get_clients_exceeding_balance_threshold(
+        &self,
+        threshold: Amount,
+    ) -> ProcessingResult<Vec<ClientId>>;
+    fn get_accounts_by_tier(
+        &self,
+        config: &TierConfig,
+    ) -> ProcessingResult<HashMap<AccountTier, Vec<Account>>>;
+    fn remove_account(&self, id: ClientId) -> ProcessingResult<()>;
+    fn migrate_client_id(
+        &self,
+        old_id: ClientId,
+        new_id: ClientId,
+        merge: bool,
+    ) -> ProcessingResult<()>;
+    fn register_delegation(&self, from: ClientId, to: ClientId) -> ProcessingResult<()>;
+    fn resolve_delegation(&self, id: ClientId) -> ProcessingResult<ClientId>;
+    fn upsert_account(&self, account: Account, triggered_by: TransactionId)
+        -> ProcessingResult<()>;
+    fn upsert_accounts(
+        &self,
+        accounts: Vec<Account>,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()>;
+
+    /// Applies `update` to the client's account and stores the result. The default reads
+    /// and upserts in two steps; storages that can lock a single account override it to
+    /// hold that lock for the whole mutation.
+    fn update_account(
+        &self,
+        id: ClientId,
+        triggered_by: TransactionId,
+        update: &mut dyn FnMut(&mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<()> {
+        let mut account = self.get_account(&id)?;
+        update(&mut account)?;
+        self.upsert_account(account, triggered_by)
+    }
+
+    /// Applies `update` to the accounts of two different clients, e.g. the source and the
+    /// destination of a transfer, and stores both together. The default reads and upserts
+    /// in two steps; storages override it to hold their accounts lock for the whole mutation.
+    fn update_accounts(
+        &self,
+        from: ClientId,
+        to: ClientId,
+        triggered_by: TransactionId,
+        update: &mut dyn FnMut(&mut Account, &mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<()> {
+        check_distinct_clients(from, to, triggered_by)?;
+        let mut source = self.get_account(&from)?;
+        let mut destination = self.get_account(&to)?;
+        update(&mut source, &mut destination)?;
+        self.upsert_accounts(vec![source, destination], triggered_by)
+    }
+    fn balance_history(&self, id: ClientId) -> ProcessingResult<Vec<BalanceSnapshot>>;
+    fn get_account_versions(&self, id: ClientId) -> ProcessingResult<Vec<AccountVersion>>;
+    fn compact_account_history(&self, id: ClientId, keep_last: usize) -> ProcessingResult<()>;
+    fn subscribe_to_account_updates(
+        &self,
+        id: ClientId,
+    ) -> ProcessingResult<watch::Receiver<Account>>;
+    fn clear(&self) -> ProcessingResult<()>;
+
+    /// Copies all accounts, transactions and their history into a new storage without
+    /// account subscribers.
+    fn snapshot(&self) -> ProcessingResult<Self>
+    where
+        Self: Sized;
+
+    /// Replaces all accounts, transactions and their history with the ones of `snapshot`
+    /// and notifies account subscribers of the restored balances.
+    fn restore(&self, snapshot: &Self) -> ProcessingResult<()>
+    where
+        Self: Sized;
+
+    /// Starts an explicit transaction whose changes are staged on a snapshot of this storage.
+    fn begin_transaction(&self) -> ProcessingResult<StateTransaction<'_, Self>>
+    where
+        Self: Sized,
+    {
+        tracing::debug!("Beginning state transaction");
+        let staging = self.snapshot()?;
+        let versions = staging
+            .get_all_accounts()?
+            .into_iter()
+            .map(|account| (account.client, account.version))
+            .collect();
+        let journaled = staging.get_journal_entries_until(SystemTime::now())?.len();
+        Ok(StateTransaction {
+            target: self,
+            staging,
+            versions,
+            journaled,
+            committed: false,
+        })
+    }
+}
+
+/// Explicit transaction modeled after `rusqlite::Transaction`. Changes are made to the staging
+/// storage and reach the storage the transaction began on only on `commit`. Dropping the
+/// transaction without committing rolls the changes back.
+///
+/// The storage isn't locked while the transaction is open. `commit` applies only the staged
+/// changes, so writes made to the storage in the meantime are kept. If any account changed
+/// by the transaction was also changed in the storage since `begin_transaction`, or a
+/// transaction id it stored was taken, the commit fails with
+/// [`StateTransactionConflict`](ProcessingError::StateTransactionConflict) or
+/// [`TransactionAlreadyExists`](ProcessingError::TransactionAlreadyExists) before writing
+/// anything. The changes are written one after the other, a conflicting write racing with
+/// the commit can still leave it applied in part.
+pub struct StateTransaction<'a, S: StateStorage> {
+    target: &'a S,
+    staging: S,
+    /// Account versions when the transaction began, accounts missing have version `0`
+    versions: HashMap<ClientId, u32>,
+    /// Number of journal entries when the transaction began
+    journaled: usize,
+    committed: bool,
+}
+
+impl<S: StateStorage> StateTransaction<'_, S> {
+    /// Storage holding the changes of this transaction
+    pub fn staging(&self) -> &S {
+        &self.staging
+    }
+
+    pub(crate) fn staging_mut(&mut self) -> &mut S {
+        &mut self.staging
+    }
+
+    /// Applies the transactions journaled and the accounts changed on the staging storage.
+    /// Transactions that were stored and then rejected in the staging storage are skipped.
+    pub fn commit(mut self) -> ProcessingResult<()> {
+        tracing::debug!("Committing state transaction");
+        let staged = self
+            .staging
+            .get_journal_entries_until(SystemTime::now())?
+            .into_iter()
+            .skip(self.journaled)
+            .map(|entry| entry.transaction)
+            .collect::<Vec<_>>();
+        let accounts = self
+            .staging
+            .get_all_accounts()?
+            .into_iter()
+            .filter(|account| self.began_at(account.client) != account.version)
+            .collect::<Vec<_>>();
+        self.check_conflicts(&staged, &accounts)?;
+        for transaction in &staged {
+            self.commit_transaction(transaction)?;
+        }
+        for account in accounts {
+            let client_id = account.client;
+            let began_at = self.began_at(client_id);
+            let triggered_by = staged
+                .iter()
+                .rev()
+                .find(|transaction| *transaction.client_id() == client_id)
+                .map_or(0, |transaction| *transaction.id());
+            let mut staged_account = Some(account);
+            self.target
+                .update_account(client_id, triggered_by, &mut |current| {
+                    if current.version != began_at {
+                        return Err(conflict(client_id));
+                    }
+                    if let Some(staged_account) = staged_account.take() {
+                        *current = staged_account;
+                    }
+                    Ok(())
+                })?;
+        }
+        self.committed = true;
+        Ok(())
+    }
+
+    pub fn rollback(self) {
+        drop(self)
+    }
+
+    fn began_at(&self, client_id: ClientId) -> u32 {
+        self.versions.get(&client_id).copied().unwrap_or(0)
+    }
+
+    fn check_conflicts(
+        &self,
+        staged: &[StoredTransaction],
+        accounts: &[Account],
+    ) -> ProcessingResult<()> {
+        for account in accounts {
+            if self.target.get_account(&account.client)?.version != self.began_at(account.client) {
+                return Err(conflict(account.client));
+            }
+        }
+        for transaction in staged.iter().filter(|tx| tx.is_stored()) {
+            if self.target.contains_transaction(*transaction.id())? {
+                tracing::error!(
+                    "Transaction id {} taken since the state transaction began",
+                    transaction.id()
+                );
+                return Err(ProcessingError::TransactionAlreadyExists {
+                    id: *transaction.id(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores or journals the staged transaction on the target and carries over the flags it
+    /// changed on the transactions it references
+    fn commit_transaction(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        if transaction.is_stored() {
+            // The stored version has the flags set by later staged transactions
+            return match self.staging.get_transaction(*transaction.id()) {
+                Ok(stored) => self.target.insert_transaction(stored).map(|_| ()),
+                Err(ProcessingError::TransactionNotFound { .. }) => Ok(()),
+                Err(e) => Err(e),
+            };
+        }
+        self.target.insert_transaction(transaction.clone())?;
+        match transaction {
+            StoredTransaction::Dispute { id, client_id }
+            | StoredTransaction::Resolve { id, client_id }
+            | StoredTransaction::Chargeback { id, client_id } => {
+                match self.staging.get_transaction_for_client(*id, *client_id) {
+                    Ok(stored) => self.target.under_dispute(*id, stored.is_under_dispute()),
+                    Err(ProcessingError::TransactionNotFound { .. }) => Ok(()),
+                    Err(e) => Err(e),
+                }
+            }
+            StoredTransaction::RegulatoryRelease { id, .. } => {
+                match self.staging.get_transaction(*id) {
+                    Ok(StoredTransaction::RegulatoryHold { released: true, .. }) => {
+                        self.target.release_hold(*id)
+                    }
+                    Ok(_) | Err(ProcessingError::TransactionNotFound { .. }) => Ok(()),
+                    Err(e) => Err(e),
+                }
+            }
+            StoredTransaction::KycApproved { client_id, .. } => {
+                if self.staging.get_account(client_id)?.kyc_pending == Amount::ZERO {
+                    self.target.approve_kyc(*client_id)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn conflict(client_id: ClientId) -> ProcessingError {
+    tracing::error!(
+        "Client {} account changed since the state transaction began",
+        client_id
+    );
+    ProcessingError::StateTransactionConflict { client_id }
+}
+
+impl<S: StateStorage> Drop for StateTransaction<'_, S> {
+    fn drop(&mut self) {
+        if !self.committed {
+            tracing::debug!("Rolling back state transaction");
+        }
+    }
+}
+
+pub type DefaultState = State<RandomState>;
+
+pub struct State<H = RandomState> {
+    accounts: RwLock<HashMap<ClientId, Account, H>>,
+    balance_index: RwLock<BTreeSet<(Amount, ClientId)>>,
+    transactions: RwLock<HashMap<TransactionId, StoredTransaction, H>>,
+    amount_index: RwLock<BTreeMap<Amount, Vec<TransactionId>>>,
+    journal: RwLock<Vec<JournalEntry>>,
+    account_subscribers: RwLock<HashMap<ClientId, watch::Sender<Account>, H>>,
+    delegations: RwLock<HashMap<ClientId, ClientId, H>>,
+    balance_history: RwLock<HashMap<ClientId, VecDeque<BalanceSnapshot>, H>>,
+    history_depth: usize,
+    account_versions: RwLock<HashMap<ClientId, Vec<AccountVersion>, H>>,
+    max_account_versions: usize,
+    max_transaction_id: Option<TransactionId>,
+}
+
+impl<H: BuildHasher + Default + Clone> Clone for State<H> {
+    fn clone(&self) -> Self {
+        Self {
+            accounts: RwLock::new(read_unpoisoned(&self.accounts).clone()),
+            balance_index: RwLock::new(read_unpoisoned(&self.balance_index).clone()),
+            transactions: RwLock::new(read_unpoisoned(&self.transactions).clone()),
+            amount_index: RwLock::new(read_unpoisoned(&self.amount_index).clone()),
+            journal: RwLock::new(read_unpoisoned(&self.journal).clone()),
+            account_subscribers: RwLock::new(HashMap::default()),
+            delegations: RwLock::new(read_unpoisoned(&self.delegations).clone()),
+            balance_history: RwLock::new(read_unpoisoned(&self.balance_history).clone()),
+            history_depth: self.history_depth,
+            account_versions: RwLock::new(read_unpoisoned(&self.account_versions).clone()),
+            max_account_versions: self.max_account_versions,
+            max_transaction_id: self.max_transaction_id,
+        }
+    }
 }
 
-pub struct State {
-    accounts: RwLock<HashMap<ClientId, Account>>,
-    transactions: RwLock<HashMap<TransactionId, StoredTransaction>>,
+impl<H: BuildHasher + Default> Default for State<H> {
+    fn default() -> Self {
+        Self::with_hasher()
+    }
 }
 
 impl State {
     pub fn new() -> Self {
+        Self::with_hasher()
+    }
+}
+
+impl<H: BuildHasher + Default> State<H> {
+    /// Creates an empty state whose maps hash keys with `H` instead of the default SipHash
+    pub fn with_hasher() -> Self {
         Self {
-            accounts: RwLock::new(HashMap::new()),
-            transactions: RwLock::new(HashMap::new()),
+            accounts: RwLock::new(HashMap::default()),
+            balance_index: RwLock::new(BTreeSet::new()),
+            transactions: RwLock::new(HashMap::default()),
+            amount_index: RwLock::new(BTreeMap::new()),
+            journal: RwLock::new(Vec::new()),
+            account_subscribers: RwLock::new(HashMap::default()),
+            delegations: RwLock::new(HashMap::default()),
+            balance_history: RwLock::new(HashMap::default()),
+            history_depth: DEFAULT_BALANCE_HISTORY_DEPTH,
+            account_versions: RwLock::new(HashMap::default()),
+            max_account_versions: DEFAULT_MAX_ACCOUNT_VERSIONS,
+            max_transaction_id: None,
+        }
+    }
+
+    pub fn with_history_depth(mut self, history_depth: usize) -> Self {
+        self.history_depth = history_depth;
+        self
+    }
+
+    pub fn with_max_account_versions(mut self, max_account_versions: usize) -> Self {
+        self.max_account_versions = max_account_versions;
+        self
+    }
+
+    /// Rejects new deposits, withdrawals, transfers, regulatory holds and refunds with ids
+    /// above `max` with `TransactionIdSpaceExhausted`
+    pub fn with_max_transaction_id(mut self, max: TransactionId) -> Self {
+        self.max_transaction_id = Some(max);
+        self
+    }
+
+    fn check_transaction_id(&self, id: TransactionId) -> ProcessingResult<()> {
+        match self.max_transaction_id {
+            Some(max) if id > max => {
+                tracing::error!("Transaction id {} exceeds the maximum of {}", id, max);
+                Err(ProcessingError::TransactionIdSpaceExhausted { id, max })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn validate_consistency(&self) -> Vec<ConsistencyError> {
+        let accounts = read_unpoisoned(&self.accounts);
+        let transactions = read_unpoisoned(&self.transactions);
+        let mut errors = Vec::new();
+
+        for account in accounts.values() {
+            if account.available + account.held != account.total {
+                errors.push(ConsistencyError::TotalMismatch {
+                    client_id: account.client,
+                    available: account.available,
+                    held: account.held,
+                    total: account.total,
+                });
+            }
+            let balances = [
+                ("available", account.available),
+                ("held", account.held),
+                ("total", account.total),
+            ];
+            for (field, amount) in balances {
+                if amount < Amount::ZERO {
+                    errors.push(ConsistencyError::NegativeBalance {
+                        client_id: account.client,
+                        field,
+                        amount,
+                    });
+                }
+            }
+        }
+
+        let mut on_hold = HashMap::<ClientId, Amount>::new();
+        for tx in transactions.values() {
+            let amount = match tx {
+                StoredTransaction::Deposit {
+                    amount,
+                    under_dispute: true,
+                    ..
+                }
+                | StoredTransaction::Withdrawal {
+                    amount,
+                    under_dispute: true,
+                    ..
+                } => amount,
+                StoredTransaction::RegulatoryHold {
+                    amount,
+                    released: false,
+                    ..
+                } => amount,
+                _ => continue,
+            };
+            if !accounts.contains_key(tx.client_id()) {
+                errors.push(ConsistencyError::MissingAccount {
+                    id: *tx.id(),
+                    client_id: *tx.client_id(),
+                });
+            }
+            *on_hold.entry(*tx.client_id()).or_default() += amount;
+        }
+        for (client_id, on_hold) in on_hold {
+            if let Some(account) = accounts.get(&client_id) {
+                if account.held < on_hold {
+                    errors.push(ConsistencyError::InsufficientHeldFunds {
+                        client_id,
+                        held: account.held,
+                        on_hold,
+                    });
+                }
+            }
+        }
+        errors
+    }
+
+    fn on_account_updated(
+        &self,
+        account: &Account,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        self.record_balance_snapshot(account, triggered_by)?;
+        self.record_account_version(account)?;
+        self.notify_account_update(account)
+    }
+
+    /// Stores the account as the next version into the locked `accounts`
+    fn store_account(
+        &self,
+        accounts: &mut HashMap<ClientId, Account, H>,
+        mut account: Account,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        account.version = accounts.get(&account.client).map_or(0, |a| a.version) + 1;
+        account.risk_score = Some(RiskScore::of(&account).score);
+        self.on_account_updated(&account, triggered_by)?;
+        let previous = accounts.insert(account.client, account.clone());
+        self.update_balance_index(previous.as_ref(), Some(&account))
+    }
+
+    fn update_balance_index(
+        &self,
+        previous: Option<&Account>,
+        account: Option<&Account>,
+    ) -> ProcessingResult<()> {
+        self.balance_index
+            .write()
+            .map_err(|e| storage_poisoned("balance_index", e))
+            .map(|mut index| {
+                if let Some(previous) = previous {
+                    index.remove(&(previous.total, previous.client));
+                }
+                if let Some(account) = account {
+                    index.insert((account.total, account.client));
+                }
+            })
+    }
+
+    fn index_amount(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        match transaction {
+            StoredTransaction::Deposit { id, amount, .. }
+            | StoredTransaction::Withdrawal { id, amount, .. } => self
+                .amount_index
+                .write()
+                .map_err(|e| storage_poisoned("amount_index", e))
+                .map(|mut index| index.entry(*amount).or_default().push(*id)),
+            _ => Ok(()),
+        }
+    }
+
+    fn unindex_amount(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        match transaction {
+            StoredTransaction::Deposit { id, amount, .. }
+            | StoredTransaction::Withdrawal { id, amount, .. } => self
+                .amount_index
+                .write()
+                .map_err(|e| storage_poisoned("amount_index", e))
+                .map(|mut index| {
+                    if let Some(ids) = index.get_mut(amount) {
+                        ids.retain(|indexed| indexed != id);
+                        if ids.is_empty() {
+                            index.remove(amount);
+                        }
+                    }
+                }),
+            _ => Ok(()),
+        }
+    }
+
+    fn append_to_journal(&self, transaction: &StoredTransaction) -> ProcessingResult<()> {
+        self.journal
+            .write()
+            .map_err(|e| storage_poisoned("journal", e))
+            .map(|mut journal| {
+                journal.push(JournalEntry {
+                    created_at: SystemTime::now(),
+                    transaction: transaction.clone(),
+                })
+            })
+    }
+
+    fn record_account_version(&self, account: &Account) -> ProcessingResult<()> {
+        if self.max_account_versions == 0 {
+            return Ok(());
+        }
+        self.account_versions
+            .write()
+            .map_err(|e| storage_poisoned("account_versions", e))
+            .map(|mut versions| {
+                let versions = versions.entry(account.client).or_default();
+                if versions.len() >= self.max_account_versions {
+                    let overflow = versions.len() + 1 - self.max_account_versions;
+                    versions.drain(..overflow);
+                }
+                versions.push(AccountVersion {
+                    version: account.version,
+                    created_at: SystemTime::now(),
+                    account: account.clone(),
+                });
+            })
+    }
+
+    fn record_balance_snapshot(
+        &self,
+        account: &Account,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        if self.history_depth == 0 {
+            return Ok(());
+        }
+        self.balance_history
+            .write()
+            .map_err(|e| storage_poisoned("balance_history", e))
+            .map(|mut history| {
+                let snapshots = history.entry(account.client).or_default();
+                if snapshots.len() >= self.history_depth {
+                    snapshots.pop_front();
+                }
+                snapshots.push_back(BalanceSnapshot {
+                    timestamp: SystemTime::now(),
+                    available: account.available,
+                    held: account.held,
+                    total: account.total,
+                    triggered_by_tx: triggered_by,
+                });
+            })
+    }
+
+    fn notify_account_update(&self, account: &Account) -> ProcessingResult<()> {
+        self.account_subscribers
+            .read()
+            .map_err(|e| storage_poisoned("account_subscribers", e))
+            .map(|subscribers| {
+                if let Some(sender) = subscribers.get(&account.client) {
+                    sender.send_replace(account.clone());
+                }
+            })
+    }
+}
+
+pub(crate) fn check_balance_invariant(account: &Account) -> ProcessingResult<()> {
+    if account.available < Amount::ZERO || account.held < Amount::ZERO {
+        tracing::error!(
+            "Refusing to store account with negative balance: {:?}",
+            account
+        );
+        return Err(ProcessingError::AccountBalanceInvariantViolated {
+            client_id: account.client,
+        });
+    }
+    Ok(())
+}
+
+/// `update_accounts` takes the two accounts mutably, so they can't be the same one
+pub(crate) fn check_distinct_clients(
+    from: ClientId,
+    to: ClientId,
+    triggered_by: TransactionId,
+) -> ProcessingResult<()> {
+    if from == to {
+        tracing::error!(
+            "Refusing to update account of client {} against itself",
+            from
+        );
+        return Err(ProcessingError::TransactionIsNotValid { id: triggered_by });
+    }
+    Ok(())
+}
+
+/// Pairs the accounts with their cached or computed risk scores, the riskiest first
+pub(crate) fn sorted_by_risk(accounts: impl Iterator<Item = Account>) -> Vec<(Account, RiskScore)> {
+    let mut scored = accounts
+        .map(|account| {
+            let score = account.risk_score();
+            (account, score)
+        })
+        .collect::<Vec<_>>();
+    scored.sort_by(|(a, a_score), (b, b_score)| {
+        b_score
+            .score
+            .total_cmp(&a_score.score)
+            .then(a.client.cmp(&b.client))
+    });
+    scored
+}
+
+pub(crate) fn delegation_target<H: BuildHasher>(
+    delegations: &HashMap<ClientId, ClientId, H>,
+    id: ClientId,
+) -> ProcessingResult<ClientId> {
+    let mut chain = vec![id];
+    let mut target = id;
+    while let Some(next) = delegations.get(&target) {
+        if chain.contains(next) {
+            chain.push(*next);
+            tracing::error!("Circular delegation {:?}", chain);
+            return Err(ProcessingError::CircularDelegation { chain });
         }
+        chain.push(*next);
+        target = *next;
+    }
+    Ok(target)
+}
+
+/// Holds the transactions read lock while cloning the transactions one at a time, in the
+/// order of the ids collected when the lock was taken
+struct TransactionsIter<'a, H> {
+    transactions: RwLockReadGuard<'a, HashMap<TransactionId, StoredTransaction, H>>,
+    ids: std::vec::IntoIter<TransactionId>,
+}
+
+impl<H: BuildHasher> Iterator for TransactionsIter<'_, H> {
+    type Item = ProcessingResult<StoredTransaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.ids.next()?;
+        Some(
+            self.transactions
+                .get(&id)
+                .cloned()
+                .ok_or(ProcessingError::TransactionNotFound { id }),
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.ids.size_hint()
+    }
+}
+
+fn copy_locked<T: Clone>(lock: &RwLock<T>, context: &str) -> ProcessingResult<T> {
+    lock.read()
+        .map_err(|e| storage_poisoned(context, e))
+        .map(|value| value.clone())
+}
+
+fn copy_locked_map<K: Copy + Eq + Hash, V: Clone, H: BuildHasher + Default>(
+    lock: &RwLock<HashMap<K, V, H>>,
+    context: &str,
+) -> ProcessingResult<HashMap<K, V, H>> {
+    lock.read()
+        .map_err(|e| storage_poisoned(context, e))
+        .map(|map| map.iter().map(|(k, v)| (*k, v.clone())).collect())
+}
+
+fn read_unpoisoned<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub(crate) fn storage_poisoned(context: &str, error: impl std::fmt::Display) -> ProcessingError {
+    tracing::error!("Storage lock poisoned for {}: {}", context, error);
+    ProcessingError::StoragePoisoned {
+        context: format!("{}: {}", context, error),
     }
 }
 
-impl StateStorage for State {
+impl<H: BuildHasher + Default + Send + Sync> StateStorage for State<H> {
     fn get_transaction(&self, id: TransactionId) -> ProcessingResult<StoredTransaction> {
-        tracing::debug!("Retrieving all client account transactions");
+        tracing::debug!("Retrieving transaction with id {}", id);
         self.transactions
             .read()
-            .map_err(|e| ProcessingError::UnknownError(e.to_string()))
+            .map_err(|e| storage_poisoned("transactions", e))
             .and_then(|transactions| {
                 transactions
                     .get(&id)
@@ -47,31 +792,77 @@ impl StateStorage for State {
             })
     }
 
+    fn get_transaction_for_client(
+        &self,
+        id: TransactionId,
+        client_id: ClientId,
+    ) -> ProcessingResult<StoredTransaction> {
+        tracing::debug!("Retrieving client {} transaction {}", client_id, id);
+        self.transactions
+            .read()
+            .map_err(|e| storage_poisoned("transactions", e))
+            .and_then(|transactions| {
+                transactions
+                    .get(&id)
+                    .filter(|tx| *tx.client_id() == client_id)
+                    .cloned()
+                    .ok_or(ProcessingError::TransactionNotFound { id })
+            })
+    }
+
+    fn contains_transaction(&self, id: TransactionId) -> ProcessingResult<bool> {
+        self.transactions
+            .read()
+            .map_err(|e| storage_poisoned("transactions", e))
+            .map(|transactions| transactions.contains_key(&id))
+    }
+
     fn insert_transaction(
         &self,
         transaction: StoredTransaction,
     ) -> ProcessingResult<StoredTransaction> {
         match transaction {
-            StoredTransaction::Deposit { .. } | StoredTransaction::Withdrawal { .. } => {
+            StoredTransaction::Deposit { .. }
+            | StoredTransaction::Withdrawal { .. }
+            | StoredTransaction::Transfer { .. }
+            | StoredTransaction::RegulatoryHold { .. }
+            | StoredTransaction::Refund { .. } => {
                 tracing::debug!("Inserting: {:?}", transaction);
+                self.check_transaction_id(*transaction.id())?;
                 self.transactions
                     .write()
-                    .map_err(|e| ProcessingError::UnknownError(e.to_string()))
+                    .map_err(|e| storage_poisoned("transactions", e))
                     .and_then(|mut transactions| {
-                        if !transactions.contains_key(&transaction.id()) {
-                            transactions.insert(transaction.id().clone(), transaction.clone());
+                        if !transactions.contains_key(transaction.id()) {
+                            transactions.insert(*transaction.id(), transaction.clone());
+                            self.index_amount(&transaction)?;
+                            self.append_to_journal(&transaction)?;
                             Ok(transaction)
                         } else {
                             Err(ProcessingError::TransactionAlreadyExists {
-                                id: transaction.id().clone(),
+                                id: *transaction.id(),
                             })
                         }
                     })
             }
-            _ => Ok(transaction),
+            _ => {
+                self.append_to_journal(&transaction)?;
+                Ok(transaction)
+            }
         }
     }
 
+    fn remove_transaction(&self, id: TransactionId) -> ProcessingResult<()> {
+        tracing::debug!("Removing transaction with id {}", id);
+        self.transactions
+            .write()
+            .map_err(|e| storage_poisoned("transactions", e))
+            .and_then(|mut transactions| match transactions.remove(&id) {
+                Some(removed) => self.unindex_amount(&removed),
+                None => Ok(()),
+            })
+    }
+
     fn under_dispute(&self, id: TransactionId, under_dispute: bool) -> ProcessingResult<()> {
         tracing::debug!(
             "Updating transaction with id {} to under dispute = {}",
@@ -80,7 +871,7 @@ impl StateStorage for State {
         );
         self.transactions
             .write()
-            .map_err(|e| ProcessingError::UnknownError(e.to_string()))
+            .map_err(|e| storage_poisoned("transactions", e))
             .map(|mut transactions| {
                 if let Some(tx) = transactions.get_mut(&id) {
                     tx.set_under_dispute(under_dispute);
@@ -88,34 +879,1092 @@ impl StateStorage for State {
             })
     }
 
-    fn get_all_accounts(&self) -> ProcessingResult<Box<Vec<Account>>> {
-        tracing::debug!("Retrieving all client account balances");
-        self.accounts
+    fn release_hold(&self, id: TransactionId) -> ProcessingResult<()> {
+        tracing::debug!("Releasing regulatory hold with id {}", id);
+        self.transactions
+            .write()
+            .map_err(|e| storage_poisoned("transactions", e))
+            .map(|mut transactions| {
+                if let Some(tx) = transactions.get_mut(&id) {
+                    tx.set_released(true);
+                }
+            })
+    }
+
+    fn approve_kyc(&self, client_id: ClientId) -> ProcessingResult<()> {
+        tracing::debug!("Approving KYC deposits for client with id {}", client_id);
+        self.transactions
+            .write()
+            .map_err(|e| storage_poisoned("transactions", e))
+            .map(|mut transactions| {
+                transactions
+                    .values_mut()
+                    .filter(|tx| tx.client_id() == &client_id)
+                    .for_each(StoredTransaction::set_kyc_approved);
+            })
+    }
+
+    fn get_transactions_for_client(&self, id: ClientId) -> ProcessingResult<Vec<JournalEntry>> {
+        tracing::debug!("Retrieving transactions of client with id {}", id);
+        self.journal
             .read()
-            .map_err(|e| ProcessingError::UnknownError(e.to_string()))
-            .map(|accounts| Box::new(accounts.values().cloned().collect::<Vec<_>>()))
+            .map_err(|e| storage_poisoned("journal", e))
+            .map(|journal| {
+                journal
+                    .iter()
+                    .filter(|entry| match &entry.transaction {
+                        StoredTransaction::Transfer {
+                            client_id,
+                            to_client,
+                            ..
+                        } => client_id == &id || to_client == &id,
+                        tx => tx.client_id() == &id,
+                    })
+                    .cloned()
+                    .collect()
+            })
     }
 
-    fn get_account(&self, id: &ClientId) -> ProcessingResult<Account> {
-        tracing::debug!("Retrieving account for client with id {} ", id);
-        self.accounts
+    fn get_journal_entries_until(&self, end: SystemTime) -> ProcessingResult<Vec<JournalEntry>> {
+        tracing::debug!("Retrieving journal entries until {:?}", end);
+        self.journal
             .read()
-            .map_err(|e| ProcessingError::UnknownError(e.to_string()))
-            .map(|accounts| {
-                accounts
-                    .get(id)
+            .map_err(|e| storage_poisoned("journal", e))
+            .map(|journal| {
+                journal
+                    .iter()
+                    .filter(|entry| entry.created_at <= end)
                     .cloned()
-                    .unwrap_or_else(|| Account::new(id.clone()))
+                    .collect()
             })
     }
 
-    fn upsert_account(&self, account: Account) -> ProcessingResult<()> {
-        tracing::debug!("Upserting {:?}", account);
-        self.accounts
-            .write()
-            .map_err(|e| ProcessingError::UnknownError(e.to_string()))
-            .map(|mut accounts| {
-                accounts.insert(account.client, account.clone());
+    fn get_clients_with_no_activity_since(
+        &self,
+        cutoff: SystemTime,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        tracing::debug!("Retrieving clients with no activity since {:?}", cutoff);
+        self.journal
+            .read()
+            .map_err(|e| storage_poisoned("journal", e))
+            .map(|journal| {
+                let mut last_activity = BTreeMap::<ClientId, SystemTime>::new();
+                for entry in journal.iter() {
+                    let mut clients = vec![*entry.transaction.client_id()];
+                    if let StoredTransaction::Transfer { to_client, .. } = &entry.transaction {
+                        clients.push(*to_client);
+                    }
+                    for client in clients {
+                        let last = last_activity.entry(client).or_insert(entry.created_at);
+                        *last = (*last).max(entry.created_at);
+                    }
+                }
+                last_activity
+                    .into_iter()
+                    .filter(|(_, last)| *last < cutoff)
+                    .map(|(client, _)| client)
+                    .collect()
+            })
+    }
+
+    fn get_transactions_in_amount_range(
+        &self,
+        min: Amount,
+        max: Amount,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        tracing::debug!(
+            "Retrieving transactions with amount from {} to {}",
+            min,
+            max
+        );
+        if min > max {
+            return Ok(Vec::new());
+        }
+        let transactions = self
+            .transactions
+            .read()
+            .map_err(|e| storage_poisoned("transactions", e))?;
+        self.amount_index
+            .read()
+            .map_err(|e| storage_poisoned("amount_index", e))
+            .map(|index| {
+                index
+                    .range(min..=max)
+                    .flat_map(|(_, ids)| ids)
+                    .filter_map(|id| transactions.get(id).cloned())
+                    .collect()
+            })
+    }
+
+    fn get_transactions_by_processor(
+        &self,
+        processor_id: &str,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        tracing::debug!(
+            "Retrieving transactions recorded by processor {}",
+            processor_id
+        );
+        self.transactions
+            .read()
+            .map_err(|e| storage_poisoned("transactions", e))
+            .map(|transactions| {
+                transactions
+                    .values()
+                    .filter(|tx| tx.processor_id() == Some(processor_id))
+                    .cloned()
+                    .collect()
+            })
+    }
+
+    fn get_orphaned_transactions(&self) -> ProcessingResult<Vec<StoredTransaction>> {
+        tracing::debug!("Retrieving transactions without a client account");
+        let accounts = self
+            .accounts
+            .read()
+            .map_err(|e| storage_poisoned("accounts", e))?;
+        self.transactions
+            .read()
+            .map_err(|e| storage_poisoned("transactions", e))
+            .map(|transactions| {
+                transactions
+                    .values()
+                    .filter(|tx| !accounts.contains_key(tx.client_id()))
+                    .cloned()
+                    .collect()
             })
     }
+
+    fn transaction_count(&self) -> ProcessingResult<usize> {
+        self.transactions
+            .read()
+            .map_err(|e| storage_poisoned("transactions", e))
+            .map(|transactions| transactions.len())
+    }
+
+    fn count_open_disputes(&self, client_id: ClientId) -> ProcessingResult<u32> {
+        tracing::debug!("Counting open disputes of client with id {}", client_id);
+        self.transactions
+            .read()
+            .map_err(|e| storage_poisoned("transactions", e))
+            .map(|transactions| {
+                transactions
+                    .values()
+                    .filter(|tx| tx.client_id() == &client_id && tx.is_under_dispute())
+                    .count() as u32
+            })
+    }
+
+    fn iter_transactions<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = ProcessingResult<StoredTransaction>> + 'a> {
+        tracing::debug!("Iterating all transactions");
+        match self.transactions.read() {
+            Ok(transactions) => Box::new(TransactionsIter {
+                ids: transactions.keys().copied().collect::<Vec<_>>().into_iter(),
+                transactions,
+            }),
+            Err(e) => Box::new(std::iter::once(Err(storage_poisoned("transactions", e)))),
+        }
+    }
+
+    fn get_all_accounts(&self) -> ProcessingResult<Vec<Account>> {
+        tracing::debug!("Retrieving all client account balances");
+        self.accounts
+            .read()
+            .map_err(|e| storage_poisoned("accounts", e))
+            .map(|accounts| accounts.values().cloned().collect())
+    }
+
+    fn get_accounts_sorted_by_risk(&self) -> ProcessingResult<Vec<(Account, RiskScore)>> {
+        tracing::debug!("Retrieving accounts sorted by risk score");
+        self.accounts
+            .read()
+            .map_err(|e| storage_poisoned("accounts", e))
+            .map(|accounts| sorted_by_risk(accounts.values().cloned()))
+    }
+
+    fn get_account(&self, id: &ClientId) -> ProcessingResult<Account> {
+        tracing::debug!("Retrieving account for client with id {} ", id);
+        self.accounts
+            .read()
+            .map_err(|e| storage_poisoned("accounts", e))
+            .map(|accounts| {
+                accounts
+                    .get(id)
+                    .cloned()
+                    .unwrap_or_else(|| Account::new(*id))
+            })
+    }
+
+    fn with_account<F, R>(&self, id: ClientId, f: F) -> ProcessingResult<R>
+    where
+        F: FnOnce(&Account) -> R,
+    {
+        tracing::debug!("Reading account for client with id {} ", id);
+        self.accounts
+            .read()
+            .map_err(|e| storage_poisoned("accounts", e))
+            .map(|accounts| match accounts.get(&id) {
+                Some(account) => f(account),
+                None => f(&Account::new(id)),
+            })
+    }
+
+    fn account_count(&self) -> ProcessingResult<usize> {
+        self.accounts
+            .read()
+            .map_err(|e| storage_poisoned("accounts", e))
+            .map(|accounts| accounts.len())
+    }
+
+    fn get_accounts_with_zero_balance(&self) -> ProcessingResult<Vec<Account>> {
+        tracing::debug!("Retrieving client accounts with zero balance");
+        self.accounts
+            .read()
+            .map_err(|e| storage_poisoned("accounts", e))
+            .map(|accounts| {
+                accounts
+                    .values()
+                    .filter(|account| account.total == Amount::ZERO && account.held == Amount::ZERO)
+                    .cloned()
+                    .collect()
+            })
+    }
+
+    fn get_clients_exceeding_balance_threshold(
+        &self,
+        threshold: Amount,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        tracing::debug!("Retrieving clients with total balance above {}", threshold);
+        self.balance_index
+            .read()
+            .map_err(|e| storage_poisoned("balance_index", e))
+            .map(|index| {
+                index
+                    .range((
+                        Bound::Excluded((threshold, ClientId::MAX)),
+                        Bound::Unbounded,
+                    ))
+                    .map(|(_, client_id)| *client_id)
+                    .collect()
+            })
+    }
+
+    fn get_accounts_by_tier(
+        &self,
+        config: &TierConfig,
+    ) -> ProcessingResult<HashMap<AccountTier, Vec<Account>>> {
+        tracing::debug!("Retrieving client accounts by tier");
+        self.accounts
+            .read()
+            .map_err(|e| storage_poisoned("accounts", e))
+            .map(|accounts| {
+                let mut tiers = HashMap::<AccountTier, Vec<Account>>::new();
+                for tier in [
+                    AccountTier::Standard,
+                    AccountTier::Premium,
+                    AccountTier::Vip,
+                ] {
+                    tiers.insert(tier, Vec::new());
+                }
+                for account in accounts.values() {
+                    tiers
+                        .entry(account.tier(config))
+                        .or_default()
+                        .push(account.clone());
+                }
+                tiers
+            })
+    }
+
+    fn remove_account(&self, id: ClientId) -> ProcessingResult<()> {
+        tracing::debug!("Removing account for client with id {}", id);
+        self.accounts
+            .write()
+            .map_err(|e| storage_poisoned("accounts", e))
+            .and_then(|mut accounts| {
+                let removed = accounts.remove(&id);
+                self.update_balance_index(removed.as_ref(), None)
+            })
+    }
+
+    fn migrate_client_id(
+        &self,
+        old_id: ClientId,
+        new_id: ClientId,
+        merge: bool,
+    ) -> ProcessingResult<()> {
+        tracing::debug!(
+            "Migrating client with id {} to id {}, merge = {}",
+            old_id,
+            new_id,
+            merge
+        );
+        let mut accounts = self
+            .accounts
+            .write()
+            .map_err(|e| storage_poisoned("accounts", e))?;
+        let old = accounts
+            .get(&old_id)
+            .cloned()
+            .ok_or(ProcessingError::AccountNotFound { client_id: old_id })?;
+        if old_id == new_id {
+            return Ok(());
+        }
+        let previous = accounts.get(&new_id).cloned();
+        let mut account = match &previous {
+            Some(_) if !merge => {
+                return Err(ProcessingError::AccountAlreadyExists { client_id: new_id })
+            }
+            Some(existing) => {
+                let mut account = existing.clone();
+                account.merge(&old)?;
+                account
+            }
+            None => Account {
+                client: new_id,
+                version: 0,
+                ..old.clone()
+            },
+        };
+        account.version = previous.as_ref().map_or(0, |a| a.version) + 1;
+
+        self.transactions
+            .write()
+            .map_err(|e| storage_poisoned("transactions", e))?
+            .values_mut()
+            .for_each(|tx| tx.set_client_id(old_id, new_id));
+        self.journal
+            .write()
+            .map_err(|e| storage_poisoned("journal", e))?
+            .iter_mut()
+            .for_each(|entry| entry.transaction.set_client_id(old_id, new_id));
+
+        accounts.remove(&old_id);
+        accounts.insert(new_id, account.clone());
+        self.update_balance_index(Some(&old), None)?;
+        self.update_balance_index(previous.as_ref(), Some(&account))?;
+        self.record_account_version(&account)?;
+        self.notify_account_update(&account)
+    }
+
+    fn register_delegation(&self, from: ClientId, to: ClientId) -> ProcessingResult<()> {
+        tracing::debug!(
+            "Delegating client with id {} to client with id {}",
+            from,
+            to
+        );
+        self.delegations
+            .write()
+            .map_err(|e| storage_poisoned("delegations", e))
+            .and_then(|mut delegations| {
+                let previous = delegations.insert(from, to);
+                if let Err(e) = delegation_target(&delegations, from) {
+                    match previous {
+                        Some(previous) => delegations.insert(from, previous),
+                        None => delegations.remove(&from),
+                    };
+                    return Err(e);
+                }
+                Ok(())
+            })
+    }
+
+    fn resolve_delegation(&self, id: ClientId) -> ProcessingResult<ClientId> {
+        self.delegations
+            .read()
+            .map_err(|e| storage_poisoned("delegations", e))
+            .and_then(|delegations| delegation_target(&delegations, id))
+    }
+
+    fn upsert_account(
+        &self,
+        account: Account,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        tracing::debug!("Upserting {}", account);
+        check_balance_invariant(&account)?;
+        self.accounts
+            .write()
+            .map_err(|e| storage_poisoned("accounts", e))
+            .and_then(|mut accounts| {
+                let mut account = account;
+                account.version = accounts.get(&account.client).map_or(0, |a| a.version) + 1;
+                account.risk_score = Some(RiskScore::of(&account).score);
+                let previous = accounts.insert(account.client, account.clone());
+                self.update_balance_index(previous.as_ref(), Some(&account))?;
+                self.on_account_updated(&account, triggered_by)
+            })
+    }
+
+    fn upsert_accounts(
+        &self,
+        accounts: Vec<Account>,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        tracing::debug!("Upserting {:?}", accounts);
+        accounts.iter().try_for_each(check_balance_invariant)?;
+        self.accounts
+            .write()
+            .map_err(|e| storage_poisoned("accounts", e))
+            .and_then(|mut stored| {
+                accounts
+                    .into_iter()
+                    .try_for_each(|account| self.store_account(&mut stored, account, triggered_by))
+            })
+    }
+
+    /// Holds the accounts write lock from reading both accounts until both are stored.
+    fn update_accounts(
+        &self,
+        from: ClientId,
+        to: ClientId,
+        triggered_by: TransactionId,
+        update: &mut dyn FnMut(&mut Account, &mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<()> {
+        tracing::debug!("Updating accounts of clients with ids {} and {}", from, to);
+        check_distinct_clients(from, to, triggered_by)?;
+        let mut stored = self
+            .accounts
+            .write()
+            .map_err(|e| storage_poisoned("accounts", e))?;
+        let account = |id: ClientId| stored.get(&id).cloned().unwrap_or_else(|| Account::new(id));
+        let mut source = account(from);
+        let mut destination = account(to);
+        update(&mut source, &mut destination)?;
+        check_balance_invariant(&source)?;
+        check_balance_invariant(&destination)?;
+        self.store_account(&mut stored, source, triggered_by)?;
+        self.store_account(&mut stored, destination, triggered_by)
+    }
+
+    fn balance_history(&self, id: ClientId) -> ProcessingResult<Vec<BalanceSnapshot>> {
+        tracing::debug!("Retrieving balance history for client with id {}", id);
+        self.balance_history
+            .read()
+            .map_err(|e| storage_poisoned("balance_history", e))
+            .map(|history| {
+                history
+                    .get(&id)
+                    .map(|snapshots| snapshots.iter().cloned().collect())
+                    .unwrap_or_default()
+            })
+    }
+
+    fn get_account_versions(&self, id: ClientId) -> ProcessingResult<Vec<AccountVersion>> {
+        tracing::debug!("Retrieving account versions for client with id {}", id);
+        self.account_versions
+            .read()
+            .map_err(|e| storage_poisoned("account_versions", e))
+            .map(|versions| versions.get(&id).cloned().unwrap_or_default())
+    }
+
+    fn compact_account_history(&self, id: ClientId, keep_last: usize) -> ProcessingResult<()> {
+        tracing::debug!(
+            "Compacting account versions for client with id {} to last {}",
+            id,
+            keep_last
+        );
+        self.account_versions
+            .write()
+            .map_err(|e| storage_poisoned("account_versions", e))
+            .map(|mut versions| {
+                if let Some(versions) = versions.get_mut(&id) {
+                    let overflow = versions.len().saturating_sub(keep_last);
+                    versions.drain(..overflow);
+                }
+            })
+    }
+
+    fn subscribe_to_account_updates(
+        &self,
+        id: ClientId,
+    ) -> ProcessingResult<watch::Receiver<Account>> {
+        tracing::debug!("Subscribing to account updates for client with id {}", id);
+        let accounts = self
+            .accounts
+            .read()
+            .map_err(|e| storage_poisoned("accounts", e))?;
+        self.account_subscribers
+            .write()
+            .map_err(|e| storage_poisoned("account_subscribers", e))
+            .map(|mut subscribers| {
+                subscribers
+                    .entry(id)
+                    .or_insert_with(|| {
+                        let account = accounts.get(&id).cloned();
+                        watch::channel(account.unwrap_or_else(|| Account::new(id))).0
+                    })
+                    .subscribe()
+            })
+    }
+
+    fn clear(&self) -> ProcessingResult<()> {
+        tracing::debug!("Clearing all accounts and transactions");
+        let mut accounts = self
+            .accounts
+            .write()
+            .map_err(|e| storage_poisoned("accounts", e))?;
+        *accounts = HashMap::default();
+        *self
+            .balance_index
+            .write()
+            .map_err(|e| storage_poisoned("balance_index", e))? = BTreeSet::new();
+        *self
+            .transactions
+            .write()
+            .map_err(|e| storage_poisoned("transactions", e))? = HashMap::default();
+        *self
+            .amount_index
+            .write()
+            .map_err(|e| storage_poisoned("amount_index", e))? = BTreeMap::new();
+        *self
+            .journal
+            .write()
+            .map_err(|e| storage_poisoned("journal", e))? = Vec::new();
+        *self
+            .account_subscribers
+            .write()
+            .map_err(|e| storage_poisoned("account_subscribers", e))? = HashMap::default();
+        *self
+            .delegations
+            .write()
+            .map_err(|e| storage_poisoned("delegations", e))? = HashMap::default();
+        *self
+            .balance_history
+            .write()
+            .map_err(|e| storage_poisoned("balance_history", e))? = HashMap::default();
+        *self
+            .account_versions
+            .write()
+            .map_err(|e| storage_poisoned("account_versions", e))? = HashMap::default();
+        Ok(())
+    }
+
+    fn snapshot(&self) -> ProcessingResult<Self> {
+        tracing::debug!("Taking a snapshot of all accounts and transactions");
+        Ok(Self {
+            accounts: RwLock::new(copy_locked_map(&self.accounts, "accounts")?),
+            balance_index: RwLock::new(copy_locked(&self.balance_index, "balance_index")?),
+            transactions: RwLock::new(copy_locked_map(&self.transactions, "transactions")?),
+            amount_index: RwLock::new(copy_locked(&self.amount_index, "amount_index")?),
+            journal: RwLock::new(copy_locked(&self.journal, "journal")?),
+            account_subscribers: RwLock::new(HashMap::default()),
+            delegations: RwLock::new(copy_locked_map(&self.delegations, "delegations")?),
+            balance_history: RwLock::new(copy_locked_map(
+                &self.balance_history,
+                "balance_history",
+            )?),
+            history_depth: self.history_depth,
+            account_versions: RwLock::new(copy_locked_map(
+                &self.account_versions,
+                "account_versions",
+            )?),
+            max_account_versions: self.max_account_versions,
+            max_transaction_id: self.max_transaction_id,
+        })
+    }
+
+    fn restore(&self, snapshot: &Self) -> ProcessingResult<()> {
+        tracing::debug!("Restoring all accounts and transactions from a snapshot");
+        if std::ptr::eq(self, snapshot) {
+            return Ok(());
+        }
+        let mut accounts = self
+            .accounts
+            .write()
+            .map_err(|e| storage_poisoned("accounts", e))?;
+        *accounts = copy_locked_map(&snapshot.accounts, "accounts")?;
+        *self
+            .balance_index
+            .write()
+            .map_err(|e| storage_poisoned("balance_index", e))? =
+            copy_locked(&snapshot.balance_index, "balance_index")?;
+        *self
+            .transactions
+            .write()
+            .map_err(|e| storage_poisoned("transactions", e))? =
+            copy_locked_map(&snapshot.transactions, "transactions")?;
+        *self
+            .amount_index
+            .write()
+            .map_err(|e| storage_poisoned("amount_index", e))? =
+            copy_locked(&snapshot.amount_index, "amount_index")?;
+        *self
+            .journal
+            .write()
+            .map_err(|e| storage_poisoned("journal", e))? =
+            copy_locked(&snapshot.journal, "journal")?;
+        *self
+            .delegations
+            .write()
+            .map_err(|e| storage_poisoned("delegations", e))? =
+            copy_locked_map(&snapshot.delegations, "delegations")?;
+        *self
+            .balance_history
+            .write()
+            .map_err(|e| storage_poisoned("balance_history", e))? =
+            copy_locked_map(&snapshot.balance_history, "balance_history")?;
+        *self
+            .account_versions
+            .write()
+            .map_err(|e| storage_poisoned("account_versions", e))? =
+            copy_locked_map(&snapshot.account_versions, "account_versions")?;
+        self.account_subscribers
+            .read()
+            .map_err(|e| storage_poisoned("account_subscribers", e))
+            .map(|subscribers| {
+                for (id, sender) in subscribers.iter() {
+                    let account = accounts.get(id).cloned();
+                    sender.send_replace(account.unwrap_or_else(|| Account::new(*id)));
+                }
+            })
+    }
+}
+
+/// Shares a storage, e.g. between [`AsyncState`](crate::async_state::AsyncState) and the
+/// processor running on it.
+impl<S: StateStorage> StateStorage for Arc<S> {
+    fn get_transaction(&self, id: TransactionId) -> ProcessingResult<StoredTransaction> {
+        (**self).get_transaction(id)
+    }
+
+    fn get_transaction_for_client(
+        &self,
+        id: TransactionId,
+        client_id: ClientId,
+    ) -> ProcessingResult<StoredTransaction> {
+        (**self).get_transaction_for_client(id, client_id)
+    }
+
+    fn contains_transaction(&self, id: TransactionId) -> ProcessingResult<bool> {
+        (**self).contains_transaction(id)
+    }
+
+    fn insert_transaction(
+        &self,
+        transaction: StoredTransaction,
+    ) -> ProcessingResult<StoredTransaction> {
+        (**self).insert_transaction(transaction)
+    }
+
+    fn remove_transaction(&self, id: TransactionId) -> ProcessingResult<()> {
+        (**self).remove_transaction(id)
+    }
+
+    fn under_dispute(&self, id: TransactionId, under_dispute: bool) -> ProcessingResult<()> {
+        (**self).under_dispute(id, under_dispute)
+    }
+
+    fn release_hold(&self, id: TransactionId) -> ProcessingResult<()> {
+        (**self).release_hold(id)
+    }
+
+    fn approve_kyc(&self, client_id: ClientId) -> ProcessingResult<()> {
+        (**self).approve_kyc(client_id)
+    }
+
+    fn get_transactions_for_client(&self, id: ClientId) -> ProcessingResult<Vec<JournalEntry>> {
+        (**self).get_transactions_for_client(id)
+    }
+
+    fn get_journal_entries_until(&self, end: SystemTime) -> ProcessingResult<Vec<JournalEntry>> {
+        (**self).get_journal_entries_until(end)
+    }
+
+    fn get_clients_with_no_activity_since(
+        &self,
+        cutoff: SystemTime,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        (**self).get_clients_with_no_activity_since(cutoff)
+    }
+
+    fn get_transactions_in_amount_range(
+        &self,
+        min: Amount,
+        max: Amount,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        (**self).get_transactions_in_amount_range(min, max)
+    }
+
+    fn get_transactions_by_processor(
+        &self,
+        processor_id: &str,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        (**self).get_transactions_by_processor(processor_id)
+    }
+
+    fn get_orphaned_transactions(&self) -> ProcessingResult<Vec<StoredTransaction>> {
+        (**self).get_orphaned_transactions()
+    }
+
+    fn transaction_count(&self) -> ProcessingResult<usize> {
+        (**self).transaction_count()
+    }
+
+    fn count_open_disputes(&self, client_id: ClientId) -> ProcessingResult<u32> {
+        (**self).count_open_disputes(client_id)
+    }
+
+    fn iter_transactions<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = ProcessingResult<StoredTransaction>> + 'a> {
+        (**self).iter_transactions()
+    }
+    fn get_all_accounts(&self) -> ProcessingResult<Vec<Account>> {
+        (**self).get_all_accounts()
+    }
+
+    fn get_accounts_sorted_by_risk(&self) -> ProcessingResult<Vec<(Account, RiskScore)>> {
+        (**self).get_accounts_sorted_by_risk()
+    }
+
+    fn get_account(&self, id: &ClientId) -> ProcessingResult<Account> {
+        (**self).get_account(id)
+    }
+
+    fn with_account<F, R>(&self, id: ClientId, f: F) -> ProcessingResult<R>
+    where
+        F: FnOnce(&Account) -> R,
+    {
+        (**self).with_account(id, f)
+    }
+
+    fn account_count(&self) -> ProcessingResult<usize> {
+        (**self).account_count()
+    }
+
+    fn get_accounts_with_zero_balance(&self) -> ProcessingResult<Vec<Account>> {
+        (**self).get_accounts_with_zero_balance()
+    }
+
+    fn get_clients_exceeding_balance_threshold(
+        &self,
+        threshold: Amount,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        (**self).get_clients_exceeding_balance_threshold(threshold)
+    }
+
+    fn get_accounts_by_tier(
+        &self,
+        config: &TierConfig,
+    ) -> ProcessingResult<HashMap<AccountTier, Vec<Account>>> {
+        (**self).get_accounts_by_tier(config)
+    }
+
+    fn remove_account(&self, id: ClientId) -> ProcessingResult<()> {
+        (**self).remove_account(id)
+    }
+
+    fn migrate_client_id(
+        &self,
+        old_id: ClientId,
+        new_id: ClientId,
+        merge: bool,
+    ) -> ProcessingResult<()> {
+        (**self).migrate_client_id(old_id, new_id, merge)
+    }
+
+    fn register_delegation(&self, from: ClientId, to: ClientId) -> ProcessingResult<()> {
+        (**self).register_delegation(from, to)
+    }
+
+    fn resolve_delegation(&self, id: ClientId) -> ProcessingResult<ClientId> {
+        (**self).resolve_delegation(id)
+    }
+
+    fn upsert_account(
+        &self,
+        account: Account,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        (**self).upsert_account(account, triggered_by)
+    }
+
+    fn upsert_accounts(
+        &self,
+        accounts: Vec<Account>,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        (**self).upsert_accounts(accounts, triggered_by)
+    }
+
+    fn update_account(
+        &self,
+        id: ClientId,
+        triggered_by: TransactionId,
+        update: &mut dyn FnMut(&mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<()> {
+        (**self).update_account(id, triggered_by, update)
+    }
+
+    fn update_accounts(
+        &self,
+        from: ClientId,
+        to: ClientId,
+        triggered_by: TransactionId,
+        update: &mut dyn FnMut(&mut Account, &mut Account) -> ProcessingResult<()>,
+    ) -> ProcessingResult<()> {
+        (**self).update_accounts(from, to, triggered_by, update)
+    }
+
+    fn balance_history(&self, id: ClientId) -> ProcessingResult<Vec<BalanceSnapshot>> {
+        (**self).balance_history(id)
+    }
+
+    fn get_account_versions(&self, id: ClientId) -> ProcessingResult<Vec<AccountVersion>> {
+        (**self).get_account_versions(id)
+    }
+
+    fn compact_account_history(&self, id: ClientId, keep_last: usize) -> ProcessingResult<()> {
+        (**self).compact_account_history(id, keep_last)
+    }
+
+    fn subscribe_to_account_updates(
+        &self,
+        id: ClientId,
+    ) -> ProcessingResult<watch::Receiver<Account>> {
+        (**self).subscribe_to_account_updates(id)
+    }
+
+    fn clear(&self) -> ProcessingResult<()> {
+        (**self).clear()
+    }
+
+    fn snapshot(&self) -> ProcessingResult<Self> {
+        (**self).snapshot().map(Arc::new)
+    }
+
+    fn restore(&self, snapshot: &Self) -> ProcessingResult<()> {
+        (**self).restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        domain::{Transaction, TransactionType},
+        processor::TransactionProcessor,
+    };
+
+    fn deposit(client: ClientId, tx: TransactionId, amount: Amount) -> Transaction {
+        Transaction::new(TransactionType::Deposit, client, tx, Some(amount))
+    }
+
+    #[test]
+    fn commit_keeps_writes_made_during_the_transaction() {
+        let processor = TransactionProcessor::new(State::new());
+        assert_eq!(
+            processor.process_transaction(deposit(1, 1, dec!(5))),
+            Ok(())
+        );
+
+        let mut txn = processor.state().begin_transaction().expect("transaction");
+        let dispute = Transaction::new(TransactionType::Dispute, 1, 1, None);
+        assert_eq!(processor.process_in_txn(&mut txn, dispute), Ok(()));
+        assert_eq!(
+            processor.process_in_txn(&mut txn, deposit(2, 2, dec!(2))),
+            Ok(())
+        );
+        assert_eq!(
+            processor.process_transaction(deposit(3, 3, dec!(3))),
+            Ok(())
+        );
+        assert_eq!(txn.commit(), Ok(()));
+
+        let mut accounts = processor.get_accounts().expect("accounts");
+        accounts.sort();
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(accounts[0].held, dec!(5));
+        assert_eq!(accounts[1].total, dec!(2));
+        assert_eq!(accounts[2].total, dec!(3));
+        let disputed = processor.state().get_transaction(1).expect("deposit");
+        assert!(disputed.is_under_dispute());
+        assert!(processor.state().contains_transaction(2).expect("lookup"));
+    }
+
+    #[test]
+    fn commit_fails_when_an_account_changed_during_the_transaction() {
+        let processor = TransactionProcessor::new(State::new());
+        assert_eq!(
+            processor.process_transaction(deposit(1, 1, dec!(5))),
+            Ok(())
+        );
+
+        let mut txn = processor.state().begin_transaction().expect("transaction");
+        assert_eq!(
+            processor.process_in_txn(&mut txn, deposit(1, 2, dec!(2))),
+            Ok(())
+        );
+        assert_eq!(
+            processor.process_transaction(deposit(1, 3, dec!(3))),
+            Ok(())
+        );
+        assert_eq!(
+            txn.commit(),
+            Err(ProcessingError::StateTransactionConflict { client_id: 1 })
+        );
+
+        let accounts = processor.get_accounts().expect("accounts");
+        assert_eq!(accounts[0].total, dec!(8));
+        assert!(!processor.state().contains_transaction(2).expect("lookup"));
+    }
+
+    #[test]
+    fn rollback_leaves_the_storage_untouched() {
+        let processor = TransactionProcessor::new(State::new());
+        let mut txn = processor.state().begin_transaction().expect("transaction");
+        assert_eq!(
+            processor.process_in_txn(&mut txn, deposit(1, 1, dec!(5))),
+            Ok(())
+        );
+        txn.rollback();
+
+        assert_eq!(processor.get_accounts(), Ok(Vec::new()));
+    }
+
+    /// A processor whose state has a 5.0 deposit by client 1 (tx 1) and a 3.0 deposit by
+    /// client 2 (tx 2).
+    fn two_clients() -> TransactionProcessor<State> {
+        let processor = TransactionProcessor::new(State::new());
+        assert_eq!(
+            processor.process_transaction(deposit(1, 1, dec!(5))),
+            Ok(())
+        );
+        assert_eq!(
+            processor.process_transaction(deposit(2, 2, dec!(3))),
+            Ok(())
+        );
+        processor
+    }
+
+    #[test]
+    fn update_accounts_stores_both_accounts_or_neither() {
+        let state = State::new();
+        let mut funded = |account: &mut Account| {
+            account.available = dec!(5);
+            account.total = dec!(5);
+            Ok(())
+        };
+        assert_eq!(state.update_account(1, 1, &mut funded), Ok(()));
+
+        let mut overdraw = |from: &mut Account, to: &mut Account| {
+            from.available -= dec!(6);
+            to.available += dec!(6);
+            Ok(())
+        };
+        assert_eq!(
+            state.update_accounts(1, 2, 2, &mut overdraw),
+            Err(ProcessingError::AccountBalanceInvariantViolated { client_id: 1 })
+        );
+        assert_eq!(state.get_account(&1).map(|a| a.available), Ok(dec!(5)));
+        assert_eq!(state.get_all_accounts().map(|a| a.len()), Ok(1));
+
+        let mut move_two = |from: &mut Account, to: &mut Account| {
+            from.available -= dec!(2);
+            from.total -= dec!(2);
+            to.available += dec!(2);
+            to.total += dec!(2);
+            Ok(())
+        };
+        assert_eq!(state.update_accounts(1, 2, 3, &mut move_two), Ok(()));
+        let mut accounts = state.get_all_accounts().expect("accounts");
+        accounts.sort();
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|a| (a.client, a.total, a.version))
+                .collect::<Vec<_>>(),
+            vec![(1, dec!(3), 2), (2, dec!(2), 1)]
+        );
+        assert_eq!(
+            state.update_accounts(1, 1, 4, &mut move_two),
+            Err(ProcessingError::TransactionIsNotValid { id: 4 })
+        );
+    }
+
+    #[test]
+    fn migrating_a_missing_client_fails_in_both_modes() {
+        for merge in [false, true] {
+            for new_id in [2, 3] {
+                let processor = two_clients();
+
+                assert_eq!(
+                    processor.state().migrate_client_id(4, new_id, merge),
+                    Err(ProcessingError::AccountNotFound { client_id: 4 })
+                );
+                assert_eq!(processor.state().account_count(), Ok(2));
+            }
+        }
+    }
+
+    #[test]
+    fn migrating_to_a_new_id_moves_the_account_and_its_transactions() {
+        for merge in [false, true] {
+            let processor = two_clients();
+
+            assert_eq!(processor.state().migrate_client_id(1, 3, merge), Ok(()));
+
+            let mut clients = processor
+                .get_accounts()
+                .expect("accounts")
+                .iter()
+                .map(|account| account.client)
+                .collect::<Vec<_>>();
+            clients.sort();
+            assert_eq!(clients, vec![2, 3]);
+            let migrated = processor.state().get_account(&3).expect("migrated account");
+            assert_eq!((migrated.available, migrated.total), (dec!(5), dec!(5)));
+            let deposit = processor.state().get_transaction(1).expect("deposit");
+            assert_eq!(deposit.client_id(), &3);
+            assert_eq!(
+                processor.state().get_transactions_for_client(1),
+                Ok(Vec::new())
+            );
+        }
+    }
+
+    #[test]
+    fn migrating_to_an_existing_id_without_merge_fails() {
+        let processor = two_clients();
+
+        assert_eq!(
+            processor.state().migrate_client_id(1, 2, false),
+            Err(ProcessingError::AccountAlreadyExists { client_id: 2 })
+        );
+
+        assert_eq!(
+            processor.state().get_account(&1).map(|a| a.total),
+            Ok(dec!(5))
+        );
+        assert_eq!(
+            processor.state().get_account(&2).map(|a| a.total),
+            Ok(dec!(3))
+        );
+        let deposit = processor.state().get_transaction(1).expect("deposit");
+        assert_eq!(deposit.client_id(), &1);
+    }
+
+    #[test]
+    fn migrating_to_an_existing_id_with_merge_sums_the_accounts() {
+        let processor = two_clients();
+
+        assert_eq!(processor.state().migrate_client_id(1, 2, true), Ok(()));
+
+        assert_eq!(processor.state().account_count(), Ok(1));
+        let merged = processor.state().get_account(&2).expect("merged account");
+        assert_eq!((merged.available, merged.total), (dec!(8), dec!(8)));
+        let deposit = processor.state().get_transaction(1).expect("deposit");
+        assert_eq!(deposit.client_id(), &2);
+        assert_eq!(
+            processor
+                .state()
+                .get_transactions_for_client(2)
+                .map(|e| e.len()),
+            Ok(2)
+        );
+    }
 }
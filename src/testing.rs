@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tokio::sync::watch;
+
+use crate::{
+    api::{ProcessingError, ProcessingResult},
+    domain::{
+        Account, AccountTier, AccountVersion, Amount, BalanceSnapshot, ClientId, JournalEntry,
+        RiskScore, StoredTransaction, TierConfig, TransactionId,
+    },
+    state::{State, StateStorage},
+};
+
+/// A call made to [`MockStateStorage`] together with its arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageCall {
+    GetTransaction {
+        id: TransactionId,
+    },
+    GetTransactionForClient {
+        id: TransactionId,
+        client_id: ClientId,
+    },
+    ContainsTransaction {
+        id: TransactionId,
+    },
+    InsertTransaction {
+        transaction: StoredTransaction,
+    },
+    RemoveTransaction {
+        id: TransactionId,
+    },
+    UnderDispute {
+        id: TransactionId,
+        under_dispute: bool,
+    },
+    ReleaseHold {
+        id: TransactionId,
+    },
+    ApproveKyc {
+        client_id: ClientId,
+    },
+    GetTransactionsForClient {
+        id: ClientId,
+    },
+    GetJournalEntriesUntil {
+        end: SystemTime,
+    },
+    GetClientsWithNoActivitySince {
+        cutoff: SystemTime,
+    },
+    GetTransactionsInAmountRange {
+        min: Amount,
+        max: Amount,
+    },
+    GetTransactionsByProcessor {
+        processor_id: String,
+    },
+    GetOrphanedTransactions,
+    TransactionCount,
+    CountOpenDisputes {
+        client_id: ClientId,
+    },
+    IterTransactions,
+    GetAllAccounts,
+    GetAccountsSortedByRisk,
+    GetAccount {
+        id: ClientId,
+    },
+    WithAccount {
+        id: ClientId,
+    },
+    AccountCount,
+    GetAccountsWithZeroBalance,
+    GetClientsExceedingBalanceThreshold {
+        threshold: Amount,
+    },
+    GetAccountsByTier {
+        config: TierConfig,
+    },
+    RemoveAccount {
+        id: ClientId,
+    },
+    MigrateClientId {
+        old_id: ClientId,
+        new_id: ClientId,
+        merge: bool,
+    },
+    RegisterDelegation {
+        from: ClientId,
+        to: ClientId,
+    },
+    ResolveDelegation {
+        id: ClientId,
+    },
+    UpsertAccount {
+        account: Account,
+        triggered_by: TransactionId,
+    },
+    UpsertAccounts {
+        accounts: Vec<Account>,
+        triggered_by: TransactionId,
+    },
+    BalanceHistory {
+        id: ClientId,
+    },
+    GetAccountVersions {
+        id: ClientId,
+    },
+    CompactAccountHistory {
+        id: ClientId,
+        keep_last: usize,
+    },
+    SubscribeToAccountUpdates {
+        id: ClientId,
+    },
+    Clear,
+    Snapshot,
+    Restore,
+}
+
+/// Storage backed by an in-memory [`State`] that records every call made through
+/// [`StateStorage`], so tests can assert on what a processor read and wrote.
+#[derive(Default)]
+pub struct MockStateStorage {
+    inner: State,
+    calls: Mutex<Vec<StorageCall>>,
+    failing_inserts: Mutex<usize>,
+}
+
+impl MockStateStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seeds an account without recording a call.
+    pub fn with_account(self, account: Account) -> Self {
+        self.inner
+            .upsert_account(account, 0)
+            .expect("seeding an account into an empty mock");
+        self
+    }
+
+    /// Seeds a transaction without recording a call.
+    pub fn with_transaction(self, transaction: StoredTransaction) -> Self {
+        self.inner
+            .insert_transaction(transaction)
+            .expect("seeding a transaction into the mock");
+        self
+    }
+
+    /// Fails the next `count` `insert_transaction` calls with the transient
+    /// `StoragePoisoned`, e.g. to exercise retries. Failed calls are still recorded.
+    pub fn with_failing_inserts(self, count: usize) -> Self {
+        match self.failing_inserts.lock() {
+            Ok(mut failing_inserts) => *failing_inserts = count,
+            Err(poisoned) => *poisoned.into_inner() = count,
+        }
+        self
+    }
+
+    pub fn calls(&self) -> Vec<StorageCall> {
+        match self.calls.lock() {
+            Ok(calls) => calls.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn calls_matching(&self, predicate: impl Fn(&StorageCall) -> bool) -> Vec<StorageCall> {
+        self.calls()
+            .into_iter()
+            .filter(|call| predicate(call))
+            .collect()
+    }
+
+    pub fn clear_calls(&self) {
+        match self.calls.lock() {
+            Ok(mut calls) => calls.clear(),
+            Err(poisoned) => poisoned.into_inner().clear(),
+        }
+    }
+
+    fn record(&self, call: StorageCall) {
+        match self.calls.lock() {
+            Ok(mut calls) => calls.push(call),
+            Err(poisoned) => poisoned.into_inner().push(call),
+        }
+    }
+}
+
+impl StateStorage for MockStateStorage {
+    fn get_transaction(&self, id: TransactionId) -> ProcessingResult<StoredTransaction> {
+        self.record(StorageCall::GetTransaction { id });
+        self.inner.get_transaction(id)
+    }
+
+    fn get_transaction_for_client(
+        &self,
+        id: TransactionId,
+        client_id: ClientId,
+    ) -> ProcessingResult<StoredTransaction> {
+        self.record(StorageCall::GetTransactionForClient { id, client_id });
+        self.inner.get_transaction_for_client(id, client_id)
+    }
+
+    fn contains_transaction(&self, id: TransactionId) -> ProcessingResult<bool> {
+        self.record(StorageCall::ContainsTransaction { id });
+        self.inner.contains_transaction(id)
+    }
+
+    fn insert_transaction(
+        &self,
+        transaction: StoredTransaction,
+    ) -> ProcessingResult<StoredTransaction> {
+        self.record(StorageCall::InsertTransaction {
+            transaction: transaction.clone(),
+        });
+        let mut failing_inserts = match self.failing_inserts.lock() {
+            Ok(failing_inserts) => failing_inserts,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if *failing_inserts > 0 {
+            *failing_inserts -= 1;
+            return Err(ProcessingError::StoragePoisoned {
+                context: "mock: failing insert".to_string(),
+            });
+        }
+        drop(failing_inserts);
+        self.inner.insert_transaction(transaction)
+    }
+
+    fn remove_transaction(&self, id: TransactionId) -> ProcessingResult<()> {
+        self.record(StorageCall::RemoveTransaction { id });
+        self.inner.remove_transaction(id)
+    }
+
+    fn under_dispute(&self, id: TransactionId, under_dispute: bool) -> ProcessingResult<()> {
+        self.record(StorageCall::UnderDispute { id, under_dispute });
+        self.inner.under_dispute(id, under_dispute)
+    }
+
+    fn release_hold(&self, id: TransactionId) -> ProcessingResult<()> {
+        self.record(StorageCall::ReleaseHold { id });
+        self.inner.release_hold(id)
+    }
+
+    fn approve_kyc(&self, client_id: ClientId) -> ProcessingResult<()> {
+        self.record(StorageCall::ApproveKyc { client_id });
+        self.inner.approve_kyc(client_id)
+    }
+
+    fn get_transactions_for_client(&self, id: ClientId) -> ProcessingResult<Vec<JournalEntry>> {
+        self.record(StorageCall::GetTransactionsForClient { id });
+        self.inner.get_transactions_for_client(id)
+    }
+
+    fn get_journal_entries_until(&self, end: SystemTime) -> ProcessingResult<Vec<JournalEntry>> {
+        self.record(StorageCall::GetJournalEntriesUntil { end });
+        self.inner.get_journal_entries_until(end)
+    }
+
+    fn get_clients_with_no_activity_since(
+        &self,
+        cutoff: SystemTime,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        self.record(StorageCall::GetClientsWithNoActivitySince { cutoff });
+        self.inner.get_clients_with_no_activity_since(cutoff)
+    }
+
+    fn get_transactions_in_amount_range(
+        &self,
+        min: Amount,
+        max: Amount,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.record(StorageCall::GetTransactionsInAmountRange { min, max });
+        self.inner.get_transactions_in_amount_range(min, max)
+    }
+
+    fn get_transactions_by_processor(
+        &self,
+        processor_id: &str,
+    ) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.record(StorageCall::GetTransactionsByProcessor {
+            processor_id: processor_id.to_string(),
+        });
+        self.inner.get_transactions_by_processor(processor_id)
+    }
+
+    fn get_orphaned_transactions(&self) -> ProcessingResult<Vec<StoredTransaction>> {
+        self.record(StorageCall::GetOrphanedTransactions);
+        self.inner.get_orphaned_transactions()
+    }
+
+    fn transaction_count(&self) -> ProcessingResult<usize> {
+        self.record(StorageCall::TransactionCount);
+        self.inner.transaction_count()
+    }
+
+    fn count_open_disputes(&self, client_id: ClientId) -> ProcessingResult<u32> {
+        self.record(StorageCall::CountOpenDisputes { client_id });
+        self.inner.count_open_disputes(client_id)
+    }
+
+    fn iter_transactions<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = ProcessingResult<StoredTransaction>> + 'a> {
+        self.record(StorageCall::IterTransactions);
+        self.inner.iter_transactions()
+    }
+    fn get_all_accounts(&self) -> ProcessingResult<Vec<Account>> {
+        self.record(StorageCall::GetAllAccounts);
+        self.inner.get_all_accounts()
+    }
+
+    fn get_accounts_sorted_by_risk(&self) -> ProcessingResult<Vec<(Account, RiskScore)>> {
+        self.record(StorageCall::GetAccountsSortedByRisk);
+        self.inner.get_accounts_sorted_by_risk()
+    }
+
+    fn get_account(&self, id: &ClientId) -> ProcessingResult<Account> {
+        self.record(StorageCall::GetAccount { id: *id });
+        self.inner.get_account(id)
+    }
+
+    fn with_account<F, R>(&self, id: ClientId, f: F) -> ProcessingResult<R>
+    where
+        F: FnOnce(&Account) -> R,
+    {
+        self.record(StorageCall::WithAccount { id });
+        self.inner.with_account(id, f)
+    }
+
+    fn account_count(&self) -> ProcessingResult<usize> {
+        self.record(StorageCall::AccountCount);
+        self.inner.account_count()
+    }
+
+    fn get_accounts_with_zero_balance(&self) -> ProcessingResult<Vec<Account>> {
+        self.record(StorageCall::GetAccountsWithZeroBalance);
+        self.inner.get_accounts_with_zero_balance()
+    }
+
+    fn get_clients_exceeding_balance_threshold(
+        &self,
+        threshold: Amount,
+    ) -> ProcessingResult<Vec<ClientId>> {
+        self.record(StorageCall::GetClientsExceedingBalanceThreshold { threshold });
+        self.inner
+            .get_clients_exceeding_balance_threshold(threshold)
+    }
+
+    fn get_accounts_by_tier(
+        &self,
+        config: &TierConfig,
+    ) -> ProcessingResult<HashMap<AccountTier, Vec<Account>>> {
+        self.record(StorageCall::GetAccountsByTier {
+            config: config.clone(),
+        });
+        self.inner.get_accounts_by_tier(config)
+    }
+
+    fn remove_account(&self, id: ClientId) -> ProcessingResult<()> {
+        self.record(StorageCall::RemoveAccount { id });
+        self.inner.remove_account(id)
+    }
+
+    fn migrate_client_id(
+        &self,
+        old_id: ClientId,
+        new_id: ClientId,
+        merge: bool,
+    ) -> ProcessingResult<()> {
+        self.record(StorageCall::MigrateClientId {
+            old_id,
+            new_id,
+            merge,
+        });
+        self.inner.migrate_client_id(old_id, new_id, merge)
+    }
+
+    fn register_delegation(&self, from: ClientId, to: ClientId) -> ProcessingResult<()> {
+        self.record(StorageCall::RegisterDelegation { from, to });
+        self.inner.register_delegation(from, to)
+    }
+
+    fn resolve_delegation(&self, id: ClientId) -> ProcessingResult<ClientId> {
+        self.record(StorageCall::ResolveDelegation { id });
+        self.inner.resolve_delegation(id)
+    }
+
+    fn upsert_account(
+        &self,
+        account: Account,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        self.record(StorageCall::UpsertAccount {
+            account: account.clone(),
+            triggered_by,
+        });
+        self.inner.upsert_account(account, triggered_by)
+    }
+
+    fn upsert_accounts(
+        &self,
+        accounts: Vec<Account>,
+        triggered_by: TransactionId,
+    ) -> ProcessingResult<()> {
+        self.record(StorageCall::UpsertAccounts {
+            accounts: accounts.clone(),
+            triggered_by,
+        });
+        self.inner.upsert_accounts(accounts, triggered_by)
+    }
+
+    fn balance_history(&self, id: ClientId) -> ProcessingResult<Vec<BalanceSnapshot>> {
+        self.record(StorageCall::BalanceHistory { id });
+        self.inner.balance_history(id)
+    }
+
+    fn get_account_versions(&self, id: ClientId) -> ProcessingResult<Vec<AccountVersion>> {
+        self.record(StorageCall::GetAccountVersions { id });
+        self.inner.get_account_versions(id)
+    }
+
+    fn compact_account_history(&self, id: ClientId, keep_last: usize) -> ProcessingResult<()> {
+        self.record(StorageCall::CompactAccountHistory { id, keep_last });
+        self.inner.compact_account_history(id, keep_last)
+    }
+
+    fn subscribe_to_account_updates(
+        &self,
+        id: ClientId,
+    ) -> ProcessingResult<watch::Receiver<Account>> {
+        self.record(StorageCall::SubscribeToAccountUpdates { id });
+        self.inner.subscribe_to_account_updates(id)
+    }
+
+    fn clear(&self) -> ProcessingResult<()> {
+        self.record(StorageCall::Clear);
+        self.inner.clear()
+    }
+
+    /// The snapshot starts with no recorded calls and no failing inserts.
+    fn snapshot(&self) -> ProcessingResult<Self> {
+        self.record(StorageCall::Snapshot);
+        Ok(Self {
+            inner: self.inner.snapshot()?,
+            calls: Mutex::new(Vec::new()),
+            failing_inserts: Mutex::new(0),
+        })
+    }
+
+    fn restore(&self, snapshot: &Self) -> ProcessingResult<()> {
+        self.record(StorageCall::Restore);
+        self.inner.restore(&snapshot.inner)
+    }
+}
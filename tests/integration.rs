@@ -0,0 +1,317 @@
+use std::io::Write;
+use std::process::Command;
+
+use rust_decimal_macros::dec;
+use tempfile::NamedTempFile;
+use trasaction_processor::domain::Account;
+
+/// Runs the binary on `input` written to a temporary CSV file, returning the accounts it
+/// printed, sorted by client, and its exit code
+fn process(input: &str) -> (Vec<Account>, i32) {
+    process_with_args(input, &[])
+}
+
+fn process_with_args(input: &str, args: &[&str]) -> (Vec<Account>, i32) {
+    let mut file = NamedTempFile::new().expect("temporary file");
+    file.write_all(input.as_bytes()).expect("input written");
+    let output = Command::new(env!("CARGO_BIN_EXE_trasaction-processor"))
+        .arg(file.path())
+        .args(args)
+        .env("RUST_LOG", "off")
+        .output()
+        .expect("binary runs");
+    let mut accounts = csv::Reader::from_reader(output.stdout.as_slice())
+        .deserialize::<Account>()
+        .collect::<Result<Vec<_>, _>>()
+        .expect("accounts output");
+    accounts.sort();
+    (accounts, output.status.code().expect("exit code"))
+}
+
+#[test]
+fn single_deposit() {
+    let (accounts, code) = process("type,client,tx,amount\ndeposit,1,1,1.5\n");
+
+    assert_eq!(code, 0);
+    assert_eq!(accounts.len(), 1);
+    assert_eq!(accounts[0].client, 1);
+    assert_eq!(accounts[0].available, dec!(1.5));
+    assert_eq!(accounts[0].held, dec!(0));
+    assert_eq!(accounts[0].total, dec!(1.5));
+    assert!(!accounts[0].locked);
+}
+
+#[test]
+fn byte_order_mark_is_skipped() {
+    let (accounts, code) = process("\u{feff}type,client,tx,amount\ndeposit,1,1,1.5\n");
+
+    assert_eq!(code, 0);
+    assert_eq!(accounts.len(), 1);
+    assert_eq!(accounts[0].available, dec!(1.5));
+}
+
+#[test]
+fn byte_order_mark_is_skipped_in_ndjson() {
+    let (accounts, code) = process_with_args(
+        "\u{feff}{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.5\"}\n",
+        &["--input-format", "ndjson"],
+    );
+
+    assert_eq!(code, 0);
+    assert_eq!(accounts.len(), 1);
+    assert_eq!(accounts[0].available, dec!(1.5));
+}
+
+#[test]
+fn tab_separated_input_matches_comma_separated_input() {
+    let (csv_accounts, csv_code) = process(include_str!("../transactions.csv"));
+    let (tsv_accounts, tsv_code) =
+        process_with_args(include_str!("../transactions.tsv"), &["--delimiter", "tab"]);
+
+    assert_eq!(tsv_code, csv_code);
+    assert_eq!(tsv_accounts.len(), 4);
+    assert_eq!(tsv_accounts, csv_accounts);
+}
+
+#[test]
+fn deposit_then_withdrawal() {
+    let (accounts, code) = process(
+        "type,client,tx,amount\n\
+         deposit,1,1,2.0\n\
+         withdrawal,1,2,0.5\n",
+    );
+
+    assert_eq!(code, 0);
+    assert_eq!(accounts[0].available, dec!(1.5));
+    assert_eq!(accounts[0].total, dec!(1.5));
+}
+
+#[test]
+fn deposit_then_dispute_then_chargeback() {
+    let (accounts, code) = process(
+        "type,client,tx,amount\n\
+         deposit,1,1,3.0\n\
+         deposit,1,2,1.0\n\
+         dispute,1,1,\n\
+         chargeback,1,1,\n",
+    );
+
+    assert_eq!(code, 0);
+    assert_eq!(accounts[0].available, dec!(1));
+    assert_eq!(accounts[0].held, dec!(0));
+    assert_eq!(accounts[0].total, dec!(1));
+    assert!(accounts[0].locked);
+    assert_eq!(accounts[0].chargeback_count, 1);
+}
+
+#[test]
+fn locked_account_rejects_withdrawals() {
+    let (accounts, code) = process(
+        "type,client,tx,amount\n\
+         deposit,1,1,3.0\n\
+         deposit,1,2,1.0\n\
+         dispute,1,1,\n\
+         chargeback,1,1,\n\
+         withdrawal,1,3,0.5\n\
+         deposit,2,4,1.0\n",
+    );
+
+    assert_eq!(code, 1);
+    assert_eq!(accounts.len(), 2);
+    assert_eq!(accounts[0].available, dec!(1));
+    assert_eq!(accounts[0].total, dec!(1));
+    assert!(accounts[0].locked);
+    assert_eq!(accounts[1].available, dec!(1));
+}
+
+#[test]
+fn reserved_client_is_rejected() {
+    let (accounts, code) = process(
+        "type,client,tx,amount\n\
+         deposit,0,1,3.0\n\
+         deposit,1,2,1.0\n",
+    );
+
+    assert_eq!(code, 1);
+    assert_eq!(accounts.len(), 1);
+    assert_eq!(accounts[0].client, 1);
+    assert_eq!(accounts[0].available, dec!(1));
+}
+
+#[test]
+fn dispute_of_another_clients_transaction_is_ignored() {
+    let (accounts, code) = process(
+        "type,client,tx,amount\n\
+         deposit,1,1,3.0\n\
+         deposit,2,2,1.0\n\
+         dispute,2,1,\n\
+         chargeback,2,1,\n",
+    );
+
+    assert_eq!(code, 0);
+    assert_eq!(accounts[0].available, dec!(3));
+    assert_eq!(accounts[0].held, dec!(0));
+    assert!(!accounts[0].locked);
+    assert_eq!(accounts[1].available, dec!(1));
+    assert!(!accounts[1].locked);
+}
+
+#[test]
+fn disputes_beyond_the_limit_are_rejected() {
+    let (accounts, code) = process_with_args(
+        "type,client,tx,amount\n\
+         deposit,1,1,1.0\n\
+         deposit,1,2,2.0\n\
+         deposit,1,3,4.0\n\
+         dispute,1,1,\n\
+         dispute,1,2,\n\
+         resolve,1,1,\n\
+         dispute,1,3,\n",
+        &["--max-open-disputes", "1"],
+    );
+
+    assert_eq!(code, 1);
+    assert_eq!(accounts[0].available, dec!(3));
+    assert_eq!(accounts[0].held, dec!(4));
+    assert_eq!(accounts[0].total, dec!(7));
+}
+
+#[test]
+fn rejected_transaction_of_unknown_client_is_not_an_orphan() {
+    let (accounts, code) = process_with_args(
+        "type,client,tx,amount\n\
+         deposit,1,1,1.0\n\
+         withdrawal,2,2,5.0\n",
+        &["--check-orphans"],
+    );
+
+    assert_eq!(code, 1);
+    assert_eq!(accounts.len(), 1);
+    assert_eq!(accounts[0].client, 1);
+}
+
+#[test]
+fn archived_accounts_leave_no_orphans_and_locked_accounts_stay() {
+    let archive = NamedTempFile::new().expect("temporary file");
+    let (accounts, code) = process_with_args(
+        "type,client,tx,amount\n\
+         deposit,1,1,1.0\n\
+         withdrawal,1,2,1.0\n\
+         deposit,2,3,2.0\n\
+         deposit,3,4,1.0\n\
+         dispute,3,4,\n\
+         chargeback,3,4,\n",
+        &[
+            "--archive-zero-balance",
+            archive.path().to_str().expect("UTF-8 path"),
+            "--check-orphans",
+            "--validate",
+        ],
+    );
+
+    assert_eq!(code, 0);
+    assert_eq!(accounts.len(), 2);
+    assert_eq!(accounts[0].client, 2);
+    assert_eq!(accounts[1].client, 3);
+    assert!(accounts[1].locked);
+    let archived = csv::Reader::from_path(archive.path())
+        .expect("archive")
+        .deserialize::<Account>()
+        .collect::<Result<Vec<_>, _>>()
+        .expect("archived accounts");
+    assert_eq!(archived.len(), 1);
+    assert_eq!(archived[0].client, 1);
+}
+
+#[test]
+fn rejected_transaction_of_unknown_client_passes_validation() {
+    let (accounts, code) = process_with_args(
+        "type,client,tx,amount\n\
+         deposit,1,1,1.0\n\
+         withdrawal,2,2,5.0\n",
+        &["--validate"],
+    );
+
+    assert_eq!(code, 1);
+    assert_eq!(accounts.len(), 1);
+}
+
+#[test]
+fn missing_input_is_rejected_before_processing() {
+    let output = Command::new(env!("CARGO_BIN_EXE_trasaction-processor"))
+        .arg("does-not-exist.csv")
+        .env("RUST_LOG", "off")
+        .output()
+        .expect("binary runs");
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not a file"));
+}
+
+#[test]
+fn missing_columns_are_rejected_before_processing() {
+    let mut file = NamedTempFile::new().expect("temporary file");
+    file.write_all(b"type,client,amount\ndeposit,1,1.0\n")
+        .expect("input written");
+    let output = Command::new(env!("CARGO_BIN_EXE_trasaction-processor"))
+        .arg(file.path())
+        .env("RUST_LOG", "off")
+        .output()
+        .expect("binary runs");
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing required columns: tx"));
+}
+
+#[test]
+fn client_filter_outputs_a_single_account() {
+    let (accounts, code) = process_with_args(
+        "type,client,tx,amount,to_client\n\
+         deposit,1,1,1.0,\n\
+         deposit,2,2,2.0,\n\
+         transfer,2,3,0.5,1\n",
+        &["--client-filter", "1"],
+    );
+
+    assert_eq!(code, 0);
+    assert_eq!(accounts.len(), 1);
+    assert_eq!(accounts[0].client, 1);
+    assert_eq!(accounts[0].available, dec!(1.5));
+}
+
+#[test]
+fn total_first_column_order() {
+    let mut file = NamedTempFile::new().expect("temporary file");
+    file.write_all(b"type,client,tx,amount\ndeposit,1,1,1.5\n")
+        .expect("input written");
+    let output = Command::new(env!("CARGO_BIN_EXE_trasaction-processor"))
+        .arg(file.path())
+        .args(["--column-order", "total-first", "--summary"])
+        .env("RUST_LOG", "off")
+        .output()
+        .expect("binary runs");
+
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,total,available,held,locked,chargebacks\n\
+         1,1.5,1.5,0,false,0\n\
+         TOTAL,1.5,1.5,0,0,0\n"
+    );
+}
+
+#[test]
+fn summary_overflow_fails_only_with_summary() {
+    let input = "type,client,tx,amount\n\
+                 deposit,1,1,40000000000000000000000000000\n\
+                 refund,2,2,40000000000000000000000000000\n";
+
+    let (accounts, code) = process(input);
+    assert_eq!(code, 0);
+    assert_eq!(accounts.len(), 2);
+
+    let (accounts, code) = process_with_args(input, &["--summary"]);
+    assert_eq!(code, 2);
+    assert!(accounts.is_empty());
+}
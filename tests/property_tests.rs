@@ -0,0 +1,77 @@
+use proptest::prelude::*;
+use trasaction_processor::{
+    domain::{Amount, ClientId, Transaction, TransactionId, TransactionType},
+    processor::TransactionProcessor,
+    state::State,
+};
+
+const CLIENTS: ClientId = 4;
+const TRANSACTION_IDS: TransactionId = 32;
+
+/// Amounts with up to four decimal places, including zero
+fn amount() -> impl Strategy<Value = Amount> {
+    (0i64..=1_000_000).prop_map(|units| Amount::new(units, 4))
+}
+
+/// Few clients and transaction ids so that disputes, resolves and chargebacks often refer
+/// to existing transactions, sometimes of another client
+fn transaction() -> impl Strategy<Value = Transaction> {
+    let client = 1..=CLIENTS;
+    let tx = 1..=TRANSACTION_IDS;
+    prop_oneof![
+        (client.clone(), tx.clone(), amount()).prop_map(|(client, tx, amount)| {
+            Transaction::new(TransactionType::Deposit, client, tx, Some(amount))
+        }),
+        (client.clone(), tx.clone(), amount()).prop_map(|(client, tx, amount)| {
+            Transaction::new(TransactionType::Withdrawal, client, tx, Some(amount))
+        }),
+        (client.clone(), tx.clone(), amount()).prop_map(|(client, tx, amount)| {
+            Transaction::new(TransactionType::Refund, client, tx, Some(amount))
+        }),
+        (client.clone(), tx.clone(), amount(), client.clone()).prop_map(
+            |(client, tx, amount, to_client)| {
+                Transaction::new(TransactionType::Transfer, client, tx, Some(amount))
+                    .with_to_client(to_client)
+            }
+        ),
+        (
+            prop_oneof![
+                Just(TransactionType::Dispute),
+                Just(TransactionType::Resolve),
+                Just(TransactionType::Chargeback),
+            ],
+            client,
+            tx,
+        )
+            .prop_map(|(transaction_type, client, tx)| {
+                Transaction::new(transaction_type, client, tx, None)
+            }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn account_totals_are_available_plus_held(
+        transactions in prop::collection::vec(transaction(), 0..200)
+    ) {
+        let processor = TransactionProcessor::new(State::new());
+        for transaction in transactions {
+            // Rejections, e.g. withdrawals exceeding the balance, are part of the sequence
+            let _ = processor.process_transaction(transaction);
+        }
+
+        for account in processor.get_accounts().unwrap().iter() {
+            prop_assert_eq!(account.total, account.available + account.held, "{}", account);
+        }
+    }
+
+    #[test]
+    fn state_stays_consistent(transactions in prop::collection::vec(transaction(), 0..200)) {
+        let processor = TransactionProcessor::new(State::new());
+        for transaction in transactions {
+            let _ = processor.process_transaction(transaction);
+        }
+
+        prop_assert_eq!(processor.state().validate_consistency(), vec![]);
+    }
+}